@@ -0,0 +1,92 @@
+#![feature(test)]
+
+extern crate test;
+extern crate zub;
+
+use test::Bencher;
+
+use zub::vm::*;
+use zub::ir::*;
+
+// List literals hold at most 255 elements, so the 10000 numbers are split across rows
+const ROWS: usize = 100;
+const COLUMNS: usize = 100;
+const RENDERS: usize = 10;
+
+// A large list rendered in full over and over without changing
+fn large_list() -> VM {
+    let mut builder = IrBuilder::new();
+
+    let elements = (0 .. ROWS)
+        .map(|row| builder.list((0 .. COLUMNS).map(|column| builder.number((row * COLUMNS + column) as f64)).collect()))
+        .collect();
+
+    let list = builder.list(elements);
+    builder.bind(Binding::global("list"), list);
+
+    let mut vm = VM::new();
+    vm.exec(&builder.build(), false).unwrap();
+
+    vm
+}
+
+fn render_repeatedly(b: &mut Bencher, cached: bool) {
+    let mut vm = large_list();
+    vm.heap.set_display_cache(cached);
+
+    let list = vm.globals["list"];
+
+    b.iter(|| {
+        for _ in 0 .. RENDERS {
+            test::black_box(list.display_with(&vm.heap, DisplayPolicy::Full));
+        }
+    });
+}
+
+#[bench]
+fn render_uncached(b: &mut Bencher) {
+    render_repeatedly(b, false)
+}
+
+#[bench]
+fn render_cached(b: &mut Bencher) {
+    render_repeatedly(b, true)
+}
+
+// Renders in between runs of a script that only updates an upvalue, which mustn't cost the cache
+#[bench]
+fn render_cached_between_runs(b: &mut Bencher) {
+    let mut vm = large_list();
+    vm.heap.set_display_cache(true);
+
+    let list = vm.globals["list"];
+
+    let mut builder = IrBuilder::new();
+
+    builder.bind(Binding::local("n", 0, 0), builder.number(0.0));
+
+    let bump = builder.function(Binding::local("bump", 0, 0), &[], |builder| {
+        for _ in 0 .. 100 {
+            let n = builder.var(Binding::local("n", 1, 0));
+            let next = builder.binary(n.clone(), BinaryOp::Add, builder.number(1.0));
+
+            builder.mutate(n, next);
+        }
+
+        builder.ret(None)
+    });
+
+    builder.emit(bump);
+
+    let call = builder.call(builder.var(Binding::local("bump", 0, 0)), vec![], None);
+    builder.emit(call);
+
+    let script = vm.compile_only(&builder.build()).unwrap();
+
+    b.iter(|| {
+        for _ in 0 .. RENDERS {
+            vm.exec_function(script.clone(), false).unwrap();
+            test::black_box(list.display_with(&vm.heap, DisplayPolicy::Full));
+        }
+    });
+}
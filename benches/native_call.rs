@@ -0,0 +1,45 @@
+#![feature(test)]
+
+extern crate test;
+extern crate zub;
+
+use test::Bencher;
+
+use zub::vm::*;
+use zub::ir::*;
+
+fn identity(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    Ok(context.get_arg(1))
+}
+
+// while i < 10000 { i = identity(i) + 1 }
+#[bench]
+fn native_call_loop(b: &mut Bencher) {
+    let mut builder = IrBuilder::new();
+
+    builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+
+    let i = builder.var(Binding::local("i", 0, 0));
+    let cond = builder.binary(i, BinaryOp::Lt, builder.number(10000.0));
+
+    let body = builder.while_(cond, |builder| {
+        let i = builder.var(Binding::local("i", 0, 0));
+
+        let callee = builder.var(Binding::global("identity"));
+        let call = builder.call(callee, vec![i.clone()], None);
+
+        let next = builder.binary(call, BinaryOp::Add, builder.number(1.0));
+        builder.mutate(i, next)
+    });
+
+    builder.emit(body);
+
+    let program = builder.build();
+
+    b.iter(|| {
+        let mut vm = VM::new();
+        vm.add_native("identity", identity, 1);
+
+        vm.exec(&program, false).unwrap()
+    });
+}
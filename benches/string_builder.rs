@@ -0,0 +1,67 @@
+#![feature(test)]
+
+extern crate test;
+extern crate zub;
+
+use test::Bencher;
+
+use zub::vm::*;
+use zub::ir::*;
+
+const FRAGMENTS: f64 = 1000.0;
+
+// while i < 1000 { body(s); i = i + 1 }
+fn fragment_loop(init: ExprNode, body: fn(&mut IrBuilder, ExprNode)) -> Vec<ExprNode> {
+    let mut builder = IrBuilder::new();
+
+    builder.bind(Binding::local("s", 0, 0), init);
+    builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+
+    let i = builder.var(Binding::local("i", 0, 0));
+    let cond = builder.binary(i, BinaryOp::Lt, builder.number(FRAGMENTS));
+
+    let body = builder.while_loop(move |_| cond.clone(), move |builder| {
+        body(builder, builder.var(Binding::local("s", 0, 0)));
+
+        let i = builder.var(Binding::local("i", 0, 0));
+        builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+    });
+
+    builder.emit(body);
+    builder.build()
+}
+
+#[bench]
+fn concat_fragments(b: &mut Bencher) {
+    let program = fragment_loop(IrBuilder::new().string(""), |builder, s| {
+        let next = builder.binary(s.clone(), BinaryOp::Add, builder.string("fragment"));
+        builder.mutate(s, next)
+    });
+
+    b.iter(|| {
+        let mut vm = VM::new();
+        vm.exec(&program, false).unwrap()
+    });
+}
+
+#[bench]
+fn string_builder_fragments(b: &mut Bencher) {
+    let sb_new = {
+        let builder = IrBuilder::new();
+        builder.call(builder.var(Binding::global("sb_new")), vec![], None)
+    };
+
+    let program = fragment_loop(sb_new, |builder, s| {
+        let append = builder.call(builder.var(Binding::global("sb_append")), vec![s, builder.string("fragment")], None);
+
+        builder.emit(append);
+        builder.emit(Expr::Pop.node(TypeInfo::nil()))
+    });
+
+    b.iter(|| {
+        let mut vm = VM::new();
+        vm.add_std_string_builder();
+
+        vm.exec(&program, false).unwrap()
+    });
+}
@@ -142,6 +142,6 @@ fn main() {
 
     let mut vm = VM::new();
 
-    vm.exec(&build, false);
+    vm.exec(&build, false).unwrap();
     println!("{:?}", vm.globals["entry"]);
 }
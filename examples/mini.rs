@@ -693,7 +693,7 @@ fn main() {
     println!("{:#?}", ir);
 
     let mut vm = VM::new();
-    vm.exec(&ir, true);
+    vm.exec(&ir, true).unwrap();
 
     println!("{:#?}", vm.globals)
 }
\ No newline at end of file
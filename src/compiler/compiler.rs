@@ -1,6 +1,12 @@
 use super::chunk::{ Chunk, Op };
 use super::*;
 
+// Matches with fewer arms than this aren't worth a jump table
+const JUMP_TABLE_MIN_ARMS: usize = 4;
+
+// Most arguments a call or method invocation can pass
+pub(crate) const MAX_ARGS: usize = 8;
+
 #[derive(Debug, Clone)]
 pub struct Local {
     pub name: String,
@@ -11,7 +17,7 @@ pub struct Local {
 
 #[derive(Debug, Clone)]
 struct UpValue {
-    pub index: u8,
+    pub index: u16,
     pub is_local: bool,
 }
 
@@ -22,12 +28,20 @@ pub struct CompileState {
     upvalues: Vec<UpValue>,
     function: FunctionBuilder,
     scope_depth: usize,
+    loop_depth: usize,
     breaks: Vec<usize>,
     method: bool,
+    // Names, scope depths and slots of functions bound ahead of their declaration, see
+    // `compile_sequence`
+    hoisted: Vec<(String, usize, u8)>,
 }
 
 impl CompileState {
-    pub fn new(method: bool, reserved: &str, function: FunctionBuilder, scope_depth: usize) -> Self {
+    pub fn new(method: bool, reserved: &str, mut function: FunctionBuilder, scope_depth: usize) -> Self {
+        if method {
+            function.open_local(reserved, 0)
+        }
+
         let locals = vec![
             Local {
                 name: reserved.into(),
@@ -43,14 +57,22 @@ impl CompileState {
             upvalues: Vec::new(),
             function,
             scope_depth,
+            loop_depth: scope_depth,
             breaks: Vec::new(),
             method,
+            hoisted: Vec::new(),
         }
     }
 
     fn capture_local(&mut self, var: &str) -> Option<u8> {
+        let method = self.method;
+
         for (i, local) in self.locals.iter_mut().enumerate().rev() {
             if local.name == var {
+                if local.reserved && !method {
+                    return None
+                }
+
                 local.captured = true;
 
                 return Some(i as u8)
@@ -60,8 +82,10 @@ impl CompileState {
         None
     }
 
-    fn add_local(&mut self, var: &str, depth: usize) -> u8 {
-        let depth = self.scope_depth - depth;
+    // Locals belong to the innermost scope, so `end_scope` discards them and resolution falls back
+    // to any shadowed local of the same name
+    fn add_local(&mut self, var: &str) -> u8 {
+        let depth = self.scope_depth;
 
         if self.locals.len() == std::u8::MAX as usize {
             panic!("local variable overflow")
@@ -76,27 +100,39 @@ impl CompileState {
             }
         );
 
-        (self.locals.len() - 1) as u8
+        let slot = (self.locals.len() - 1) as u8;
+
+        // Hidden locals of the compiler, like the subject of a match, start with a space
+        if !var.starts_with(' ') {
+            self.function.open_local(var, slot)
+        }
+
+        slot
     }
 
-    fn resolve_local(&mut self, var: &str) -> u8 {
+    // The reserved slot holds the called closure, which only methods may refer to, as `self`
+    fn resolve_local(&self, var: &str) -> Option<u8> {
         for (i, local) in self.locals.iter().enumerate().rev() {
             if local.name == var {
-                return i as u8
+                if local.reserved && !self.method {
+                    return None
+                }
+
+                return Some(i as u8)
             }
         }
 
-        panic!("TODO: unresolved var: {} in {:#?}", var, self.locals)
+        None
     }
 
-    fn add_upvalue(&mut self, index: u8, is_local: bool) -> u8 {
+    fn add_upvalue(&mut self, index: u16, is_local: bool) -> u16 {
         for (i, upval) in self.upvalues.iter().enumerate() {
             if upval.index == index && upval.is_local == is_local {
-                return i as u8
+                return i as u16
             }
         }
 
-        if self.upvalues.len() == std::u8::MAX as usize {
+        if self.upvalues.len() == u16::MAX as usize {
             panic!("too many upvalues, not cool")
         } else {
             self.upvalues.push(
@@ -106,36 +142,90 @@ impl CompileState {
                 }
             );
 
-            (self.upvalues.len() - 1) as u8
+            (self.upvalues.len() - 1) as u16
         }
     }
 
+    // Only a declaration in the scope that hoisted the function fills its slot, not one of the
+    // same name in a nested block
+    fn take_hoisted(&mut self, var: &str) -> Option<u8> {
+        let depth = self.scope_depth;
+        let idx = self.hoisted.iter().rposition(|(name, d, _)| name == var && *d == depth)?;
+
+        Some(self.hoisted.remove(idx).2)
+    }
+
     fn begin_scope(&mut self) {
         self.scope_depth += 1;
     }
 
     fn end_scope(&mut self) {
+        let captured = self.close_scope();
+        self.emit_discards(captured)
+    }
+
+    // Forget the locals of the innermost scope, returning whether each was captured, innermost
+    // first. Their values are left on the stack.
+    fn close_scope(&mut self) -> Vec<bool> {
         let last = self.scope_depth;
 
         self.scope_depth -= 1;
 
-        let mut ops = Vec::new();
+        let mut captured = Vec::new();
 
-        self.locals.retain(|local| {
+        // Locals are ordered by depth, so those of the scope are the last ones
+        while let Some(local) = self.locals.last().cloned() {
             if local.depth < last || local.reserved {
-                return true
+                break
             }
 
-            if local.captured {
-                ops.push(Op::CloseUpValue)
+            self.locals.pop();
+            self.function.close_local(self.locals.len() as u8);
+
+            captured.push(local.captured)
+        }
+
+        captured
+    }
+
+    // Discard the locals of all scopes deeper than `depth` without forgetting them, for jumping
+    // out of those scopes
+    fn discard_locals(&mut self, depth: usize) {
+        let captured = self.locals.iter()
+            .rev()
+            .take_while(|local| local.depth > depth && !local.reserved)
+            .map(|local| local.captured)
+            .collect::<Vec<bool>>();
+
+        self.emit_discards(captured)
+    }
+
+    // Discard locals innermost first. Captured ones are closed one by one, while the others in
+    // between are popped together.
+    fn emit_discards(&mut self, captured: Vec<bool>) {
+        let mut pops = 0;
+
+        for captured in captured {
+            if captured {
+                self.emit_pops(pops);
+                self.emit(Op::CloseUpValue);
+
+                pops = 0
             } else {
-                ops.push(Op::Pop)
+                pops += 1
             }
+        }
 
-            false
-        });
+        self.emit_pops(pops)
+    }
 
-        ops.into_iter().rev().for_each(|op| self.emit(op))
+    // A function has at most 255 locals, so any run of them fits a single `PopN`
+    fn emit_pops(&mut self, count: u8) {
+        match count {
+            0 => (),
+            1 => self.emit(Op::Pop),
+            count => self.emit(Op::PopN(count)),
+        }
     }
 
     fn emit(&mut self, op: Op) {
@@ -159,6 +249,7 @@ pub struct Compiler<'g> {
     heap: &'g mut Heap<Object>,
     pub states: Vec<CompileState>,
     pub locals_cache: Vec<Local>,
+    validate: bool,
 }
 
 impl<'g> Compiler<'g> {
@@ -167,39 +258,110 @@ impl<'g> Compiler<'g> {
             heap,
             states: Vec::new(),
             locals_cache: Vec::new(),
+            validate: false,
         }
     }
 
-    pub fn compile(&mut self, exprs: &[ExprNode]) -> Function {
+    /// Check each compiled chunk with `Chunk::validate`, failing with `CompileError::Invalid`.
+    pub fn set_validation(&mut self, enabled: bool) {
+        self.validate = enabled
+    }
+
+    pub fn compile(&mut self, exprs: &[ExprNode]) -> Result<Function, CompileError> {
         self.start_function(false, "<zub>", 0, 0);
 
-        for expr in exprs.iter() {
-            self.compile_expr(expr)
+        self.compile_sequence(exprs)?;
+
+        self.emit_return(None)?;
+
+        self.end_function()
+    }
+
+    /// Like `compile`, but the program returns the value of its last expression rather than nil.
+    /// A `Pop` the front-end put after it to end the statement is left out, so the value is kept.
+    pub fn compile_returning(&mut self, exprs: &[ExprNode]) -> Result<Function, CompileError> {
+        self.start_function(false, "<zub>", 0, 0);
+
+        let exprs = match exprs {
+            [.., last, pop] if matches!(pop.inner(), Expr::Pop) && leaves_value(last) => &exprs[.. exprs.len() - 1],
+            _ => exprs,
+        };
+
+        self.compile_sequence(exprs)?;
+
+        // The value of the last expression is then on top of the stack
+        if exprs.last().map(leaves_value).unwrap_or(false) {
+            self.emit(Op::Return)
+        } else {
+            self.emit_return(None)?
         }
 
-        self.emit_return(None);
         self.end_function()
     }
 
-    pub fn compile_from(&mut self, exprs: &[ExprNode], locals: Vec<Local>) -> Function {
+    pub fn compile_from(&mut self, exprs: &[ExprNode], locals: Vec<Local>) -> Result<Function, CompileError> {
         self.start_function(false, "<zub>", 0, 0);
         self.states.last_mut().unwrap().locals = locals;
 
+        self.compile_sequence(exprs)?;
+
+        self.emit_return(None)?;
+
+        self.end_function()
+    }
+
+    // Local functions declared in the same sequence may call each other, so when there's more than
+    // one they're all bound to nil up front, and assigned as their declarations are reached. A lone
+    // function can already refer to itself.
+    fn compile_sequence(&mut self, exprs: &[ExprNode]) -> Result<(), CompileError> {
+        let functions = exprs.iter()
+            .filter_map(|expr| match expr.inner() {
+                Expr::Function(ref f) if f.var.depth.is_some() => Some(f.var.name()),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        if functions.len() > 1 {
+            for name in functions {
+                self.emit(Op::Nil);
+
+                let state = self.state_mut();
+                let slot = state.add_local(name);
+
+                state.hoisted.push((name.to_string(), state.scope_depth, slot));
+            }
+        }
+
         for expr in exprs.iter() {
-            self.compile_expr(expr)
+            self.compile_expr(expr)?
         }
 
-        self.emit_return(None);
-        self.end_function()
+        Ok(())
+    }
+
+    // Code of nodes without a span of their own belongs to the closest one that has
+    fn compile_expr(&mut self, expr: &ExprNode) -> Result<(), CompileError> {
+        let span = match expr.span() {
+            Some(span) => span,
+            None => return self.compile_node(expr),
+        };
+
+        let outer = self.chunk().span(self.chunk().len());
+
+        self.chunk_mut().set_span(Some(span));
+        let result = self.compile_node(expr);
+        self.chunk_mut().set_span(outer);
+
+        result
     }
 
-    fn compile_expr(&mut self, expr: &ExprNode) {
+    fn compile_node(&mut self, expr: &ExprNode) -> Result<(), CompileError> {
         use self::Expr::*;
 
         match expr.inner() {
-            Literal(ref lit) => self.emit_constant(lit),
+            Literal(ref lit) => self.emit_constant(lit)?,
             Unary(ref op, ref node) => {
-                self.compile_expr(node);
+                self.compile_expr(node)?;
 
                 use self::UnaryOp::*;
 
@@ -209,74 +371,99 @@ impl<'g> Compiler<'g> {
                 }
             },
 
-            Var(ref var) => self.var_get(var),
+            Var(ref var) => self.var_get(var)?,
             Mutate(ref lhs, ref rhs) => {
-                // Currently just handling Var
-                if let Var(ref var) = lhs.inner() {
-                    self.compile_expr(rhs);
-
-                    if var.is_upvalue() {
-                        let idx = self.resolve_upvalue(var.name());
-
-                        self.emit(Op::SetUpValue);
-                        self.emit_byte(idx)
-                    } else {
-                        if var.depth.is_none() { // Global
-                            self.set_global(var.name())
-                        } else {
-                            let idx = self.state_mut().resolve_local(var.name());
-
-                            self.emit(Op::SetLocal);
-                            self.emit_byte(idx)
-                        }
-                    }
-                } else {
-                    // When classes are a thing, this is where we handle setting properties
-                    panic!("can't mutate non-variable")
-                }
+                self.compile_expr(rhs)?;
+                self.var_set(lhs)?
             },
 
-            Return(val) => self.emit_return((*val).clone()),
+            Swap(ref lhs, ref rhs) => self.compile_swap(lhs, rhs)?,
 
-            Function(ref ir_func) => {
-                self.var_define(&ir_func.var, None);
+            Return(val) => self.emit_return((*val).clone())?,
 
-                self.function_decl(ir_func);
+            Function(ref ir_func) => {
+                if let Some(slot) = self.state_mut().take_hoisted(ir_func.var.name()) {
+                    self.function_decl(ir_func)?;
+
+                    self.emit(Op::SetLocal);
+                    self.emit_byte(slot);
+                    self.emit(Op::Pop)
+                } else if ir_func.var.depth.is_some() {
+                    self.var_define(&ir_func.var, None)?;
+                    self.function_decl(ir_func)?;
+                } else {
+                    // The closure has to be on the stack for `DefineGlobal` to bind it
+                    self.function_decl(ir_func)?;
+                    self.var_define(&ir_func.var, None)?;
+                }
             },
 
             AnonFunction(ref ir_func) => {
-                self.function_decl(ir_func);
+                self.function_decl(ir_func)?;
             }
 
             Not(ref expr) => {
-                self.compile_expr(expr);
+                self.compile_expr(expr)?;
                 self.emit(Op::Not)
             }
 
             Neg(ref expr) => {
-                self.compile_expr(expr);
+                self.compile_expr(expr)?;
                 self.emit(Op::Neg)
             }
 
             Call(ref call) => {
-                let arity = call.args.len();
+                let arity = call_arity(&call.args)?;
+
+                // Globals called by name are looked up by the call itself, after the arguments
+                // are evaluated. So that an argument can't rebind the global first and have
+                // another function called, this is only done for arguments without side effects
+                if let Var(ref var) = call.callee.inner() {
+                    if var.depth.is_none() && call.args.iter().all(without_side_effects) {
+                        let idx = self.string_constant(var.name())?;
 
-                if arity > 8 {
-                    panic!("That's a lot of arguments. But I will fix this limitation asap.")
+                        if idx <= u8::MAX as u16 {
+                            for arg in call.args.iter() {
+                                self.compile_expr(arg)?
+                            }
+
+                            self.emit(Op::CallGlobal(idx as u8, arity));
+
+                            return Ok(())
+                        }
+                    }
                 }
 
-                self.compile_expr(&call.callee);
+                self.compile_expr(&call.callee)?;
 
                 for arg in call.args.iter() {
-                    self.compile_expr(arg)
+                    self.compile_expr(arg)?
+                }
+
+                self.emit(Op::Call(arity))
+            },
+
+            Invoke(ref receiver, ref name, ref args) => {
+                let arity = call_arity(args)?;
+
+                let idx = self.string_constant(name)?;
+
+                self.compile_expr(receiver)?;
+
+                for arg in args.iter() {
+                    self.compile_expr(arg)?
                 }
 
-                self.emit(Op::Call(arity as u8))
+                if idx <= u8::MAX as u16 {
+                    self.emit(Op::Invoke(idx as u8, arity))
+                } else {
+                    self.emit(Op::InvokeLong(idx, arity))
+                }
             },
 
             List(ref content) => {
                 for el in content.iter().rev() {
-                    self.compile_expr(el)
+                    self.compile_expr(el)?
                 }
 
                 self.emit(Op::List);
@@ -284,17 +471,17 @@ impl<'g> Compiler<'g> {
             },
 
             SetElement(ref list, ref index, ref value) => {
-                self.compile_expr(value);
-                self.compile_expr(index);
-                self.compile_expr(list);
+                self.compile_expr(value)?;
+                self.compile_expr(index)?;
+                self.compile_expr(list)?;
 
                 self.emit(Op::SetElement);
             },
 
             Dict(keys, values) => {
                 for (key, val) in keys.iter().zip(values.iter()) {
-                    self.compile_expr(key);
-                    self.compile_expr(val);
+                    self.compile_expr(key)?;
+                    self.compile_expr(val)?;
                 }
 
                 self.emit(Op::Dict);
@@ -302,46 +489,93 @@ impl<'g> Compiler<'g> {
             },
 
             If(ref cond, ref then, ref els) => {
-                self.compile_expr(cond);
+                self.compile_expr(cond)?;
 
                 let else_jmp = self.emit_jze();
 
                 self.emit(Op::Pop);
-                self.compile_expr(then);
+                self.compile_expr(then)?;
 
                 let end_jmp = self.emit_jmp();
 
-                self.patch_jmp(else_jmp);
+                self.patch_jmp(else_jmp)?;
                 self.emit(Op::Pop);
 
                 if let &Some(ref els) = els {
-                    self.compile_expr(els)
+                    self.compile_expr(els)?
                 }
 
-                self.patch_jmp(end_jmp)
+                self.patch_jmp(end_jmp)?
             },
 
             While(ref cond, ref body) => {
+                // Breaks of enclosing loops must not be patched to the end of this one
+                let outer_breaks = self.state_mut().breaks();
+                let outer_depth = self.state_mut().loop_depth;
+
+                self.state_mut().loop_depth = self.state_mut().scope_depth;
+
                 let ip = self.ip();
 
-                self.compile_expr(cond);
+                self.compile_expr(cond)?;
 
                 let end_jmp = self.emit_jze();
 
                 self.emit(Op::Pop);
-                self.compile_expr(body);
+                self.compile_expr(body)?;
 
-                self.emit_loop(ip);
-                self.patch_jmp(end_jmp);
+                self.emit_loop(ip)?;
+                self.patch_jmp(end_jmp)?;
 
                 self.emit(Op::Pop);
 
                 for b in self.state_mut().breaks() {
-                    self.patch_jmp(b)
+                    self.patch_jmp(b)?
+                }
+
+                self.state_mut().breaks = outer_breaks;
+                self.state_mut().loop_depth = outer_depth;
+            },
+
+            DoWhile(ref body, ref cond) => {
+                let outer_breaks = self.state_mut().breaks();
+                let outer_depth = self.state_mut().loop_depth;
+
+                self.state_mut().loop_depth = self.state_mut().scope_depth;
+
+                let ip = self.ip();
+
+                self.compile_expr(body)?;
+                self.compile_expr(cond)?;
+
+                let end_jmp = self.emit_jze();
+
+                self.emit(Op::Pop);
+                self.emit_loop(ip)?;
+
+                self.patch_jmp(end_jmp)?;
+                self.emit(Op::Pop);
+
+                for b in self.state_mut().breaks() {
+                    self.patch_jmp(b)?
+                }
+
+                self.state_mut().breaks = outer_breaks;
+                self.state_mut().loop_depth = outer_depth;
+            },
+
+            Match(ref subject, ref arms, ref default) => {
+                if let Some((base, len)) = jump_table_range(arms) {
+                    self.compile_jump_table(subject, arms, default, base, len)?
+                } else {
+                    self.compile_match_chain(subject, arms, default)?
                 }
             },
 
             Break => {
+                let depth = self.state_mut().loop_depth;
+                self.state_mut().discard_locals(depth);
+
                 let jmp = self.emit_jmp();
                 self.state_mut().add_break(jmp)
             },
@@ -350,47 +584,83 @@ impl<'g> Compiler<'g> {
                 self.emit(Op::Pop)
             }
 
+            Print(ref value) => {
+                self.compile_expr(value)?;
+                self.emit(Op::Print)
+            }
+
+            Write(ref value) => {
+                self.compile_expr(value)?;
+                self.emit(Op::Write)
+            }
+
+            Yield(ref value) => {
+                self.compile_expr(value)?;
+                self.emit(Op::Yield)
+            }
+
             Binary(lhs, op, rhs) => {
                 use self::BinaryOp::*;
 
                 match op {
                     And => {
-                        self.compile_expr(lhs);
+                        self.compile_expr(lhs)?;
 
                         let short_circuit_jmp = self.emit_jze();
 
                         self.emit(Op::Pop);
-                        self.compile_expr(rhs);
+                        self.compile_expr(rhs)?;
 
-                        self.patch_jmp(short_circuit_jmp);
+                        self.patch_jmp(short_circuit_jmp)?;
                     },
 
                     Or => {
-                        self.compile_expr(lhs);
+                        self.compile_expr(lhs)?;
 
                         let else_jmp = self.emit_jze();
                         let end_jmp = self.emit_jmp();
 
-                        self.patch_jmp(else_jmp);
+                        self.patch_jmp(else_jmp)?;
                         self.emit(Op::Pop);
 
-                        self.compile_expr(rhs);
+                        self.compile_expr(rhs)?;
 
-                        self.patch_jmp(end_jmp)
+                        self.patch_jmp(end_jmp)?
                     },
 
                     Index => {
-                        self.compile_expr(rhs);
-                        self.compile_expr(lhs);
-        
-                        self.emit(Op::Index);
+                        // Flatten `a[b][c]...` so the whole chain is walked by a single instruction
+                        let mut keys = vec![rhs];
+                        let mut collection = lhs;
+
+                        while let Binary(ref inner, Index, ref key) = collection.inner() {
+                            keys.push(key);
+                            collection = inner;
+                        }
+
+                        if keys.len() > u8::MAX as usize {
+                            panic!("index chain too long")
+                        }
+
+                        for key in keys.iter() {
+                            self.compile_expr(key)?
+                        }
+
+                        self.compile_expr(collection)?;
+
+                        if keys.len() == 1 {
+                            self.emit(Op::Index)
+                        } else {
+                            self.emit(Op::IndexPath);
+                            self.emit_byte(keys.len() as u8)
+                        }
                     }
 
                     _ => {
                         // This looks kinda funny, but it's an ok way of matching I guess
 
-                        self.compile_expr(lhs); // will handle type in the future :)
-                        self.compile_expr(rhs);
+                        self.compile_expr(lhs)?; // will handle type in the future :)
+                        self.compile_expr(rhs)?;
 
                         match op {
                             Add => self.emit(Op::Add),
@@ -404,15 +674,8 @@ impl<'g> Compiler<'g> {
                             Lt => self.emit(Op::Less),
                             Pow => self.emit(Op::Pow),
 
-                            GtEqual => {
-                                self.emit(Op::Less);
-                                self.emit(Op::Not)
-                            },
-
-                            LtEqual => {
-                                self.emit(Op::Greater);
-                                self.emit(Op::Not)
-                            },
+                            GtEqual => self.emit(Op::GreaterEqual),
+                            LtEqual => self.emit(Op::LessEqual),
 
                             NEqual => {
                                 self.emit(Op::Equal);
@@ -426,78 +689,240 @@ impl<'g> Compiler<'g> {
             },
 
             Bind(ref var, ref init) => {
-                self.compile_expr(init);
-                self.var_define(var, None);
+                self.compile_expr(init)?;
+                self.var_define(var, None)?
             },
 
             BindGlobal(ref var, ref init) => {
-                self.compile_expr(init);
-                self.var_define(var, None)
+                self.compile_expr(init)?;
+                self.var_define(var, None)?
+            },
+
+            Block(ref body) => {
+                self.state_mut().begin_scope();
+
+                self.compile_sequence(body)?;
+
+                self.state_mut().end_scope()
             },
 
-            Block(ref body) => for node in body {
-                self.compile_expr(node)
+            // The result gets a slot of its own below the locals of the block, so they're discarded
+            // as usual, closing any that were captured, and the result is left in their place
+            ValueBlock(ref body, ref value) => {
+                self.emit(Op::Nil);
+
+                self.state_mut().begin_scope();
+                let slot = self.state_mut().add_local(" value");
+
+                self.state_mut().begin_scope();
+
+                self.compile_sequence(body)?;
+                self.compile_expr(value)?;
+
+                self.emit(Op::SetLocal);
+                self.emit_byte(slot);
+                self.emit(Op::Pop);
+
+                self.state_mut().end_scope();
+                self.state_mut().close_scope();
             },
 
             _ => todo!()
         }
+
+        Ok(())
+    }
+
+
+
+    // Compares the subject, kept in a hidden local, against each key in turn
+    fn compile_match_chain(&mut self, subject: &ExprNode, arms: &[(Literal, ExprNode)], default: &Option<ExprNode>) -> Result<(), CompileError> {
+        self.state_mut().begin_scope();
+
+        self.compile_expr(subject)?;
+        let slot = self.state_mut().add_local(" match");
+
+        let mut end_jmps = Vec::new();
+
+        for (key, body) in arms.iter() {
+            self.emit(Op::GetLocal);
+            self.emit_byte(slot);
+            self.emit_constant(key)?;
+            self.emit(Op::Equal);
+
+            let next_jmp = self.emit_jze();
+
+            self.emit(Op::Pop);
+            self.compile_expr(body)?;
+
+            end_jmps.push(self.emit_jmp());
+
+            self.patch_jmp(next_jmp)?;
+            self.emit(Op::Pop);
+        }
+
+        if let Some(ref default) = default {
+            self.compile_expr(default)?
+        }
+
+        for jmp in end_jmps {
+            self.patch_jmp(jmp)?
+        }
+
+        self.state_mut().end_scope();
+
+        Ok(())
+    }
+
+    // `JumpTable` is followed by the first key, the number of keys and a jump target for each of
+    // them, plus one for the default
+    fn compile_jump_table(&mut self, subject: &ExprNode, arms: &[(Literal, ExprNode)], default: &Option<ExprNode>, base: i64, len: usize) -> Result<(), CompileError> {
+        self.compile_expr(subject)?;
+
+        self.emit(Op::JumpTable);
+        self.chunk_mut().write_u64((base as f64).to_bits());
+        self.emit_byte(len as u8);
+
+        let table = self.ip();
+
+        for _ in 0 ..= len {
+            self.emit_u16(0xffff)
+        }
+
+        // The last slot is the default, which no arm patches
+        let mut patched = vec![false; len + 1];
+        let mut end_jmps = Vec::new();
+
+        for (key, body) in arms.iter() {
+            let slot = match *key {
+                Literal::Number(n) => (n as i64 - base) as usize,
+                _ => unreachable!(),
+            };
+
+            // A repeated key can never be reached, like in a chain
+            if patched[slot] {
+                continue
+            }
+
+            patched[slot] = true;
+
+            self.patch_jmp(table + slot * 2)?;
+            self.compile_expr(body)?;
+
+            end_jmps.push(self.emit_jmp());
+        }
+
+        for (slot, &done) in patched.iter().enumerate() {
+            if !done {
+                self.patch_jmp(table + slot * 2)?
+            }
+        }
+
+        if let Some(ref default) = default {
+            self.compile_expr(default)?
+        }
+
+        for jmp in end_jmps {
+            self.patch_jmp(jmp)?
+        }
+
+        Ok(())
     }
 
+    // Store the top of the stack in a variable, leaving it there
+    fn var_set(&mut self, lhs: &ExprNode) -> Result<(), CompileError> {
+        // Currently just handling Var
+        if let Expr::Var(ref var) = lhs.inner() {
+            if var.is_upvalue() {
+                let idx = self.resolve_upvalue(var.name())?;
 
+                self.emit_indexed_op(Op::SetUpValue, Op::SetUpValueLong, idx)
+            } else {
+                if var.depth.is_none() { // Global
+                    self.set_global(var.name())?
+                } else {
+                    let idx = self.local_slot(var.name())?;
+
+                    self.emit(Op::SetLocal);
+                    self.emit_byte(idx)
+                }
+            }
 
-    fn var_get(&mut self, var: &Binding) {
+            Ok(())
+        } else {
+            // When classes are a thing, this is where we handle setting properties
+            panic!("can't mutate non-variable")
+        }
+    }
+
+    // Both values are pushed in order, so the one of `rhs` is on top to be stored in `lhs` first,
+    // which needs no temporary
+    fn compile_swap(&mut self, lhs: &ExprNode, rhs: &ExprNode) -> Result<(), CompileError> {
+        self.compile_expr(lhs)?;
+        self.compile_expr(rhs)?;
+
+        self.var_set(lhs)?;
+        self.emit(Op::Pop);
+
+        self.var_set(rhs)?;
+        self.emit(Op::Pop);
+
+        Ok(())
+    }
+
+    fn var_get(&mut self, var: &Binding) -> Result<(), CompileError> {
         if var.is_upvalue() {
-            let idx = self.resolve_upvalue(var.name());
+            let idx = self.resolve_upvalue(var.name())?;
 
-            self.emit(Op::GetUpValue);
-            self.emit_byte(idx);
+            self.emit_indexed_op(Op::GetUpValue, Op::GetUpValueLong, idx)
         } else {
             // local time B)
             if var.depth.is_none() {
-                self.emit(Op::GetGlobal);
-                let idx = self.string_constant(var.name());
-                self.emit_byte(idx)
+                let idx = self.string_constant(var.name())?;
+                self.emit_indexed_op(Op::GetGlobal, Op::GetGlobalLong, idx)
             } else {
-                let idx = self.state_mut().resolve_local(var.name());
+                let idx = self.local_slot(var.name())?;
 
                 self.emit(Op::GetLocal);
                 self.emit_byte(idx)
             }
         }
+
+        Ok(())
     }
 
-    fn var_define(&mut self, var: &Binding, constant: Option<u8>) {
+    fn var_define(&mut self, var: &Binding, constant: Option<u16>) -> Result<(), CompileError> {
         // If there's depth, it's a local
-        if let Some(depth) = var.depth {
-            self.state_mut().add_local(var.name(), depth);
-            self.state_mut().resolve_local(var.name());
+        if var.depth.is_some() {
+            self.state_mut().add_local(var.name());
         } else {
-            self.emit(Op::DefineGlobal);
+            let idx = match constant {
+                Some(idx) => idx,
+                None => self.string_constant(var.name())?,
+            };
 
-            let idx = constant.unwrap_or_else(|| {
-                self.string_constant(var.name())
-            });
-
-            self.emit_byte(idx)
+            self.emit_indexed_op(Op::DefineGlobal, Op::DefineGlobalLong, idx)
         }
-    }
 
-    fn set_global(&mut self, name: &str) {
-        self.emit(Op::SetGlobal);
+        Ok(())
+    }
 
+    fn set_global(&mut self, name: &str) -> Result<(), CompileError> {
         let idx = {
             let chunk = self.states.last_mut()
                 .unwrap()
                 .function
                 .chunk_mut();
 
-            chunk.string_constant(self.heap, name)
+            chunk.string_constant(self.heap, name)?
         };
 
-        self.emit_byte(idx)
+        self.emit_indexed_op(Op::SetGlobal, Op::SetGlobalLong, idx);
+
+        Ok(())
     }
 
-    fn function_decl(&mut self, f: &IrFunction) {
+    fn function_decl(&mut self, f: &IrFunction) -> Result<(), CompileError> {
         let name = f.var.name();
         let decl = f.body.borrow();
 
@@ -508,26 +933,22 @@ impl<'g> Compiler<'g> {
         self.start_function(decl.method, name, arity, 1);
 
         for p in params {
-            self.state_mut().add_local(p.name(), 0);
-            self.state_mut().resolve_local(p.name());
+            self.state_mut().add_local(p.name());
         }
 
-        for expr in body.iter() {
-            self.compile_expr(expr)
-        }
+        self.compile_sequence(body)?;
 
         self.state_mut().end_scope();
 
         let upvalues = self.state_mut().upvalues.clone();
 
-        let function = self.end_function(); // Might delete later, felt cute
+        let function = self.end_function()?; // Might delete later, felt cute
         let handle = self.heap.insert(Object::Function(function)).into_handle();
 
         let value = Value::object(handle);
-        let idx = self.chunk_mut().add_constant(value);
+        let idx = self.chunk_mut().add_constant(value)?;
 
-        self.emit(Op::Closure);
-        self.emit_byte(idx);
+        self.emit_indexed_op(Op::Closure, Op::ClosureLong, idx);
 
         for upvalue in upvalues {
             self.emit_byte(
@@ -538,8 +959,10 @@ impl<'g> Compiler<'g> {
                 }
             );
 
-            self.emit_byte(upvalue.index)
+            self.emit_u16(upvalue.index)
         }
+
+        Ok(())
     }
 
     fn start_function(&mut self, method: bool, name: &str, arity: u8, scope: usize) {
@@ -550,18 +973,33 @@ impl<'g> Compiler<'g> {
         self.states.push(state)
     }
 
-    fn end_function(&mut self) -> Function {
+    fn end_function(&mut self) -> Result<Function, CompileError> {
         // self.emit_return(None);
 
         let mut state: CompileState = self.states.pop().expect("states can't be empty");
 
         self.locals_cache.extend(state.locals.clone());
 
+        if self.validate {
+            state.function.chunk.validate(self.heap).map_err(CompileError::Invalid)?
+        }
+
         state.function.set_upvalue_count(state.upvalues.len());
-        state.function.build()
+
+        let always_returns = !state.function.chunk.falls_off_end(self.heap);
+        state.function.set_always_returns(always_returns);
+
+        Ok(state.function.build())
+    }
+
+    fn local_slot(&self, name: &str) -> Result<u8, CompileError> {
+        self.states.last()
+            .expect("states to be non-empty")
+            .resolve_local(name)
+            .ok_or_else(|| CompileError::UnresolvedLocal(name.into()))
     }
 
-    fn resolve_upvalue(&mut self, name: &str) -> u8 {
+    fn resolve_upvalue(&mut self, name: &str) -> Result<u16, CompileError> {
         let end = self.states.len() - 1;
 
         let (scope, mut index) =
@@ -569,27 +1007,24 @@ impl<'g> Compiler<'g> {
                 .enumerate()
                 .rev()
                 .filter_map(|(i, enclosing)| {
-                    enclosing.capture_local(name).map(|local| (i, local))
+                    enclosing.capture_local(name).map(|local| (i, local as u16))
                 })
                 .next()
-                .expect(&format!("upvalue marked during resolution, but wasn't found: {}", name));
-
+                .ok_or_else(|| CompileError::UnresolvedLocal(name.into()))?;
 
         index = self.states[scope + 1].add_upvalue(index, true);
 
-        if scope >= self.states.len() - 2 {
-            // if we're one scope from current function
-            index
-        } else {
+        if scope < self.states.len() - 2 {
+            // Thread it through every function between the one owning the local and the current
             for enclosing in &mut self.states[scope + 2..] {
                 index = enclosing.add_upvalue(index, false)
             }
-
-            index
         }
+
+        Ok(index)
     }
 
-    fn emit_return(&mut self, ret: Option<ExprNode>) {
+    fn emit_return(&mut self, ret: Option<ExprNode>) -> Result<(), CompileError> {
         let state = self.state_mut();
         let initializer = state.function.name() == "init" && state.method;
 
@@ -597,12 +1032,14 @@ impl<'g> Compiler<'g> {
             self.emit(Op::GetLocal);
             self.emit_byte(0)
         } else if let Some(ref expr) = ret {
-            self.compile_expr(expr)
+            self.compile_expr(expr)?
         } else {
             self.emit(Op::Nil)
         }
 
-        self.emit(Op::Return)
+        self.emit(Op::Return);
+
+        Ok(())
     }
 
     fn state_mut(&mut self) -> &mut CompileState {
@@ -629,7 +1066,7 @@ impl<'g> Compiler<'g> {
             .line
     }
 
-    fn string_constant(&mut self, s: &str) -> u8 {
+    fn string_constant(&mut self, s: &str) -> Result<u16, CompileError> {
         let chunk = self.states.last_mut().unwrap().function.chunk_mut();
 
         chunk.string_constant(self.heap, s)
@@ -644,7 +1081,23 @@ impl<'g> Compiler<'g> {
         self.chunk_mut().write_byte(byte);
     }
 
-    fn emit_constant(&mut self, lit: &Literal) {
+    fn emit_u16(&mut self, value: u16) {
+        self.emit_byte((value & 0xff) as u8);
+        self.emit_byte((value >> 8) as u8);
+    }
+
+    // Upvalue and constant indices past a byte use the wide variant of the instruction
+    fn emit_indexed_op(&mut self, op: Op, long: Op, idx: u16) {
+        if idx <= u8::MAX as u16 {
+            self.emit(op);
+            self.emit_byte(idx as u8)
+        } else {
+            self.emit(long);
+            self.emit_u16(idx)
+        }
+    }
+
+    fn emit_constant(&mut self, lit: &Literal) -> Result<(), CompileError> {
         use self::Literal::*;
 
         match *lit {
@@ -654,14 +1107,20 @@ impl<'g> Compiler<'g> {
             String(ref s) => {
                 let idx = {
                     let chunk = self.states.last_mut().unwrap().function.chunk_mut();
-                    chunk.string_constant(self.heap, s)
+                    chunk.string_constant(self.heap, s)?
                 };
 
-                self.emit(Op::Constant(idx))
+                if idx <= u8::MAX as u16 {
+                    self.emit(Op::Constant(idx as u8))
+                } else {
+                    self.emit(Op::ConstantLong(idx))
+                }
             },
 
             _ => panic!("not a constant")
         }
+
+        Ok(())
     }
 
     fn emit_number_literal(&mut self, n: f64) {
@@ -673,6 +1132,8 @@ impl<'g> Compiler<'g> {
         chunk.write_u64(value)
     }
 
+    // `JumpIfFalse` only peeks its condition, leaving it on the stack whether or not the jump is
+    // taken. Whoever emits it must pop the condition exactly once on both paths.
     fn emit_jze(&mut self) -> usize {
         let line = self.line();
         let chunk = self.chunk_mut();
@@ -694,29 +1155,105 @@ impl<'g> Compiler<'g> {
         chunk.len() - 2
     }
 
-    fn emit_loop(&mut self, ip: usize) {
+    fn emit_loop(&mut self, ip: usize) -> Result<(), CompileError> {
         let line = self.line();
         let chunk = self.chunk_mut();
         let sub = chunk.len() - ip + 3;
 
+        if sub > u16::MAX as usize {
+            return Err(CompileError::JumpTooFar(sub))
+        }
+
         let lo = (sub & 0xff) as u8;
         let hi = ((sub >> 8) & 0xff) as u8;
 
         chunk.write(Op::Loop, line);
         chunk.write_byte(lo);
         chunk.write_byte(hi);
+
+        Ok(())
     }
 
     fn ip(&self) -> usize {
         self.chunk().len()
     }
 
-    fn patch_jmp(&mut self, idx: usize) {
+    // Jumps are absolute, so every jump target must be within the first 64K of the chunk
+    fn patch_jmp(&mut self, idx: usize) -> Result<(), CompileError> {
         let jmp = self.ip();
+
+        if jmp > u16::MAX as usize {
+            return Err(CompileError::JumpTooFar(jmp))
+        }
         let lo = (jmp & 0xff) as u8;
         let hi = ((jmp >> 8) & 0xff) as u8;
 
         self.chunk_mut().write_byte_at(idx, lo);
         self.chunk_mut().write_byte_at(idx + 1, hi);
+
+        Ok(())
+    }
+}
+
+// The argument count of a call, as its instruction encodes it
+fn call_arity(args: &[ExprNode]) -> Result<u8, CompileError> {
+    if args.len() > MAX_ARGS {
+        return Err(CompileError::TooManyArguments(args.len()))
     }
+
+    Ok(args.len() as u8)
+}
+
+// Whether the node is an expression whose value is left on the stack, rather than a statement
+fn leaves_value(expr: &ExprNode) -> bool {
+    use self::Expr::*;
+
+    matches!(
+        expr.inner(),
+        Literal(_) | Var(_) | Mutate(..) | Binary(..) | Unary(..) | Not(_) | Neg(_) |
+        Call(_) | Invoke(..) | AnonFunction(_) | List(_) | Dict(..) | ValueBlock(..)
+    )
+}
+
+// Whether evaluating the expression can't change any variable, because it neither calls nor
+// assigns anything. It may still fail, e.g. on an undefined global
+fn without_side_effects(expr: &ExprNode) -> bool {
+    use self::Expr::*;
+
+    match expr.inner() {
+        Literal(_) | Var(_) | AnonFunction(_) => true,
+        Binary(ref lhs, _, ref rhs) => without_side_effects(lhs) && without_side_effects(rhs),
+        Unary(_, ref operand) | Not(ref operand) | Neg(ref operand) => without_side_effects(operand),
+        List(ref content) => content.iter().all(without_side_effects),
+        Dict(ref keys, ref values) => keys.iter().chain(values.iter()).all(without_side_effects),
+        _ => false,
+    }
+}
+
+// The first key and number of table entries, for matches on integers dense enough to be worth a
+// jump table
+fn jump_table_range(arms: &[(Literal, ExprNode)]) -> Option<(i64, usize)> {
+    if arms.len() < JUMP_TABLE_MIN_ARMS {
+        return None
+    }
+
+    let mut keys = Vec::new();
+
+    for (key, _) in arms.iter() {
+        match *key {
+            Literal::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => keys.push(n as i64),
+            _ => return None
+        }
+    }
+
+    let min = *keys.iter().min().unwrap();
+    let max = *keys.iter().max().unwrap();
+
+    let len = (max - min + 1) as usize;
+
+    if len > u8::MAX as usize || len > keys.len() * 2 {
+        return None
+    }
+
+    Some((min, len))
 }
@@ -0,0 +1,34 @@
+use std::fmt;
+
+use super::super::vm::ValidationError;
+use super::compiler::MAX_ARGS;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileError {
+    /// A jump spans more bytes than its 16-bit offset can encode.
+    JumpTooFar(usize),
+    /// A chunk needs more constants than the 16-bit constant indices can address.
+    ConstantTooFar(usize),
+    /// A call or method invocation passes more arguments than the VM supports.
+    TooManyArguments(usize),
+    /// A local variable was used without being bound in any enclosing scope of the function.
+    UnresolvedLocal(String),
+    /// Validation, when enabled, found a malformed chunk, which is a bug in the compiler.
+    Invalid(ValidationError),
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::CompileError::*;
+
+        match self {
+            JumpTooFar(offset) => write!(f, "jump offset {} exceeds the maximum of {}", offset, u16::MAX),
+            Invalid(ref error) => write!(f, "compiled to invalid bytecode: {}", error),
+            TooManyArguments(count) => write!(f, "call with {} arguments exceeds the maximum of {}", count, MAX_ARGS),
+            UnresolvedLocal(ref name) => write!(f, "no local variable named `{}` in scope", name),
+            ConstantTooFar(idx) => write!(f, "constant index {} exceeds the maximum of {}", idx, u16::MAX),
+        }
+    }
+}
+
+impl std::error::Error for CompileError {}
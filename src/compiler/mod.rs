@@ -1,6 +1,8 @@
 pub mod compiler;
+pub mod error;
 
 use super::vm::*;
 use super::ir::*;
 
-pub use self::compiler::*;
\ No newline at end of file
+pub use self::compiler::*;
+pub use self::error::*;
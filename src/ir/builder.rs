@@ -23,9 +23,20 @@ impl IrBuilder {
     }
 
     pub fn mutate(&mut self, lhs: ExprNode, rhs: ExprNode) {
-        let mutate = Expr::Mutate(lhs, rhs);
+        let mutate = self.assign(lhs, rhs);
 
-        self.emit(mutate.clone().node(TypeInfo::nil()))
+        self.emit(mutate);
+        self.emit(Expr::Pop.node(TypeInfo::nil()))
+    }
+
+    // Exchange the values of two variables, like `a, b = b, a`
+    pub fn swap(&mut self, lhs: ExprNode, rhs: ExprNode) {
+        self.emit(Expr::Swap(lhs, rhs).node(TypeInfo::nil()))
+    }
+
+    // Assignment as an expression, evaluating to the assigned value
+    pub fn assign(&self, lhs: ExprNode, rhs: ExprNode) -> ExprNode {
+        Expr::Mutate(lhs, rhs).node(TypeInfo::nil())
     }
 
     pub fn ret(&mut self, value: Option<ExprNode>) {
@@ -40,6 +51,24 @@ impl IrBuilder {
         )
     }
 
+    pub fn print(&mut self, value: ExprNode) {
+        self.emit(
+            Expr::Print(value).node(TypeInfo::nil())
+        )
+    }
+
+    // Print without a trailing newline
+    pub fn write(&mut self, value: ExprNode) {
+        self.emit(
+            Expr::Write(value).node(TypeInfo::nil())
+        )
+    }
+
+    // Suspend the program, handing the value to the host, see `VM::exec_resumable`
+    pub fn yield_(&self, value: ExprNode) -> ExprNode {
+        Expr::Yield(value).node(TypeInfo::nil())
+    }
+
     pub fn break_(&mut self) {
         self.emit(
             Expr::Break.node(TypeInfo::nil())
@@ -52,11 +81,14 @@ impl IrBuilder {
         Expr::List(content).node(TypeInfo::nil())
     }
 
+    // The collection may itself be an index, as in `a[i][j] = x`, modifying the inner collection
+    // in place
     pub fn set_element(&self, list: ExprNode, index: ExprNode, value: ExprNode) -> ExprNode {
         Expr::SetElement(list, index, value).node(TypeInfo::nil())
     }
 
-
+    /// A dict literal pairing up keys and values in order. When a key appears more than once, the
+    /// last of its values wins.
     pub fn dict(&self, keys: Vec<ExprNode>, values: Vec<ExprNode>) -> ExprNode {
         Expr::Dict(keys, values).node(TypeInfo::nil())
     }
@@ -88,14 +120,33 @@ impl IrBuilder {
         )
     }
 
+    /// Call the global function `name`, the usual way of calling natives like `print`.
+    pub fn call_named(&self, name: &str, args: Vec<ExprNode>, retty: Option<TypeInfo>) -> ExprNode {
+        self.call(self.var(Binding::global(name)), args, retty)
+    }
+
+    /// Call the function stored under `name` in the dict `receiver`. The dict takes the place of
+    /// the callee, so a function built with `method` sees it as `self`.
+    pub fn invoke(&self, receiver: ExprNode, name: &str, args: Vec<ExprNode>) -> ExprNode {
+        Expr::Invoke(receiver, name.to_owned(), args).node(TypeInfo::nil())
+    }
+
 
 
     pub fn binary(&self, lhs: ExprNode, op: BinaryOp, rhs: ExprNode) -> ExprNode {
         Expr::Binary(lhs, op, rhs).node(TypeInfo::nil())
     }
 
-    pub fn unary(op: UnaryOp, rhs: ExprNode) -> Expr {
-        Expr::Unary(op, rhs)
+    pub fn unary(&self, op: UnaryOp, rhs: ExprNode) -> ExprNode {
+        Expr::Unary(op, rhs).node(TypeInfo::nil())
+    }
+
+    pub fn neg(&self, rhs: ExprNode) -> ExprNode {
+        self.unary(UnaryOp::Neg, rhs)
+    }
+
+    pub fn not(&self, rhs: ExprNode) -> ExprNode {
+        self.unary(UnaryOp::Not, rhs)
     }
 
     pub fn int(&self, n: i32) -> ExprNode {
@@ -106,29 +157,45 @@ impl IrBuilder {
     }
 
     pub fn number(&self, n: f64) -> ExprNode {
-        let info = TypeInfo::new(Type::Float);
-        let lit = Literal::Number(n);
-
-        Expr::Literal(lit).node(info)
+        self.literal(Literal::Number(n))
     }
 
     pub fn string(&self, s: &str) -> ExprNode {
-        let info = TypeInfo::new(Type::String);
-        let lit = Literal::String(s.to_owned());
-
-        Expr::Literal(lit).node(info)
+        self.literal(Literal::String(s.to_owned()))
     }
 
     pub fn bool(&self, b: bool) -> ExprNode {
-        let info = TypeInfo::new(Type::Bool);
-        let lit = Literal::Boolean(b);
+        self.literal(Literal::Boolean(b))
+    }
+
+    pub fn nil(&self) -> ExprNode {
+        self.literal(Literal::Nil)
+    }
+
+    pub fn literal(&self, lit: Literal) -> ExprNode {
+        let info = match lit {
+            Literal::Number(_)  => TypeInfo::new(Type::Float),
+            Literal::String(_)  => TypeInfo::new(Type::String),
+            Literal::Boolean(_) => TypeInfo::new(Type::Bool),
+            Literal::Nil        => TypeInfo::new(Type::Nil),
+        };
 
         Expr::Literal(lit).node(info)
     }
 
 
 
-    pub fn function(&mut self, var: Binding, params: &[&str], mut body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
+    pub fn function(&mut self, var: Binding, params: &[&str], body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
+        self.function_with(var, params, false, body_build)
+    }
+
+    /// Like `function`, but the body may refer to the called closure as the local `self`, found
+    /// in the slot below the parameters.
+    pub fn method(&mut self, var: Binding, params: &[&str], body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
+        self.function_with(var, params, true, body_build)
+    }
+
+    fn function_with(&mut self, var: Binding, params: &[&str], method: bool, mut body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
         let mut body_builder = IrBuilder::new();
 
         body_build(&mut body_builder);
@@ -138,7 +205,7 @@ impl IrBuilder {
         let func_body = IrFunctionBody {
             params: params.iter().cloned().map(|x: &str|
                 Binding::local(x, var.depth.unwrap_or(0) + 1, var.function_depth + 1)).collect::<Vec<Binding>>(),
-            method: false,
+            method,
             inner: body
         };
 
@@ -162,6 +229,14 @@ impl IrBuilder {
         ).node(TypeInfo::nil())
     }
 
+    pub fn match_(&mut self, subject: ExprNode, arms: Vec<(Literal, ExprNode)>, default: Option<ExprNode>) -> ExprNode {
+        Expr::Match(
+            subject,
+            arms,
+            default
+        ).node(TypeInfo::nil())
+    }
+
     pub fn if_(&mut self, cond: ExprNode, then_build: fn(&mut IrBuilder), else_build: Option<fn(&mut IrBuilder)>) -> ExprNode {
         let mut then_builder = IrBuilder::new();
 
@@ -199,6 +274,55 @@ impl IrBuilder {
         ).node(TypeInfo::nil())
     }
 
+    // Like `while_`, with the condition built by its own builder. Anything emitted there is
+    // evaluated before the returned condition, on every iteration
+    pub fn while_loop(&mut self, mut cond_build: impl FnMut(&mut IrBuilder) -> ExprNode, mut body_build: impl FnMut(&mut IrBuilder)) -> ExprNode {
+        let mut cond_builder = IrBuilder::new();
+
+        let cond = cond_build(&mut cond_builder);
+
+        let cond = if cond_builder.program.is_empty() {
+            cond
+        } else {
+            Expr::ValueBlock(cond_builder.build(), cond).node(TypeInfo::nil())
+        };
+
+        let mut body_builder = IrBuilder::new();
+
+        body_build(&mut body_builder);
+
+        let body = Expr::Block(body_builder.build()).node(TypeInfo::nil());
+
+        Expr::While(
+            cond,
+            body,
+        ).node(TypeInfo::nil())
+    }
+
+    // A block evaluating to the expression returned by `body_build`, after whatever it emitted.
+    // Locals bound in the block go out of scope at its end.
+    pub fn value_block(&mut self, mut body_build: impl FnMut(&mut IrBuilder) -> ExprNode) -> ExprNode {
+        let mut body_builder = IrBuilder::new();
+
+        let value = body_build(&mut body_builder);
+
+        Expr::ValueBlock(body_builder.build(), value).node(TypeInfo::nil())
+    }
+
+    // Like `while_loop`, but the condition is tested after the body, so it always runs at least once
+    pub fn do_while(&mut self, mut body_build: impl FnMut(&mut IrBuilder), cond: ExprNode) -> ExprNode {
+        let mut body_builder = IrBuilder::new();
+
+        body_build(&mut body_builder);
+
+        let body = Expr::Block(body_builder.build()).node(TypeInfo::nil());
+
+        Expr::DoWhile(
+            body,
+            cond,
+        ).node(TypeInfo::nil())
+    }
+
 
 
     pub fn build(&self) -> Vec<ExprNode> {
@@ -126,16 +126,29 @@ pub struct Call {
 pub struct Node<T> {
     inner: Box<T>,
     type_info: TypeInfo,
+    span: Option<(u32, u32)>,
 }
 
 impl<T> Node<T> {
     pub fn new(inner: T, type_info: TypeInfo) -> Self {
         Node {
             inner: Box::new(inner),
-            type_info
+            type_info,
+            span: None,
         }
     }
 
+    /// Attach the span of source the node was parsed from, which the compiler records for the
+    /// code it generates, so runtime errors can point back at it.
+    pub fn with_span(mut self, start: u32, end: u32) -> Self {
+        self.span = Some((start, end));
+        self
+    }
+
+    pub fn span(&self) -> Option<(u32, u32)> {
+        self.span
+    }
+
     pub fn inner(&self) -> &T {
         &self.inner
     }
@@ -171,9 +184,11 @@ pub enum Expr {
 
     Var(Binding), // access binding
 
-    Mutate(ExprNode, ExprNode),
+    Mutate(ExprNode, ExprNode), // leaves the assigned value on the stack
+    Swap(ExprNode, ExprNode), // exchanges the values of two variables, leaving nothing
     Binary(ExprNode, BinaryOp, ExprNode),
     Call(Call),
+    Invoke(ExprNode, String, Vec<ExprNode>), // calls the named field of a dict, with the dict as `self`
     Function(IrFunction),
     AnonFunction(IrFunction), // variable here will be unique id
     Unary(UnaryOp, ExprNode),
@@ -184,12 +199,19 @@ pub enum Expr {
 
     If(ExprNode, ExprNode, Option<ExprNode>),
     While(ExprNode, ExprNode),
+    DoWhile(ExprNode, ExprNode), // the body runs once before the condition is first tested
+    Match(ExprNode, Vec<(Literal, ExprNode)>, Option<ExprNode>), // first matching arm wins
 
     List(Vec<ExprNode>),
     Dict(Vec<ExprNode>, Vec<ExprNode>), // They need to be the same size, funny enough
     SetElement(ExprNode, ExprNode, ExprNode),
 
     Block(Vec<ExprNode>),
+    ValueBlock(Vec<ExprNode>, ExprNode), // a block evaluating to its final expression
+
+    Print(ExprNode), // followed by a newline
+    Write(ExprNode),
+    Yield(ExprNode), // evaluates to nil once resumed
 
     Break,
     Pop,
@@ -12,6 +12,7 @@ pub mod compiler;
 mod tests {
     use super::vm::*;
     use super::ir::*;
+    use super::compiler::*;
 
     #[test]
     fn globals() {
@@ -22,7 +23,7 @@ mod tests {
 
         let mut vm = VM::new();
 
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -38,7 +39,7 @@ mod tests {
 
         let mut vm = VM::new();
 
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -55,7 +56,7 @@ mod tests {
         builder.bind(Binding::global("sum"), sum);
 
         let mut vm = VM::new();
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -97,7 +98,7 @@ mod tests {
         let built = builder.build();
 
         let mut vm = VM::new();
-        vm.exec(&built, true);
+        vm.exec(&built, true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -116,15 +117,15 @@ mod tests {
 
         builder.emit(call);
 
-        fn print(heap: &mut Heap<Object>, args: &[Value]) -> Value {
-            println!("{}", args[1].with_heap(heap));
-            Value::nil()
+        fn print(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            println!("{}", context.get_arg_with_heap(1));
+            Ok(Value::nil())
         }
 
         let mut vm = VM::new();
 
         vm.add_native("print", print, 1);
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
     }
 
     #[test]
@@ -154,7 +155,7 @@ mod tests {
         builder.bind(Binding::global("element"), right); // expect 777.0
 
         let mut vm = VM::new();
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!("{:#?}", vm.globals)
     }
@@ -219,14 +220,14 @@ mod tests {
 
         builder.emit(call); // :D
 
-        fn print_native(heap: &mut Heap<Object>, args: &[Value]) -> Value {
-            println!("{}", args[1].with_heap(heap));
-            Value::nil()
+        fn print_native(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            println!("{}", context.get_arg_with_heap(1));
+            Ok(Value::nil())
         }
 
         let mut vm = VM::new();
         vm.add_native("print", print_native, 1);
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
     }
 
     #[test]
@@ -250,8 +251,3894 @@ mod tests {
         builder.bind(Binding::global("test"), get_fruit);
 
         let mut vm = VM::new();
-        vm.exec(&builder.build(), true);
+        vm.exec(&builder.build(), true).unwrap();
 
         println!(" sad sad {:#?}", vm.globals)
     }
+
+    #[test]
+    fn heap_graph() {
+        let mut vm = VM::new();
+
+        let dict = vm.heap.insert(Object::Dict(Dict::empty())).into_handle();
+        let list = vm.heap.insert(Object::List(List::new(vec![dict.into()]))).into_handle();
+
+        vm.globals.insert("list".into(), list.into());
+
+        let dot = vm.dump_heap_graph();
+
+        assert!(dot.starts_with("digraph heap {"));
+        assert_eq!(dot.matches("[label=").count(), 2);
+        assert_eq!(dot.matches(" -> ").count(), 1);
+        assert!(dot.contains("<list [1]>"));
+        assert!(dot.contains("<dict [0]>"));
+    }
+
+    #[test]
+    fn native_recursion_limit() {
+        let mut builder = IrBuilder::new();
+
+        let f_binding = Binding::local("f", 0, 0);
+
+        // f calls back into the native, which calls f again ...
+        let f = builder.function(f_binding.clone(), &[], |builder| {
+            let callee = builder.var(Binding::global("reenter"));
+            let f = builder.var(Binding::local("f", 1, 0));
+
+            let call = builder.call(callee, vec![f], None);
+
+            builder.ret(Some(call))
+        });
+
+        builder.emit(f);
+
+        let callee = builder.var(Binding::global("reenter"));
+        let f = builder.var(f_binding);
+
+        let call = builder.call(callee, vec![f], None);
+
+        builder.emit(call);
+
+        fn reenter(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let f = context.get_arg(1);
+            context.call(f, &[])
+        }
+
+        let mut vm = VM::new();
+        vm.add_native("reenter", reenter, 1);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::NativeRecursion(64));
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn assignment_expression() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("x", 0, 0), builder.number(0.0));
+        builder.bind(Binding::local("y", 0, 0), builder.number(0.0));
+
+        let x = builder.var(Binding::local("x", 0, 0));
+        let y = builder.var(Binding::local("y", 0, 0));
+
+        // x = (y = 5)
+        let inner = builder.assign(y.clone(), builder.number(5.0));
+        builder.mutate(x.clone(), inner);
+
+        // A local declared afterwards must still get its own slot
+        builder.bind(Binding::local("z", 0, 0), builder.number(7.0));
+
+        builder.bind(Binding::global("x"), x);
+        builder.bind(Binding::global("y"), y);
+        builder.bind(Binding::global("z"), builder.var(Binding::local("z", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["x"].as_float(), 5.0);
+        assert_eq!(vm.globals["y"].as_float(), 5.0);
+        assert_eq!(vm.globals["z"].as_float(), 7.0);
+    }
+
+    #[test]
+    fn nop_padding() {
+        let mut builder = IrBuilder::new();
+
+        let sum = builder.binary(builder.number(1.0), BinaryOp::Add, builder.number(2.0));
+        builder.bind(Binding::global("x"), sum);
+
+        let program = builder.build();
+
+        let mut plain = VM::new();
+        plain.exec(&program, false).unwrap();
+
+        let mut padded = VM::new();
+        let mut function = Compiler::new(&mut padded.heap).compile(&program).unwrap();
+
+        {
+            // Fold `IMMEDIATE 1, IMMEDIATE 2, ADD` into `IMMEDIATE 3` and pad out the rest
+            let chunk = function.chunk_mut();
+            let raw = Value::float(3.0).to_raw();
+
+            for i in 0 .. 8 {
+                chunk.write_byte_at(1 + i, ((raw >> (i * 8)) & 0xFF) as u8);
+            }
+
+            chunk.pad_nop(9, 10);
+        }
+
+        let closure = padded.heap.insert(Object::Closure(Closure::new(function, Vec::new()))).into_handle();
+        padded.globals.insert("padded".into(), closure.into());
+
+        let mut caller = IrBuilder::new();
+
+        let callee = caller.var(Binding::global("padded"));
+        let call = caller.call(callee, vec![], None);
+        caller.emit(call);
+
+        padded.exec(&caller.build(), false).unwrap();
+
+        assert_eq!(plain.globals["x"].as_float(), 3.0);
+        assert_eq!(padded.globals["x"].as_float(), 3.0);
+    }
+
+    #[test]
+    fn balanced_branches() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+        builder.bind(Binding::local("hits", 0, 0), builder.number(0.0));
+
+        let i = builder.var(Binding::local("i", 0, 0));
+        let cond = builder.binary(i, BinaryOp::Lt, builder.number(5.0));
+
+        fn add_hits(builder: &mut IrBuilder, n: f64) {
+            let hits = builder.var(Binding::local("hits", 0, 0));
+            let sum = builder.binary(hits.clone(), BinaryOp::Add, builder.number(n));
+
+            builder.mutate(hits, sum)
+        }
+
+        fn then_build(builder: &mut IrBuilder) { add_hits(builder, 1.0) }
+        fn else_build(builder: &mut IrBuilder) { add_hits(builder, 10.0) }
+
+        let body = builder.while_(cond, |builder| {
+            let i = builder.var(Binding::local("i", 0, 0));
+
+            let cond = builder.binary(i.clone(), BinaryOp::Lt, builder.number(3.0));
+            let branch = builder.if_(cond, then_build, Some(else_build));
+            builder.emit(branch);
+
+            let next = builder.binary(i.clone(), BinaryOp::Add, builder.number(1.0));
+            builder.mutate(i, next)
+        });
+
+        builder.emit(body);
+
+        // Only lines up with its slot if everything above left the stack balanced
+        builder.bind(Binding::local("after", 0, 0), builder.number(99.0));
+
+        builder.bind(Binding::global("i"), builder.var(Binding::local("i", 0, 0)));
+        builder.bind(Binding::global("hits"), builder.var(Binding::local("hits", 0, 0)));
+        builder.bind(Binding::global("after"), builder.var(Binding::local("after", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["i"].as_float(), 5.0);
+        assert_eq!(vm.globals["hits"].as_float(), 23.0);
+        assert_eq!(vm.globals["after"].as_float(), 99.0);
+    }
+
+    #[test]
+    fn nested_loop_break() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("count", 0, 0), builder.number(0.0));
+
+        let count = builder.var(Binding::local("count", 0, 0));
+        let cond = builder.binary(count, BinaryOp::Lt, builder.number(10.0));
+
+        let body = builder.while_(cond, |builder| {
+            let count = builder.var(Binding::local("count", 0, 0));
+
+            let next = builder.binary(count.clone(), BinaryOp::Add, builder.number(1.0));
+            builder.mutate(count.clone(), next);
+
+            let done = builder.binary(count, BinaryOp::Equal, builder.number(3.0));
+            let branch = builder.if_(done, |builder| builder.break_(), None);
+            builder.emit(branch);
+
+            // The break above belongs to the outer loop, not this one
+            let inner = builder.while_(builder.bool(false), |_| {});
+            builder.emit(inner);
+        });
+
+        builder.emit(body);
+
+        builder.bind(Binding::global("count"), builder.var(Binding::local("count", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["count"].as_float(), 3.0);
+    }
+
+    #[test]
+    fn native_multiple_results() {
+        let mut builder = IrBuilder::new();
+
+        let callee = builder.var(Binding::global("divmod"));
+        let call = builder.call(callee, vec![builder.number(7.0), builder.number(2.0)], None);
+
+        // The call leaves two values, the second is picked up by a binding with no initializer
+        builder.bind(Binding::local("quotient", 0, 0), call);
+        builder.bind(Binding::local("remainder", 0, 0), Expr::Block(Vec::new()).node(TypeInfo::nil()));
+
+        builder.bind(Binding::global("quotient"), builder.var(Binding::local("quotient", 0, 0)));
+        builder.bind(Binding::global("remainder"), builder.var(Binding::local("remainder", 0, 0)));
+
+        fn divmod(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let a = context.get_arg(1).as_float();
+            let b = context.get_arg(2).as_float();
+
+            assert_eq!(context.stack_len(), 2);
+            assert!(context.pop().is_none());
+
+            context.push((a / b).floor().into());
+
+            Ok((a % b).into())
+        }
+
+        let mut vm = VM::new();
+        vm.add_native("divmod", divmod, 2);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["quotient"].as_float(), 3.0);
+        assert_eq!(vm.globals["remainder"].as_float(), 1.0);
+    }
+
+    #[test]
+    fn dict_natives() {
+        let mut builder = IrBuilder::new();
+
+        let dict = builder.dict(
+            vec![builder.string("a"), builder.string("b")],
+            vec![builder.number(1.0), builder.number(2.0)],
+        );
+
+        builder.bind(Binding::global("d"), dict);
+
+        let len = builder.var(Binding::global("dict_len"));
+        let call = builder.call(len, vec![builder.var(Binding::global("d"))], None);
+        builder.bind(Binding::global("before"), call);
+
+        let clear = builder.var(Binding::global("dict_clear"));
+        let call = builder.call(clear, vec![builder.var(Binding::global("d"))], None);
+        builder.emit(call);
+
+        let len = builder.var(Binding::global("dict_len"));
+        let call = builder.call(len, vec![builder.var(Binding::global("d"))], None);
+        builder.bind(Binding::global("after"), call);
+
+        let is_empty = builder.var(Binding::global("dict_is_empty"));
+        let call = builder.call(is_empty, vec![builder.var(Binding::global("d"))], None);
+        builder.bind(Binding::global("empty"), call);
+
+        let mut vm = VM::new();
+        vm.add_std_dict();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["before"].as_float(), 2.0);
+        assert_eq!(vm.globals["after"].as_float(), 0.0);
+        assert!(vm.globals["empty"].truthy());
+    }
+
+    #[test]
+    fn stack_snapshot() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("a", 0, 0), builder.number(1.0));
+        builder.bind(Binding::local("b", 0, 0), builder.bool(true));
+
+        let callee = builder.var(Binding::global("snapshot"));
+        let call = builder.call(callee, vec![], None);
+        builder.emit(call);
+
+        fn snapshot(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let stack = context.vm.stack_snapshot();
+
+            // The script closure, both locals, then the native itself
+            assert_eq!(stack.len(), 4);
+            assert_eq!(stack[1..3], [Variant::Float(1.0), Variant::True]);
+
+            context.vm.globals.insert("checked".into(), true.into());
+
+            Ok(Value::nil())
+        }
+
+        let mut vm = VM::new();
+        vm.add_native("snapshot", snapshot, 0);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert!(vm.globals["checked"].truthy());
+    }
+
+    #[test]
+    fn chained_index() {
+        fn nested(builder: &IrBuilder, chained: bool) -> ExprNode {
+            let inner = builder.list(vec![builder.number(1.0), builder.number(2.0), builder.number(3.0)]);
+            let middle = builder.list(vec![builder.number(0.0), inner]);
+            let outer = builder.list(vec![middle]);
+
+            let mut access = outer;
+
+            for key in [0.0, 1.0, 2.0].iter() {
+                // A block around the collection hides the chain from the compiler
+                if !chained {
+                    access = Expr::Block(vec![access]).node(TypeInfo::nil())
+                }
+
+                access = builder.binary(access, BinaryOp::Index, builder.number(*key))
+            }
+
+            access
+        }
+
+        let mut vm = VM::new();
+        let mut code_len = Vec::new();
+
+        for chained in [true, false].iter() {
+            let mut builder = IrBuilder::new();
+
+            let access = nested(&builder, *chained);
+            builder.bind(Binding::global("x"), access);
+
+            let program = builder.build();
+
+            code_len.push(Compiler::new(&mut vm.heap).compile(&program).unwrap().chunk().len());
+
+            vm.exec(&program, false).unwrap();
+
+            assert_eq!(vm.globals["x"].as_float(), 3.0);
+        }
+
+        assert!(code_len[0] < code_len[1]);
+    }
+
+    #[test]
+    fn weak_handles() {
+        let mut heap = Heap::new();
+
+        let rooted = heap.insert(Object::String("weak".into()));
+        let weak = heap.downgrade(&rooted);
+
+        heap.clean();
+
+        assert_eq!(weak.upgrade(&heap), Some(rooted.handle()));
+
+        drop(rooted);
+        heap.clean();
+
+        assert_eq!(weak.upgrade(&heap), None);
+    }
+
+    #[test]
+    fn comparisons() {
+        let mut builder = IrBuilder::new();
+
+        let lt = builder.binary(builder.string("a"), BinaryOp::Lt, builder.string("b"));
+        builder.bind(Binding::global("lt"), lt);
+
+        let eq = builder.binary(builder.string("a"), BinaryOp::Equal, builder.string("a"));
+        builder.bind(Binding::global("eq"), eq);
+
+        let mismatch = builder.binary(builder.bool(true), BinaryOp::Equal, builder.number(1.0));
+        builder.bind(Binding::global("mismatch"), mismatch);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert!(vm.globals["lt"].truthy());
+        assert!(vm.globals["eq"].truthy());
+        assert!(!vm.globals["mismatch"].truthy());
+
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list(vec![builder.number(1.0)]);
+        let lt = builder.binary(list, BinaryOp::Lt, builder.number(2.0));
+        builder.bind(Binding::global("lt"), lt);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("can't compare list with number".into()));
+    }
+
+    #[test]
+    fn while_loop_builder() {
+        let mut builder = IrBuilder::new();
+
+        let below = builder.function(Binding::local("below", 0, 0), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            let cond = builder.binary(n, BinaryOp::Lt, builder.number(5.0));
+
+            builder.ret(Some(cond))
+        });
+
+        builder.emit(below);
+        builder.bind(Binding::local("count", 0, 0), builder.number(0.0));
+
+        let body = builder.while_loop(
+            |builder| {
+                let callee = builder.var(Binding::local("below", 0, 0));
+                let count = builder.var(Binding::local("count", 0, 0));
+
+                builder.call(callee, vec![count], None)
+            },
+            |builder| {
+                let count = builder.var(Binding::local("count", 0, 0));
+                let next = builder.binary(count.clone(), BinaryOp::Add, builder.number(1.0));
+
+                builder.mutate(count, next)
+            },
+        );
+
+        builder.emit(body);
+
+        builder.bind(Binding::global("count"), builder.var(Binding::local("count", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["count"].as_float(), 5.0);
+    }
+
+    #[test]
+    fn print_and_write() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let mut builder = IrBuilder::new();
+
+        builder.write(builder.string("a"));
+        builder.write(builder.string("b"));
+        builder.print(builder.number(1.0));
+        builder.print(builder.bool(true));
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+
+        let mut vm = VM::new();
+        vm.set_print_hook(move |text| sink.borrow_mut().push_str(text));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(*output.borrow(), "ab1\ntrue\n");
+    }
+
+    #[test]
+    fn dict_key_paths() {
+        let mut builder = IrBuilder::new();
+
+        let dict = builder.dict(
+            vec![builder.string("key"), builder.number(1.0)],
+            vec![builder.number(1.0), builder.number(2.0)],
+        );
+
+        builder.bind(Binding::global("d"), dict);
+
+        // A concatenation allocates a new string, distinct from the literal's constant
+        let key = builder.binary(builder.string("k"), BinaryOp::Add, builder.string("ey"));
+        let set = builder.set_element(builder.var(Binding::global("d")), key, builder.number(10.0));
+        builder.emit(set);
+
+        let set = builder.set_element(builder.var(Binding::global("d")), builder.number(1.0), builder.number(20.0));
+        builder.emit(set);
+
+        let get = builder.binary(builder.var(Binding::global("d")), BinaryOp::Index, builder.string("key"));
+        builder.bind(Binding::global("by_string"), get);
+
+        let get = builder.binary(builder.var(Binding::global("d")), BinaryOp::Index, builder.number(1.0));
+        builder.bind(Binding::global("by_number"), get);
+
+        let len = builder.var(Binding::global("dict_len"));
+        let call = builder.call(len, vec![builder.var(Binding::global("d"))], None);
+        builder.bind(Binding::global("len"), call);
+
+        let mut vm = VM::new();
+        vm.add_std_dict();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["by_string"].as_float(), 10.0);
+        assert_eq!(vm.globals["by_number"].as_float(), 20.0);
+        assert_eq!(vm.globals["len"].as_float(), 2.0);
+    }
+
+    #[test]
+    fn local_shadowing() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("x", 0, 0), builder.number(1.0));
+
+        let mut inner = IrBuilder::new();
+
+        inner.bind(Binding::local("x", 0, 0), inner.number(2.0));
+        inner.bind(Binding::global("inner"), inner.var(Binding::local("x", 0, 0)));
+
+        builder.emit(Expr::Block(inner.build()).node(TypeInfo::nil()));
+
+        // Breaking out of the loop discards the local declared in its body
+        let body = builder.while_(builder.bool(true), |builder| {
+            builder.bind(Binding::local("x", 0, 0), builder.number(3.0));
+            builder.break_()
+        });
+
+        builder.emit(body);
+
+        builder.bind(Binding::local("y", 0, 0), builder.number(4.0));
+
+        builder.bind(Binding::global("outer"), builder.var(Binding::local("x", 0, 0)));
+        builder.bind(Binding::global("y"), builder.var(Binding::local("y", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["inner"].as_float(), 2.0);
+        assert_eq!(vm.globals["outer"].as_float(), 1.0);
+        assert_eq!(vm.globals["y"].as_float(), 4.0);
+    }
+
+    #[test]
+    fn immediate_literals_in_collections() {
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list(vec![builder.bool(true), builder.nil(), builder.bool(false)]);
+        builder.bind(Binding::global("list"), list);
+
+        let dict = builder.dict(vec![builder.string("k"), builder.bool(false)], vec![builder.nil(), builder.bool(true)]);
+        builder.bind(Binding::global("dict"), dict);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let list = vm.globals["list"].as_object().unwrap();
+        let content = vm.heap.get(list).unwrap().as_list().unwrap().content
+            .iter()
+            .map(|value| value.decode())
+            .collect::<Vec<_>>();
+
+        assert_eq!(content, vec![Variant::True, Variant::Nil, Variant::False]);
+
+        let dict = vm.globals["dict"].as_object().unwrap();
+        let dict = vm.heap.get(dict).unwrap().as_dict().unwrap();
+
+        let key = |variant| HashValue { variant };
+
+        assert_eq!(dict.content.len(), 2);
+        assert_eq!(dict.get(&key(HashVariant::Str("k".into()))).unwrap().decode(), Variant::Nil);
+        assert_eq!(dict.get(&key(HashVariant::Bool(false))).unwrap().decode(), Variant::True);
+    }
+
+    #[test]
+    fn stack_size() {
+        fn program(depth: f64) -> Vec<ExprNode> {
+            let mut builder = IrBuilder::new();
+
+            let deep = builder.function(Binding::local("deep", 0, 0), &["n"], |builder| {
+                let n = builder.var(Binding::local("n", 1, 1));
+                let done = builder.binary(n.clone(), BinaryOp::Equal, builder.number(0.0));
+
+                let branch = builder.if_(done, |builder| builder.ret(Some(builder.number(0.0))), None);
+                builder.emit(branch);
+
+                let callee = builder.var(Binding::global("deep"));
+                let arg = builder.binary(n, BinaryOp::Sub, builder.number(1.0));
+                let call = builder.call(callee, vec![arg], None);
+
+                builder.ret(Some(builder.binary(call, BinaryOp::Add, builder.number(1.0))))
+            });
+
+            builder.emit(deep);
+            builder.bind(Binding::global("deep"), builder.var(Binding::local("deep", 0, 0)));
+
+            let callee = builder.var(Binding::global("deep"));
+            let call = builder.call(callee, vec![builder.number(depth)], None);
+            builder.bind(Binding::global("result"), call);
+
+            builder.build()
+        }
+
+        let mut vm = VM::new();
+        let error = vm.exec(&program(3000.0), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::StackOverflow(4096));
+
+        vm.set_stack_size(1 << 16);
+        vm.exec(&program(3000.0), false).unwrap();
+
+        assert_eq!(vm.globals["result"].as_float(), 3000.0);
+    }
+
+    #[test]
+    fn malformed_closure() {
+        let mut vm = VM::new();
+
+        let mut inner = FunctionBuilder::new("inner", 0);
+        inner.set_upvalue_count(1);
+
+        let inner = vm.heap.insert(Object::Function(inner.build())).into_handle();
+
+        // No upvalue pair follows the closure, so its first byte would be read from `NIL`
+        let mut outer = FunctionBuilder::new("outer", 0);
+
+        {
+            let chunk = outer.chunk_mut();
+            let idx = chunk.add_constant(inner.into()).unwrap();
+
+            chunk.write(Op::Closure, 1);
+            chunk.write_byte(idx as u8);
+            chunk.write(Op::Nil, 1);
+            chunk.write(Op::Return, 1);
+        }
+
+        let closure = Closure::new(outer.build(), Vec::new());
+        let closure = vm.heap.insert(Object::Closure(closure)).into_handle();
+        vm.globals.insert("outer".into(), closure.into());
+
+        let mut builder = IrBuilder::new();
+
+        let callee = builder.var(Binding::global("outer"));
+        let call = builder.call(callee, vec![], None);
+        builder.emit(call);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("malformed closure, expected 1 upvalues".into()));
+    }
+
+    #[test]
+    fn nan_canonicalization() {
+        let patterns = [
+            f64::NAN.to_bits(),
+            0x7ffc_0000_0000_0001, // would look like the `true` tag
+            0xfffc_0000_0000_1234, // would look like a handle
+            0xfff8_0000_0000_0000,
+            0x7ff0_0000_0000_0001, // signaling
+        ];
+
+        for bits in patterns.iter() {
+            match Value::float(f64::from_bits(*bits)).decode() {
+                Variant::Float(f) => assert!(f.is_nan()),
+                other => panic!("{:#x} decoded as {:?}", bits, other),
+            }
+        }
+    }
+
+    #[test]
+    fn rename_function() {
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list(vec![]);
+        let cmp = builder.binary(list, BinaryOp::Lt, builder.number(1.0));
+        builder.bind(Binding::global("x"), cmp);
+
+        let mut vm = VM::new();
+        let mut function = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+
+        function.set_name("<anon@line 12>");
+
+        assert_eq!(function.name(), "<anon@line 12>");
+        assert_eq!(function.chunk().name(), "<anon@line 12>");
+
+        let closure = vm.heap.insert(Object::Closure(Closure::new(function, Vec::new()))).into_handle();
+        vm.globals.insert("anon".into(), closure.into());
+
+        let mut caller = IrBuilder::new();
+
+        let callee = caller.var(Binding::global("anon"));
+        let call = caller.call(callee, vec![], None);
+        caller.emit(call);
+
+        let error = vm.exec(&caller.build(), false).unwrap_err();
+
+        assert_eq!(error.backtrace[0].name, "<anon@line 12>");
+    }
+
+    #[test]
+    fn loop_too_large() {
+        let mut builder = IrBuilder::new();
+
+        let body = builder.while_(builder.bool(false), |builder| {
+            // Each statement is an immediate and a global definition, a little over 10 bytes
+            for _ in 0 .. 7000 {
+                builder.bind(Binding::global("x"), builder.number(1.0))
+            }
+        });
+
+        builder.emit(body);
+
+        let mut vm = VM::new();
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        match error.kind {
+            ErrorKind::Compile(CompileError::JumpTooFar(_)) => (),
+            kind => panic!("expected a compile error, got {:?}", kind),
+        }
+    }
+
+    #[test]
+    fn host_built_dict() {
+        let mut vm = VM::new();
+
+        let key: Value = vm.heap.insert(Object::String("name".into())).into_handle().into();
+        let name: Value = vm.heap.insert(Object::String("zub".into())).into_handle().into();
+
+        let dict = vm.new_dict(vec![(key, name), (1.0.into(), true.into())]).unwrap();
+        vm.globals.insert("d".into(), dict);
+
+        let mut builder = IrBuilder::new();
+
+        let get = builder.binary(builder.var(Binding::global("d")), BinaryOp::Index, builder.string("name"));
+        builder.bind(Binding::global("name"), get);
+
+        let get = builder.binary(builder.var(Binding::global("d")), BinaryOp::Index, builder.number(1.0));
+        builder.bind(Binding::global("one"), get);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let name = vm.globals["name"].as_object().unwrap();
+
+        assert_eq!(vm.heap.get(name).unwrap().as_string().unwrap(), "zub");
+        assert!(vm.globals["one"].truthy());
+
+        let list = vm.new_list(Vec::new());
+        let error = vm.new_dict(vec![(list, Value::nil())]).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("can't use list as a dict key".into()));
+    }
+
+    #[test]
+    fn missing_return() {
+        let mut vm = VM::new();
+
+        let mut function = FunctionBuilder::new("truncated", 0);
+        function.chunk_mut().write(Op::Nil, 1);
+
+        let closure = Closure::new(function.build(), Vec::new());
+        let closure = vm.heap.insert(Object::Closure(closure)).into_handle();
+        vm.globals.insert("truncated".into(), closure.into());
+
+        let mut builder = IrBuilder::new();
+
+        let callee = builder.var(Binding::global("truncated"));
+        let call = builder.call(callee, vec![], None);
+        builder.emit(call);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("reached the end of the chunk without returning".into()));
+        assert_eq!(error.backtrace[0].name, "truncated");
+    }
+
+    #[test]
+    fn list_concatenation() {
+        let mut builder = IrBuilder::new();
+
+        let a = builder.list(vec![builder.number(1.0), builder.number(2.0)]);
+        builder.bind(Binding::global("a"), a);
+
+        let b = builder.list(vec![builder.number(3.0)]);
+        builder.bind(Binding::global("b"), b);
+
+        let concat = builder.var(Binding::global("concat_lists"));
+        let call = builder.call(concat, vec![builder.var(Binding::global("a")), builder.var(Binding::global("b"))], None);
+        builder.bind(Binding::global("c"), call);
+
+        let extend = builder.var(Binding::global("extend"));
+        let call = builder.call(extend, vec![builder.var(Binding::global("b")), builder.var(Binding::global("a"))], None);
+        builder.emit(call);
+
+        let mut vm = VM::new();
+        vm.add_std_list();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let floats = |vm: &VM, name: &str| {
+            let list = vm.globals[name].as_object().unwrap();
+
+            vm.heap.get(list).unwrap().as_list().unwrap().content
+                .iter()
+                .map(Value::as_float)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(floats(&vm, "c"), vec![1.0, 2.0, 3.0]);
+        assert_eq!(floats(&vm, "b"), vec![3.0, 1.0, 2.0]);
+        assert_eq!(floats(&vm, "a"), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn literals() {
+        let mut builder = IrBuilder::new();
+
+        let literals = vec![
+            Literal::Number(1.5),
+            Literal::String("zub".into()),
+            Literal::Boolean(true),
+            Literal::Nil,
+        ];
+
+        let list = builder.list(literals.into_iter().map(|lit| builder.literal(lit)).collect());
+        builder.bind(Binding::global("list"), list);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let list = vm.globals["list"].as_object().unwrap();
+        let content = &vm.heap.get(list).unwrap().as_list().unwrap().content;
+
+        assert_eq!(content[0].as_float(), 1.5);
+        assert_eq!(format!("{}", content[1].with_heap(&vm.heap)), "zub");
+        assert_eq!(content[2].decode(), Variant::True);
+        assert_eq!(content[3].decode(), Variant::Nil);
+    }
+
+    #[test]
+    fn wide_upvalues() {
+        // A function holds at most 255 locals, so the captured variables are split between the
+        // top-level and an enclosing function
+        let mut builder = IrBuilder::new();
+
+        for i in 0 .. 150 {
+            builder.bind(Binding::local(&format!("v{}", i), 0, 0), builder.number(1.0));
+        }
+
+        let middle = builder.function(Binding::local("middle", 0, 0), &[], |builder| {
+            for i in 0 .. 150 {
+                builder.bind(Binding::local(&format!("w{}", i), 1, 1), builder.number(2.0));
+            }
+
+            let inner = builder.function(Binding::local("inner", 1, 1), &[], |builder| {
+                // Summed one pair at a time, as a single expression this deep overflows the native
+                // stack of debug builds
+                builder.bind(Binding::local("sum", 2, 2), builder.number(0.0));
+
+                for i in 0 .. 150 {
+                    let v = builder.var(Binding::local(&format!("v{}", i), 2, 0));
+                    let w = builder.var(Binding::local(&format!("w{}", i), 2, 1));
+
+                    let sum = builder.var(Binding::local("sum", 2, 2));
+                    let pair = builder.binary(v, BinaryOp::Add, w);
+
+                    builder.mutate(sum.clone(), builder.binary(sum, BinaryOp::Add, pair))
+                }
+
+                builder.ret(Some(builder.var(Binding::local("sum", 2, 2))))
+            });
+
+            builder.emit(inner);
+
+            let callee = builder.var(Binding::local("inner", 1, 1));
+            let call = builder.call(callee, vec![], None);
+
+            builder.ret(Some(call))
+        });
+
+        builder.emit(middle);
+
+        let callee = builder.var(Binding::local("middle", 0, 0));
+        let call = builder.call(callee, vec![], None);
+        builder.bind(Binding::global("sum"), call);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["sum"].as_float(), 450.0);
+    }
+
+    #[test]
+    fn deadline() {
+        use std::time::{ Duration, Instant };
+
+        let mut builder = IrBuilder::new();
+
+        let forever = builder.while_(builder.bool(true), |_| {});
+        builder.emit(forever);
+
+        let mut vm = VM::new();
+        vm.set_deadline_interval(256);
+
+        let deadline = Instant::now() + Duration::from_millis(20);
+        let error = vm.exec_with_deadline(&builder.build(), deadline).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Timeout);
+    }
+
+    fn digit_match(builder: &mut IrBuilder, subject: ExprNode) -> ExprNode {
+        let arms = (0 .. 10).map(|i| {
+            let bind = Expr::Bind(Binding::global("r"), builder.number(i as f64 * 10.0));
+            (Literal::Number(i as f64), Expr::Block(vec![bind.node(TypeInfo::nil())]).node(TypeInfo::nil()))
+        }).collect();
+
+        let default = Expr::Bind(Binding::global("r"), builder.number(-1.0)).node(TypeInfo::nil());
+
+        builder.match_(subject, arms, Some(default))
+    }
+
+    #[test]
+    fn match_jump_table() {
+        let cases: Vec<(Literal, f64)> = vec![
+            (Literal::Number(0.0), 0.0),
+            (Literal::Number(3.0), 30.0),
+            (Literal::Number(9.0), 90.0),
+            (Literal::Number(10.0), -1.0),
+            (Literal::Number(-1.0), -1.0),
+            (Literal::Number(2.5), -1.0),
+            (Literal::String("3".into()), -1.0),
+        ];
+
+        for (subject, expected) in cases {
+            let mut builder = IrBuilder::new();
+
+            let subject = builder.literal(subject);
+            let match_ = digit_match(&mut builder, subject);
+            builder.emit(match_);
+
+            let mut vm = VM::new();
+            vm.exec(&builder.build(), false).unwrap();
+
+            assert_eq!(vm.globals["r"].as_float(), expected);
+        }
+
+        // The subject is an immediate, followed by the table
+        let mut builder = IrBuilder::new();
+
+        let subject = builder.number(4.0);
+        let match_ = digit_match(&mut builder, subject);
+        builder.emit(match_);
+
+        let mut vm = VM::new();
+        let function = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+
+        let mut probe = Chunk::new("probe".into());
+        probe.write(Op::JumpTable, 0);
+
+        assert_eq!(function.chunk().read_byte(9), probe.read_byte(0));
+    }
+
+    #[test]
+    fn match_chain() {
+        let mut builder = IrBuilder::new();
+
+        let arms = vec![
+            (Literal::String("a".into()), Expr::Bind(Binding::global("r"), builder.number(1.0)).node(TypeInfo::nil())),
+            (Literal::String("b".into()), Expr::Bind(Binding::global("r"), builder.number(2.0)).node(TypeInfo::nil())),
+        ];
+
+        let match_ = builder.match_(builder.string("b"), arms, None);
+        builder.emit(match_);
+
+        builder.bind(Binding::local("after", 0, 0), builder.number(3.0));
+        builder.bind(Binding::global("after"), builder.var(Binding::local("after", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["r"].as_float(), 2.0);
+        assert_eq!(vm.globals["after"].as_float(), 3.0);
+    }
+
+    #[test]
+    fn pretty_print() {
+        let mut vm = VM::new();
+
+        let key: Value = vm.heap.insert(Object::String("name".into())).into_handle().into();
+        let name: Value = vm.heap.insert(Object::String("zub".into())).into_handle().into();
+
+        let empty = vm.new_list(vec![]);
+        let dict = vm.new_dict(vec![(key, name), (1.0.into(), empty)]).unwrap();
+        let list = vm.new_list(vec![1.5.into(), dict, Value::nil()]);
+
+        assert_eq!(
+            list.pretty(&vm.heap, 2),
+            "[\n  1.5,\n  {\n    \"name\": \"zub\",\n    1: []\n  },\n  nil\n]"
+        );
+
+        // A list containing itself
+        let handle = list.as_object().unwrap();
+        if let Some(Object::List(ref mut content)) = vm.heap.get_mut(handle) {
+            content.push(list)
+        }
+
+        assert!(list.pretty(&vm.heap, 2).ends_with("  nil,\n  ...\n]"));
+    }
+
+    #[test]
+    fn insert_with() {
+        let mut heap = Heap::new();
+
+        let rooted = unsafe {
+            heap.insert_with(|slot| {
+                slot.write(Object::List(List::new(vec![Value::nil(); 1024])));
+            })
+        };
+
+        heap.clean();
+
+        let list = heap.get(&rooted).unwrap().as_list().unwrap();
+        assert_eq!(list.content.len(), 1024);
+
+        let handle = rooted.handle();
+        drop(rooted);
+        heap.clean();
+
+        assert!(!heap.contains(handle));
+    }
+
+    #[test]
+    fn nested_exec() {
+        // Runs a program with locals of its own, then one that fails
+        fn handle_event(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let mut builder = IrBuilder::new();
+
+            builder.bind(Binding::local("x", 0, 0), builder.number(context.get_arg(1).as_float()));
+            builder.bind(Binding::global("handled"), builder.var(Binding::local("x", 0, 0)));
+
+            context.exec(&builder.build())?;
+
+            let mut builder = IrBuilder::new();
+
+            // Wrong number of arguments
+            let call = builder.call(builder.var(Binding::global("handle_event")), vec![], None);
+            builder.bind(Binding::local("y", 0, 0), builder.number(2.0));
+            builder.bind(Binding::global("never"), call);
+
+            assert!(context.exec(&builder.build()).is_err());
+
+            Ok(context.get_arg(1))
+        }
+
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("a", 0, 0), builder.number(1.0));
+
+        let call = builder.call(builder.var(Binding::global("handle_event")), vec![builder.number(5.0)], None);
+        builder.bind(Binding::local("b", 0, 0), call);
+
+        let sum = builder.binary(builder.var(Binding::local("a", 0, 0)), BinaryOp::Add, builder.var(Binding::local("b", 0, 0)));
+        builder.bind(Binding::global("sum"), sum);
+
+        let mut vm = VM::new();
+        vm.add_native("handle_event", handle_event, 1);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["handled"].as_float(), 5.0);
+        assert_eq!(vm.globals["sum"].as_float(), 6.0);
+        assert!(!vm.globals.contains_key("never"));
+        assert!(vm.stack.is_empty());
+        assert!(vm.frames.is_empty());
+    }
+
+    #[test]
+    fn wide_constants() {
+        let mut builder = IrBuilder::new();
+
+        let strings = builder.function(Binding::local("strings", 0, 0), &[], |builder| {
+            // Lists hold at most 255 literal elements
+            let lists = (0 .. 3).map(|j| {
+                builder.list((0 .. 100).map(|i| builder.string(&format!("s{}", j * 100 + i))).collect())
+            }).collect();
+
+            let list = builder.list(lists);
+            builder.ret(Some(list))
+        });
+
+        builder.emit(strings);
+
+        let call = builder.call(builder.var(Binding::local("strings", 0, 0)), vec![], None);
+        builder.bind(Binding::global("strings"), call);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let lists = vm.globals["strings"].as_object().unwrap();
+        let last = vm.heap.get(lists).unwrap().as_list().unwrap().content[2];
+        let content = &vm.heap.get(last.as_object().unwrap()).unwrap().as_list().unwrap().content;
+
+        assert_eq!(format!("{}", content[0].with_heap(&vm.heap)), "s200");
+        assert_eq!(format!("{}", content[99].with_heap(&vm.heap)), "s299");
+    }
+
+    #[test]
+    fn wide_constant_operands() {
+        let mut builder = IrBuilder::new();
+
+        // Takes up the first 300 constants, leaving every name below to a 16-bit index
+        let lists = (0 .. 3).map(|j| {
+            builder.list((0 .. 100).map(|i| builder.string(&format!("s{}", j * 100 + i))).collect())
+        }).collect();
+
+        builder.bind(Binding::global("filler"), builder.list(lists));
+
+        let double = builder.function(Binding::global("double"), &["x"], |builder| {
+            let x = builder.var(Binding::local("x", 1, 1));
+            builder.ret(Some(builder.binary(x.clone(), BinaryOp::Add, x)))
+        });
+
+        builder.emit(double);
+
+        let get = builder.method(Binding::global("get"), &[], |builder| {
+            let this = builder.var(Binding::local("self", 1, 1));
+            builder.ret(Some(builder.binary(this, BinaryOp::Index, builder.string("count"))))
+        });
+
+        builder.emit(get);
+
+        let counter = builder.dict(
+            vec![builder.string("count"), builder.string("get")],
+            vec![builder.number(21.0), builder.var(Binding::global("get"))],
+        );
+
+        builder.bind(Binding::global("counter"), counter);
+
+        let count = builder.invoke(builder.var(Binding::global("counter")), "get", vec![]);
+        let call = builder.call(builder.var(Binding::global("double")), vec![count], None);
+        builder.mutate(builder.var(Binding::global("filler")), call);
+
+        let program = builder.build();
+
+        let mut vm = VM::new();
+
+        let function = vm.compile_only(&program).unwrap();
+        function.chunk().validate(&vm.heap).unwrap();
+
+        let listing = Disassembler::new(function.chunk(), &vm.heap).listing();
+
+        for op in &["CLOSURE_LONG", "DEFINE_GLOBAL_LONG\tdouble", "GET_GLOBAL_LONG\tcounter", "INVOKE_LONG_0 get", "SET_GLOBAL_LONG\tfiller"] {
+            assert!(listing.contains(op), "no {} in {}", op, listing);
+        }
+
+        vm.exec(&program, false).unwrap();
+
+        assert_eq!(vm.globals["filler"].as_float(), 42.0);
+    }
+
+    #[test]
+    fn constant_pool_limit() {
+        let mut chunk = Chunk::new("constants".into());
+
+        for i in 0 ..= u16::MAX as usize {
+            assert_eq!(chunk.add_constant((i as f64).into()), Ok(i as u16));
+        }
+
+        // Constants already in the pool still resolve
+        assert_eq!(chunk.add_constant(0.0.into()), Ok(0));
+        assert_eq!(chunk.add_constant((-1.0).into()), Err(CompileError::ConstantTooFar(u16::MAX as usize + 1)));
+    }
+
+    #[test]
+    fn function_keys() {
+        let mut builder = IrBuilder::new();
+
+        for name in &["f", "g"] {
+            let function = builder.function(Binding::global(name), &[], |builder| {
+                builder.ret(None)
+            });
+
+            builder.emit(function)
+        }
+
+        let dict = builder.dict(
+            vec![builder.var(Binding::global("f")), builder.var(Binding::global("g"))],
+            vec![builder.number(1.0), builder.number(2.0)],
+        );
+
+        builder.bind(Binding::global("d"), dict);
+
+        let get = builder.binary(builder.var(Binding::global("d")), BinaryOp::Index, builder.var(Binding::global("g")));
+        builder.bind(Binding::global("by_g"), get);
+
+        let len = builder.call(builder.var(Binding::global("dict_len")), vec![builder.var(Binding::global("d"))], None);
+        builder.bind(Binding::global("len"), len);
+
+        let mut vm = VM::new();
+        vm.add_std_dict();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["by_g"].as_float(), 2.0);
+        assert_eq!(vm.globals["len"].as_float(), 2.0);
+
+        assert!(vm.globals["f"].is_same(&vm.globals["f"]));
+        assert!(!vm.globals["f"].is_same(&vm.globals["g"]));
+
+        // Keys keep their functions alive
+        vm.globals.remove("f");
+        vm.globals.remove("g");
+        let roots = vm.globals.values().filter_map(|v| v.as_object()).collect::<Vec<_>>();
+        vm.heap.clean_excluding(roots);
+
+        let d = vm.globals["d"].as_object().unwrap();
+        let dict = vm.heap.get(d).unwrap().as_dict().unwrap();
+
+        for key in dict.content.keys() {
+            if let HashVariant::Object(handle) = key.variant {
+                assert!(vm.heap.contains(handle))
+            }
+        }
+    }
+
+    #[test]
+    fn unresolved_local() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("x"), builder.var(Binding::local("missing", 0, 0)));
+
+        let mut vm = VM::new();
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Compile(CompileError::UnresolvedLocal("missing".into())));
+        assert_eq!(format!("{}", error.kind), "no local variable named `missing` in scope");
+
+        // The reserved slot of a function isn't a variable either
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("y"), builder.var(Binding::local("", 0, 0)));
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Compile(CompileError::UnresolvedLocal("".into())));
+    }
+
+    #[test]
+    fn strict_globals() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("declared"), builder.number(1.0));
+        builder.mutate(builder.var(Binding::global("declared")), builder.number(2.0));
+        builder.mutate(builder.var(Binding::global("typo")), builder.number(3.0));
+
+        let program = builder.build();
+
+        let mut vm = VM::new();
+        vm.exec(&program, false).unwrap();
+
+        assert_eq!(vm.globals["typo"].as_float(), 3.0);
+
+        let mut vm = VM::new();
+        vm.set_strict_globals(true);
+
+        let error = vm.exec(&program, false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("assignment to undeclared global `typo`".into()));
+        assert_eq!(vm.globals["declared"].as_float(), 2.0);
+    }
+
+    #[test]
+    fn value_conversions() {
+        fn greet(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let count = context.get_arg(1).as_float() as i64;
+
+            let content = (0 .. count).map(Value::from).collect();
+            let numbers = context.new_list(content);
+
+            let greeting = context.new_string("hello");
+
+            Ok(context.new_list(vec![greeting, numbers, true.into(), Value::from(2.5)]))
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call(builder.var(Binding::global("greet")), vec![builder.number(3.0)], None);
+        builder.bind(Binding::global("result"), call);
+
+        let mut vm = VM::new();
+        vm.add_native("greet", greet, 1);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let result = vm.globals["result"].as_object().unwrap();
+        let content = &vm.heap.get(result).unwrap().as_list().unwrap().content;
+
+        assert_eq!(format!("{}", content[0].with_heap(&vm.heap)), "hello");
+        assert_eq!(content[2].decode(), Variant::True);
+        assert_eq!(content[3].as_float(), 2.5);
+
+        let numbers = vm.heap.get(content[1].as_object().unwrap()).unwrap().as_list().unwrap();
+        let numbers = numbers.content.iter().map(|n| n.as_float()).collect::<Vec<_>>();
+
+        assert_eq!(numbers, vec![0.0, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn native_allocations_survive() {
+        // Enough allocations to run the collector several times before the list roots them
+        fn words(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let words = (0 .. 5000).map(|i| context.new_string(&format!("word {}", i))).collect();
+
+            Ok(context.new_list(words))
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call(builder.var(Binding::global("words")), vec![], None);
+        builder.bind(Binding::global("words"), call);
+
+        let mut vm = VM::new();
+        vm.add_native("words", words, 0);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let words = vm.globals["words"].as_object().unwrap();
+        let words = vm.heap.get(words).unwrap().as_list().unwrap();
+
+        for (i, word) in words.content.iter().enumerate() {
+            let word = vm.heap.get(word.as_object().unwrap()).unwrap().as_string().unwrap();
+            assert_eq!(*word, format!("word {}", i));
+        }
+    }
+
+    #[test]
+    fn dict_merge_and_get_or() {
+        let mut builder = IrBuilder::new();
+
+        let a = builder.dict(
+            vec![builder.string("x"), builder.string("y")],
+            vec![builder.number(1.0), builder.number(2.0)],
+        );
+
+        let b = builder.dict(
+            vec![builder.string("y"), builder.string("z")],
+            vec![builder.number(20.0), builder.number(30.0)],
+        );
+
+        builder.bind(Binding::global("a"), a);
+        builder.bind(Binding::global("b"), b);
+
+        let merge = builder.call(
+            builder.var(Binding::global("dict_merge")),
+            vec![builder.var(Binding::global("a")), builder.var(Binding::global("b"))],
+            None
+        );
+
+        builder.bind(Binding::global("merged"), merge);
+
+        for (name, key) in &[("x", "x"), ("y", "y"), ("z", "z"), ("missing", "w")] {
+            let get = builder.call(
+                builder.var(Binding::global("get_or")),
+                vec![builder.var(Binding::global("merged")), builder.string(key), builder.number(-1.0)],
+                None
+            );
+
+            builder.bind(Binding::global(name), get);
+        }
+
+        let len = builder.call(builder.var(Binding::global("dict_len")), vec![builder.var(Binding::global("a"))], None);
+        builder.bind(Binding::global("a_len"), len);
+
+        let mut vm = VM::new();
+        vm.add_std_dict();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["x"].as_float(), 1.0);
+        assert_eq!(vm.globals["y"].as_float(), 20.0);
+        assert_eq!(vm.globals["z"].as_float(), 30.0);
+        assert_eq!(vm.globals["missing"].as_float(), -1.0);
+
+        // The merged dicts are left alone
+        assert_eq!(vm.globals["a_len"].as_float(), 2.0);
+    }
+
+    #[test]
+    fn op_counts() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+
+        let body = builder.while_loop(
+            |builder| builder.binary(builder.var(Binding::local("i", 0, 0)), BinaryOp::Lt, builder.number(10.0)),
+            |builder| {
+                let i = builder.var(Binding::local("i", 0, 0));
+                builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+            }
+        );
+
+        builder.emit(body);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert!(vm.op_counts().is_empty());
+
+        vm.set_op_counting(true);
+        vm.exec(&builder.build(), false).unwrap();
+
+        let counts = vm.op_counts();
+
+        assert_eq!(counts["Add"], 10);
+        assert_eq!(counts["Loop"], 10);
+        assert_eq!(counts["Less"], 11);
+        assert_eq!(counts["Return"], 1);
+    }
+
+    #[test]
+    fn disassembler_stack_effect() {
+        let heap = Heap::new();
+
+        // Three values pushed and only two combined, leaving two on the stack
+        let mut chunk = Chunk::new("unbalanced".into());
+
+        for n in &[1.0f64, 2.0, 3.0] {
+            chunk.write(Op::Immediate, 1);
+            chunk.write_u64(n.to_bits());
+        }
+
+        chunk.write(Op::Add, 1);
+        chunk.write(Op::List, 1);
+        chunk.write_byte(1);
+
+        assert_eq!(chunk.stack_effect(0), Some(1));
+        assert_eq!(chunk.stack_effect(27), Some(-1));
+        assert_eq!(chunk.stack_effect(28), Some(0));
+
+        assert_eq!(Disassembler::new(&chunk, &heap).disassemble(), 2);
+    }
+
+    #[test]
+    fn string_natives() {
+        let cases: Vec<(&str, &str, Value)> = vec![
+            ("starts_with", "zü", true.into()),
+            ("starts_with", "vm", false.into()),
+            ("ends_with", "vm", true.into()),
+            ("ends_with", "zü", false.into()),
+            ("contains", "b-", true.into()),
+            ("contains", "xyz", false.into()),
+            ("index_of", "b", 2.0.into()),
+            ("index_of", "xyz", (-1.0).into()),
+        ];
+
+        let mut builder = IrBuilder::new();
+
+        for (i, (native, arg, _)) in cases.iter().enumerate() {
+            let call = builder.call(
+                builder.var(Binding::global(native)),
+                vec![builder.string("züb-vm"), builder.string(arg)],
+                None
+            );
+
+            builder.bind(Binding::global(&format!("r{}", i)), call);
+        }
+
+        let mut vm = VM::new();
+        vm.add_std_string();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        for (i, (native, arg, expected)) in cases.into_iter().enumerate() {
+            assert_eq!(vm.globals[&format!("r{}", i)], expected, "{}(\"züb-vm\", {:?})", native, arg);
+        }
+    }
+
+    #[test]
+    fn chunk_validation() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+
+        let body = builder.while_loop(
+            |builder| builder.binary(builder.var(Binding::local("i", 0, 0)), BinaryOp::Lt, builder.number(3.0)),
+            |builder| {
+                let i = builder.var(Binding::local("i", 0, 0));
+                builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+            }
+        );
+
+        builder.emit(body);
+
+        let counter = builder.function(Binding::local("counter", 0, 0), &[], |builder| {
+            builder.ret(Some(builder.var(Binding::local("i", 1, 0))))
+        });
+
+        builder.emit(counter);
+
+        let subject = builder.var(Binding::local("i", 0, 0));
+        let match_ = digit_match(&mut builder, subject);
+        builder.emit(match_);
+
+        let mut vm = VM::new();
+
+        let mut compiler = Compiler::new(&mut vm.heap);
+        compiler.set_validation(true);
+        compiler.compile(&builder.build()).unwrap();
+
+        // A jump into the operand of the immediate before it
+        let mut chunk = Chunk::new("misaligned".into());
+
+        chunk.write(Op::Immediate, 1);
+        chunk.write_u64(1.0f64.to_bits());
+        chunk.write(Op::Jump, 1);
+        chunk.write_byte(3);
+        chunk.write_byte(0);
+
+        assert_eq!(chunk.validate(&vm.heap), Err(ValidationError::MisalignedJump { ip: 9, target: 3 }));
+
+        chunk.write(Op::Immediate, 1);
+        assert_eq!(chunk.validate(&vm.heap), Err(ValidationError::Truncated(12)));
+    }
+
+    #[test]
+    fn exec_function() {
+        let mut builder = IrBuilder::new();
+
+        let count = builder.binary(builder.var(Binding::global("count")), BinaryOp::Add, builder.number(1.0));
+        builder.mutate(builder.var(Binding::global("count")), count);
+
+        let greeting = builder.binary(builder.string("hello, "), BinaryOp::Add, builder.string("zub"));
+        builder.bind(Binding::global("greeting"), greeting);
+
+        let mut vm = VM::new();
+        vm.globals.insert("count".into(), 0.0.into());
+
+        let function = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+
+        let _roots = function.chunk().constants()
+            .filter_map(|constant| constant.as_object())
+            .map(|handle| vm.heap.make_rooted(handle))
+            .collect::<Vec<_>>();
+
+        vm.exec_function(function.clone(), false).unwrap();
+
+        // Nothing but the rooted constants survive between the runs
+        vm.heap.clean();
+
+        vm.exec_function(function, false).unwrap();
+
+        assert_eq!(vm.globals["count"].as_float(), 2.0);
+        assert_eq!(format!("{}", vm.globals["greeting"].with_heap(&vm.heap)), "hello, zub");
+    }
+
+    #[test]
+    fn string_builder() {
+        let mut builder = IrBuilder::new();
+
+        let sb = builder.call(builder.var(Binding::global("sb_new")), vec![], None);
+        builder.bind(Binding::local("sb", 0, 0), sb);
+        builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+
+        let body = builder.while_loop(
+            |builder| builder.binary(builder.var(Binding::local("i", 0, 0)), BinaryOp::Lt, builder.number(1000.0)),
+            |builder| {
+                let append = builder.call(
+                    builder.var(Binding::global("sb_append")),
+                    vec![builder.var(Binding::local("sb", 0, 0)), builder.var(Binding::local("i", 0, 0))],
+                    None
+                );
+
+                builder.emit(append);
+                builder.emit(Expr::Pop.node(TypeInfo::nil()));
+
+                let i = builder.var(Binding::local("i", 0, 0));
+                builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+            }
+        );
+
+        builder.emit(body);
+
+        let build = builder.call(builder.var(Binding::global("sb_build")), vec![builder.var(Binding::local("sb", 0, 0))], None);
+        builder.bind(Binding::global("built"), build);
+
+        let mut vm = VM::new();
+        vm.add_std_string_builder();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let built = vm.globals["built"].as_object().unwrap();
+        let built = vm.heap.get(built).unwrap().as_string().unwrap();
+
+        let expected = (0 .. 1000).map(|i| i.to_string()).collect::<String>();
+        assert_eq!(*built, expected);
+    }
+
+    #[test]
+    fn current_locals() {
+        fn inspect(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let locals = context.caller_locals().into_iter()
+                .map(|(name, value)| format!("{}={:?}", name, value))
+                .collect::<Vec<_>>();
+
+            let locals = locals.iter().map(|local| context.new_string(local)).collect();
+
+            Ok(context.new_list(locals))
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let add = builder.function(Binding::local("add", 0, 0), &["a", "b"], |builder| {
+            // Out of scope by the time of the call
+            let block = {
+                let mut inner = IrBuilder::new();
+                inner.bind(Binding::local("tmp", 2, 1), inner.number(0.0));
+
+                Expr::Block(inner.build()).node(TypeInfo::nil())
+            };
+
+            builder.emit(block);
+
+            let sum = builder.binary(builder.var(Binding::local("a", 1, 1)), BinaryOp::Add, builder.var(Binding::local("b", 1, 1)));
+            builder.bind(Binding::local("c", 1, 1), sum);
+
+            let call = builder.call(builder.var(Binding::global("inspect")), vec![], None);
+            builder.bind(Binding::global("locals"), call);
+
+            builder.ret(Some(builder.var(Binding::local("c", 1, 1))))
+        });
+
+        builder.emit(add);
+
+        let call = builder.call(builder.var(Binding::local("add", 0, 0)), vec![builder.number(1.0), builder.number(2.0)], None);
+        builder.emit(call);
+
+        let mut vm = VM::new();
+        vm.add_native("inspect", inspect, 0);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let locals = vm.globals["locals"].as_object().unwrap();
+        let locals = vm.heap.get(locals).unwrap().as_list().unwrap().content.iter()
+            .map(|local| format!("{}", local.with_heap(&vm.heap)))
+            .collect::<Vec<_>>();
+
+        assert_eq!(locals, vec!["a=Float(1.0)", "b=Float(2.0)", "c=Float(3.0)"]);
+    }
+
+    #[test]
+    fn signed_zero_constants() {
+        let mut chunk = Chunk::new("constants".into());
+
+        let zero = chunk.add_constant(0.0.into()).unwrap();
+        let negative_zero = chunk.add_constant((-0.0).into()).unwrap();
+        let nan = chunk.add_constant(f64::NAN.into()).unwrap();
+
+        assert_ne!(zero, negative_zero);
+        assert_eq!(chunk.add_constant(0.0.into()).unwrap(), zero);
+        assert_eq!(chunk.add_constant((-f64::NAN).into()).unwrap(), nan);
+        assert_eq!(chunk.constants().count(), 3);
+
+        let mut builder = IrBuilder::new();
+
+        let positive = builder.binary(builder.number(1.0), BinaryOp::Div, builder.number(0.0));
+        builder.bind(Binding::global("positive"), positive);
+
+        let negative = builder.binary(builder.number(1.0), BinaryOp::Div, builder.number(-0.0));
+        builder.bind(Binding::global("negative"), negative);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["positive"].as_float(), f64::INFINITY);
+        assert_eq!(vm.globals["negative"].as_float(), f64::NEG_INFINITY);
+    }
+
+    #[test]
+    fn call_named() {
+        thread_local! {
+            static PRINTED: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
+        }
+
+        fn print(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let text = format!("{}", context.get_arg_with_heap(1));
+            PRINTED.with(|printed| printed.borrow_mut().push(text));
+
+            Ok(Value::nil())
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let hello = builder.call_named("print", vec![builder.string("hello")], None);
+        builder.emit(hello);
+
+        let sum = builder.binary(builder.number(1.0), BinaryOp::Add, builder.number(2.0));
+        let sum = builder.call_named("print", vec![sum], None);
+        builder.emit(sum);
+
+        let mut vm = VM::new();
+        vm.add_native("print", print, 1);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        PRINTED.with(|printed| assert_eq!(*printed.borrow(), vec!["hello", "3"]));
+    }
+
+    #[test]
+    fn gc_payload_pressure() {
+        // Strings are left unreachable, so the heap shrinks as soon as a collection runs
+        fn allocations_until_collection(string: &str) -> usize {
+            let mut vm = VM::new();
+
+            for allocations in 1 .. {
+                vm.new_string(string);
+
+                if vm.heap.len() < allocations {
+                    return allocations
+                }
+            }
+
+            unreachable!()
+        }
+
+        let large = "x".repeat(1 << 20);
+
+        assert_eq!(allocations_until_collection(&large), 2);
+        assert!(allocations_until_collection("tiny") > 2);
+    }
+
+    #[test]
+    fn unary_ops() {
+        let mut builder = IrBuilder::new();
+
+        let neg = builder.neg(builder.number(5.0));
+        builder.bind(Binding::global("neg"), neg);
+
+        let not = builder.not(builder.bool(true));
+        builder.bind(Binding::global("not"), not);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["neg"].decode(), Variant::Float(-5.0));
+        assert_eq!(vm.globals["not"].decode(), Variant::False);
+
+        let mut builder = IrBuilder::new();
+
+        let neg = builder.unary(UnaryOp::Neg, builder.string("x"));
+        builder.bind(Binding::global("neg"), neg);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("can't negate string".into()));
+    }
+
+    #[test]
+    fn display_policy() {
+        let mut builder = IrBuilder::new();
+
+        let inner = builder.list(vec![builder.number(3.0)]);
+        let list = builder.list(vec![builder.number(1.0), builder.string("two"), inner]);
+        builder.bind(Binding::global("list"), list);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let list = vm.globals["list"];
+
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Summary), "<list [3]>");
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Full), "[1, \"two\", [3]]");
+    }
+
+    #[test]
+    fn method_self() {
+        let mut builder = IrBuilder::new();
+
+        let me = builder.method(Binding::local("me", 0, 0), &["x"], |builder| {
+            builder.ret(Some(builder.var(Binding::local("self", 1, 1))))
+        });
+
+        builder.emit(me);
+
+        let call = builder.call(builder.var(Binding::local("me", 0, 0)), vec![builder.number(1.0)], None);
+        builder.bind(Binding::global("result"), call);
+        builder.bind(Binding::global("me"), builder.var(Binding::local("me", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert!(vm.globals["result"].is_same(&vm.globals["me"]));
+
+        // Plain functions have no `self`
+        let mut builder = IrBuilder::new();
+
+        let plain = builder.function(Binding::local("plain", 0, 0), &[], |builder| {
+            builder.ret(Some(builder.var(Binding::local("self", 1, 1))))
+        });
+
+        builder.emit(plain);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Compile(CompileError::UnresolvedLocal("self".into())));
+    }
+
+    #[test]
+    fn globals_snapshot() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("b"), builder.bool(true));
+        builder.bind(Binding::global("a"), builder.number(1.0));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(
+            vm.globals_snapshot(),
+            vec![("a".to_string(), Variant::Float(1.0)), ("b".to_string(), Variant::True)]
+        );
+    }
+
+    #[test]
+    fn swap() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("a", 0, 0), builder.number(1.0));
+        builder.bind(Binding::local("b", 0, 0), builder.number(2.0));
+        builder.swap(builder.var(Binding::local("a", 0, 0)), builder.var(Binding::local("b", 0, 0)));
+
+        builder.bind(Binding::global("a"), builder.var(Binding::local("a", 0, 0)));
+        builder.bind(Binding::global("b"), builder.var(Binding::local("b", 0, 0)));
+
+        // Mixed kinds of variables work alike
+        builder.bind(Binding::global("c"), builder.number(3.0));
+        builder.swap(builder.var(Binding::global("c")), builder.var(Binding::local("a", 0, 0)));
+
+        builder.bind(Binding::global("d"), builder.var(Binding::local("a", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["a"].decode(), Variant::Float(2.0));
+        assert_eq!(vm.globals["b"].decode(), Variant::Float(1.0));
+        assert_eq!(vm.globals["c"].decode(), Variant::Float(2.0));
+        assert_eq!(vm.globals["d"].decode(), Variant::Float(3.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn nested_collections_survive_gc() {
+        let mut builder = IrBuilder::new();
+
+        let leaf = builder.list(vec![builder.number(1.0), builder.string("leaf")]);
+        let dict = builder.dict(vec![builder.string("inner")], vec![leaf]);
+        builder.bind(Binding::global("tree"), builder.list(vec![dict]));
+
+        // A dict only reachable through the upvalue of a closure
+        let make = builder.function(Binding::local("make", 0, 0), &[], |builder| {
+            let captured = builder.list(vec![builder.string("captured")]);
+            let dict = builder.dict(vec![builder.string("k")], vec![captured]);
+            builder.bind(Binding::local("d", 1, 1), dict);
+
+            let get = builder.function(Binding::local("get", 1, 1), &[], |builder| {
+                builder.ret(Some(builder.var(Binding::local("d", 2, 1))))
+            });
+
+            builder.emit(get);
+            builder.ret(Some(builder.var(Binding::local("get", 1, 1))))
+        });
+
+        builder.emit(make);
+
+        let getter = builder.call(builder.var(Binding::local("make", 0, 0)), vec![], None);
+        builder.bind(Binding::global("getter"), getter);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        // Garbage in between collections, so freed objects get reused
+        for i in 0 .. 3 {
+            for _ in 0 .. 100 {
+                vm.new_list(vec![Value::from(i as f64)]);
+            }
+
+            vm.collect();
+        }
+
+        assert!(vm.heap.len() < 100);
+
+        let tree = vm.globals["tree"].display_with(&vm.heap, DisplayPolicy::Full);
+        assert_eq!(tree, "[{\"inner\": [1, \"leaf\"]}]");
+
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call_named("getter", vec![], None);
+        builder.bind(Binding::global("captured"), call);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let captured = vm.globals["captured"].display_with(&vm.heap, DisplayPolicy::Full);
+        assert_eq!(captured, "{\"k\": [\"captured\"]}");
+    }
+
+    #[test]
+    fn string_index() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("word"), builder.string("Æble"));
+
+        let first = builder.binary(builder.var(Binding::global("word")), BinaryOp::Index, builder.number(0.0));
+        builder.bind(Binding::global("first"), first);
+
+        let last = builder.binary(builder.var(Binding::global("word")), BinaryOp::Index, builder.number(3.0));
+        builder.bind(Binding::global("last"), last);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(format!("{}", vm.globals["first"].with_heap(&vm.heap)), "Æ");
+        assert_eq!(format!("{}", vm.globals["last"].with_heap(&vm.heap)), "e");
+
+        for &index in &[4.0, -1.0] {
+            let mut builder = IrBuilder::new();
+
+            let element = builder.binary(builder.var(Binding::global("word")), BinaryOp::Index, builder.number(index));
+            builder.bind(Binding::global("element"), element);
+
+            let error = vm.exec(&builder.build(), false).unwrap_err();
+            let message = format!("index {} out of bounds for string of length 4", index);
+
+            assert_eq!(error.kind, ErrorKind::Message(message));
+        }
+    }
+
+    #[test]
+    fn try_add_native() {
+        fn nothing(_: &mut CallContext) -> Result<Value, RuntimeError> {
+            Ok(Value::nil())
+        }
+
+        let mut vm = VM::new();
+
+        vm.try_add_native("nothing", nothing, 0).unwrap();
+
+        let error = vm.try_add_native("nothing", nothing, 1).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Message("global `nothing` is already defined".into()));
+
+        // Overwriting is still possible on purpose
+        vm.add_native("nothing", nothing, 1);
+    }
+
+    #[test]
+    fn list_sort() {
+        let mut builder = IrBuilder::new();
+
+        let reverse = builder.function(Binding::local("reverse", 0, 0), &["a", "b"], |builder| {
+            let difference = builder.binary(
+                builder.var(Binding::local("b", 1, 1)),
+                BinaryOp::Sub,
+                builder.var(Binding::local("a", 1, 1))
+            );
+
+            builder.ret(Some(difference))
+        });
+
+        // Defined first, as the calls below leave their results on the stack
+        builder.emit(reverse);
+
+        let numbers = |builder: &IrBuilder| builder.list(
+            [3.0, 1.0, 4.0, 1.0, 5.0, 9.0, 2.0, 6.0].iter().map(|&n| builder.number(n)).collect()
+        );
+
+        builder.bind(Binding::global("ascending"), numbers(&builder));
+        builder.bind(Binding::global("descending"), numbers(&builder));
+
+        let words = builder.list(vec![builder.string("pear"), builder.string("apple"), builder.string("fig")]);
+        builder.bind(Binding::global("words"), words);
+
+        let sort = builder.call_named("sort", vec![builder.var(Binding::global("ascending"))], None);
+        builder.emit(sort);
+
+        let sort = builder.call_named("sort", vec![builder.var(Binding::global("words"))], None);
+        builder.emit(sort);
+
+        let sort_by = builder.call_named(
+            "sort_by",
+            vec![builder.var(Binding::global("descending")), builder.var(Binding::local("reverse", 0, 0))],
+            None
+        );
+
+        builder.emit(sort_by);
+
+        let mut vm = VM::new();
+        vm.add_std_list();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        let show = |vm: &VM, name: &str| vm.globals[name].display_with(&vm.heap, DisplayPolicy::Full);
+
+        assert_eq!(show(&vm, "ascending"), "[1, 1, 2, 3, 4, 5, 6, 9]");
+        assert_eq!(show(&vm, "descending"), "[9, 6, 5, 4, 3, 2, 1, 1]");
+        assert_eq!(show(&vm, "words"), "[\"apple\", \"fig\", \"pear\"]");
+    }
+
+    #[test]
+    fn value_ordering() {
+        use std::cmp::Ordering;
+
+        let mut vm = VM::new();
+
+        let mut numbers = [Value::from(2.5), Value::from(-1.0), Value::from(10.0)];
+        numbers.sort_by(|a, b| a.cmp_with(b, &vm.heap).unwrap());
+
+        assert_eq!(numbers.iter().map(Value::as_float).collect::<Vec<_>>(), vec![-1.0, 2.5, 10.0]);
+
+        let apple = vm.new_string("apple");
+        let banana = vm.new_string("banana");
+        let apples = vm.new_string("apples");
+
+        assert_eq!(apple.cmp_with(&banana, &vm.heap), Some(Ordering::Less));
+        assert_eq!(apples.cmp_with(&apple, &vm.heap), Some(Ordering::Greater));
+        assert_eq!(apple.cmp_with(&apple, &vm.heap), Some(Ordering::Equal));
+
+        assert_eq!(apple.cmp_with(&Value::from(1.0), &vm.heap), None);
+        assert_eq!(Value::from(f64::NAN).cmp_with(&Value::from(1.0), &vm.heap), None);
+    }
+
+    #[test]
+    fn pop_n() {
+        let mut builder = IrBuilder::new();
+
+        let f = builder.function(Binding::local("f", 0, 0), &[], |builder| {
+            let block = {
+                let mut inner = IrBuilder::new();
+
+                for i in 0 .. 10 {
+                    inner.bind(Binding::local(&format!("v{}", i), 2, 1), inner.number(i as f64));
+                }
+
+                Expr::Block(inner.build()).node(TypeInfo::nil())
+            };
+
+            builder.emit(block);
+
+            // Takes the slot of the first local of the block, only found if all were popped
+            builder.bind(Binding::local("after", 1, 1), builder.number(42.0));
+            builder.ret(Some(builder.var(Binding::local("after", 1, 1))))
+        });
+
+        builder.emit(f);
+
+        let call = builder.call(builder.var(Binding::local("f", 0, 0)), vec![], None);
+        builder.bind(Binding::global("result"), call);
+
+        let mut vm = VM::new();
+        vm.set_op_counting(true);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.op_counts()["PopN"], 1);
+        assert_eq!(vm.globals["result"].decode(), Variant::Float(42.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn source_spans() {
+        // a = 1
+        // b = -"x"
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("a"), builder.number(1.0).with_span(4, 5));
+
+        let string = builder.string("x").with_span(11, 14);
+        let neg = builder.neg(string).with_span(10, 14);
+        builder.bind(Binding::global("b"), neg);
+
+        let mut vm = VM::new();
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.backtrace[0].span, Some((10, 14)));
+
+        // Code outside of any spanned node has no span
+        let function = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+        let chunk = function.chunk();
+
+        assert_eq!(chunk.span(0), Some((4, 5)));
+        assert_eq!(chunk.span(chunk.len() - 1), None);
+    }
+
+    #[test]
+    fn global_snapshot_copy() {
+        let mut builder = IrBuilder::new();
+
+        let inner = builder.list(vec![builder.number(1.0)]);
+        let dict = builder.dict(vec![builder.string("inner")], vec![inner]);
+        builder.bind(Binding::global("data"), builder.list(vec![dict, builder.string("s")]));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let shared = vm.globals["data"];
+        let snapshot = vm.get_global_cloned("data").unwrap();
+
+        // Keep the snapshot alive while the script runs
+        vm.globals.insert("snapshot".into(), snapshot);
+
+        let mut builder = IrBuilder::new();
+
+        let data = builder.var(Binding::global("data"));
+        let dict = builder.binary(data.clone(), BinaryOp::Index, builder.number(0.0));
+        let inner = builder.binary(dict.clone(), BinaryOp::Index, builder.string("inner"));
+
+        builder.emit(builder.set_element(inner, builder.number(0.0), builder.number(2.0)));
+        builder.emit(builder.set_element(data, builder.number(1.0), builder.nil()));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(shared.display_with(&vm.heap, DisplayPolicy::Full), "[{\"inner\": [2]}, nil]");
+        assert_eq!(snapshot.display_with(&vm.heap, DisplayPolicy::Full), "[{\"inner\": [1]}, \"s\"]");
+    }
+
+    #[test]
+    fn disassemble_immediates() {
+        let heap = Heap::new();
+        let mut chunk = Chunk::new("floats".into());
+
+        // Bytes differ across the whole word, so any reordering shows up as a different value
+        for n in &[1.5f64, -0.1, 123456.789] {
+            chunk.write(Op::Immediate, 1);
+            chunk.write_u64(n.to_bits());
+        }
+
+        let listing = Disassembler::new(&chunk, &heap).listing();
+        let floats = listing.lines()
+            .filter_map(|line| line.split("FLOAT\t").nth(1))
+            .map(|operand| operand.split(' ').next().unwrap())
+            .collect::<Vec<_>>();
+
+        assert_eq!(floats, vec!["1.5", "-0.1", "123456.789"]);
+    }
+
+    #[test]
+    fn native_returns_dict() {
+        // Every key and value is a fresh object, and the collector runs while they're built
+        fn table(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let mut entries = Vec::new();
+
+            for i in 0 .. 2000 {
+                let key = context.new_string(&format!("key {}", i));
+                let value = context.new_list(vec![i.into()]);
+
+                entries.push((key, value));
+            }
+
+            context.new_dict(entries)
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call(builder.var(Binding::global("table")), vec![], None);
+        builder.bind(Binding::global("table"), call);
+
+        let mut vm = VM::new();
+        vm.add_native("table", table, 0);
+
+        vm.exec(&builder.build(), false).unwrap();
+        vm.collect();
+
+        let mut builder = IrBuilder::new();
+
+        let entry = builder.binary(builder.var(Binding::global("table")), BinaryOp::Index, builder.string("key 1999"));
+        let element = builder.binary(entry, BinaryOp::Index, builder.number(0.0));
+        builder.bind(Binding::global("element"), element);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["element"], 1999.0.into());
+    }
+
+    #[test]
+    fn boolean_results() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let mut builder = IrBuilder::new();
+
+        let lt = |builder: &IrBuilder, a: f64, b: f64| builder.binary(builder.number(a), BinaryOp::Lt, builder.number(b));
+        let gt = |builder: &IrBuilder, a: f64, b: f64| builder.binary(builder.number(a), BinaryOp::Gt, builder.number(b));
+
+        let and = builder.binary(lt(&builder, 1.0, 2.0), BinaryOp::And, gt(&builder, 3.0, 4.0));
+        builder.bind(Binding::global("and"), and);
+
+        let or = builder.binary(lt(&builder, 2.0, 1.0), BinaryOp::Or, gt(&builder, 4.0, 3.0));
+        builder.bind(Binding::global("or"), or);
+
+        // Short-circuiting leaves the comparison itself as the result
+        let short = builder.binary(lt(&builder, 2.0, 1.0), BinaryOp::And, builder.number(1.0));
+        builder.bind(Binding::global("short"), short);
+
+        let not = builder.not(lt(&builder, 1.0, 2.0));
+        builder.bind(Binding::global("not"), not);
+
+        let branch = builder.ternary(lt(&builder, 1.0, 2.0), builder.string("then"), Some(builder.string("else")));
+        builder.bind(Binding::global("branch"), branch);
+
+        let list = builder.list(vec![lt(&builder, 1.0, 2.0), lt(&builder, 2.0, 1.0)]);
+        builder.bind(Binding::global("list"), list);
+
+        let at_least = builder.binary(builder.number(1.0), BinaryOp::GtEqual, builder.number(1.0));
+        let dict = builder.dict(vec![builder.string("at_least")], vec![at_least]);
+        builder.bind(Binding::global("dict"), dict);
+
+        let equal = builder.binary(lt(&builder, 1.0, 2.0), BinaryOp::Equal, builder.bool(true));
+        builder.print(equal);
+        builder.print(builder.var(Binding::global("short")));
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let sink = output.clone();
+
+        let mut vm = VM::new();
+        vm.set_print_hook(move |text| sink.borrow_mut().push_str(text));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["and"].decode(), Variant::False);
+        assert_eq!(vm.globals["or"].decode(), Variant::True);
+        assert_eq!(vm.globals["short"].decode(), Variant::False);
+        assert_eq!(vm.globals["not"].decode(), Variant::False);
+
+        let display = |name: &str| vm.globals[name].display_with(&vm.heap, DisplayPolicy::Full);
+
+        assert_eq!(display("branch"), "\"then\"");
+        assert_eq!(display("list"), "[true, false]");
+        assert_eq!(display("dict"), "{\"at_least\": true}");
+        assert_eq!(*output.borrow(), "true\nfalse\n");
+    }
+
+    #[test]
+    fn heap_capacity() {
+        let mut heap = Heap::with_capacity(1000);
+        let capacity = heap.capacity();
+
+        assert!(capacity >= 1000);
+
+        let handles = (0 .. 1000)
+            .map(|i| heap.insert_temp(Object::String(i.to_string())))
+            .collect::<Vec<_>>();
+
+        assert_eq!(heap.len(), handles.len());
+        assert_eq!(heap.capacity(), capacity);
+
+        let vm = VM::with_heap_capacity(1000);
+        assert!(vm.heap.capacity() >= 1000);
+    }
+
+    #[test]
+    fn native_arity_mismatch() {
+        fn double(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            Ok((context.get_arg(1).as_float() * 2.0).into())
+        }
+
+        let mut vm = VM::new();
+        vm.add_native("double", double, 1);
+
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call(builder.var(Binding::global("double")), vec![builder.number(1.0), builder.number(2.0)], None);
+        builder.bind(Binding::global("x"), call);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Message("native `double` takes 1 argument but was called with 2".into()));
+        assert!(vm.stack.is_empty());
+
+        // The VM is still usable afterwards
+        let mut builder = IrBuilder::new();
+
+        let call = builder.call(builder.var(Binding::global("double")), vec![builder.number(21.0)], None);
+        builder.bind(Binding::global("x"), call);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["x"], 42.0.into());
+    }
+
+    #[test]
+    fn function_metrics() {
+        let mut builder = IrBuilder::new();
+
+        let greet = builder.function(Binding::global("greet"), &["name"], |builder| {
+            let greeting = builder.binary(builder.string("hello "), BinaryOp::Add, builder.var(Binding::local("name", 1, 1)));
+            builder.ret(Some(greeting))
+        });
+
+        builder.emit(greet);
+
+        let mut vm = VM::new();
+        let program = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+
+        assert_eq!(program.code_size(), program.chunk().len());
+        assert_eq!(program.upvalue_count(), 0);
+
+        let greet = program.chunk().constants()
+            .filter_map(|constant| constant.as_object())
+            .filter_map(|handle| vm.heap.get(handle).unwrap().as_function())
+            .next()
+            .unwrap();
+
+        // The string constant, and the code to load it, add the parameter and return
+        assert_eq!(greet.name(), "greet");
+        assert_eq!(greet.constant_count(), 1);
+        assert!(greet.code_size() > 4 && greet.code_size() < 32);
+    }
+
+    #[test]
+    fn deeply_nested_copy() {
+        let mut vm = VM::new();
+
+        // Each level is rooted through the global before the next one is allocated
+        let innermost = vm.new_list(vec![0.0.into()]);
+        vm.globals.insert("nested".into(), innermost);
+
+        for _ in 0 .. 10_000 {
+            let inner = vm.globals["nested"];
+            let outer = vm.new_list(vec![inner]);
+
+            vm.globals.insert("nested".into(), outer);
+        }
+
+        let copy = vm.get_global_cloned("nested").unwrap();
+
+        let mut original = vm.globals["nested"];
+        let mut copy = copy;
+        let mut depth = 0;
+
+        while let (Some(a), Some(b)) = (original.as_object(), copy.as_object()) {
+            assert_ne!(a, b);
+
+            original = vm.heap.get(a).unwrap().as_list().unwrap().content[0];
+            copy = vm.heap.get(b).unwrap().as_list().unwrap().content[0];
+            depth += 1;
+        }
+
+        assert_eq!(depth, 10_001);
+        assert_eq!(copy, 0.0.into());
+    }
+
+    #[test]
+    fn dict_duplicate_keys() {
+        let mut builder = IrBuilder::new();
+
+        let dict = builder.dict(
+            vec![builder.string("a"), builder.string("b"), builder.string("a")],
+            vec![builder.number(1.0), builder.number(3.0), builder.number(2.0)],
+        );
+        builder.bind(Binding::global("dict"), dict);
+
+        let a = builder.binary(builder.var(Binding::global("dict")), BinaryOp::Index, builder.string("a"));
+        builder.bind(Binding::global("a"), a);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["a"], 2.0.into());
+        assert_eq!(vm.globals["dict"].display_with(&vm.heap, DisplayPolicy::Full).matches("\"a\"").count(), 1);
+    }
+
+    #[test]
+    fn identical_equality() {
+        let mut vm = VM::new();
+
+        let big = "zub".repeat(100_000);
+        let string = vm.new_string(&big);
+        vm.globals.insert("string".into(), string);
+
+        let list = vm.new_list((0 .. 100_000).map(|i| (i as f64).into()).collect());
+        vm.globals.insert("list".into(), list);
+        vm.globals.insert("nan".into(), f64::NAN.into());
+
+        let mut builder = IrBuilder::new();
+
+        for name in &["string", "list", "nan"] {
+            let equal = builder.binary(builder.var(Binding::global(name)), BinaryOp::Equal, builder.var(Binding::global(name)));
+            builder.bind(Binding::global(&format!("{}_equal", name)), equal);
+        }
+
+        let copy = builder.binary(builder.var(Binding::global("string")), BinaryOp::Equal, builder.string(&big));
+        builder.bind(Binding::global("copy_equal"), copy);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["string_equal"].decode(), Variant::True);
+        assert_eq!(vm.globals["list_equal"].decode(), Variant::True);
+        assert_eq!(vm.globals["nan_equal"].decode(), Variant::False);
+        assert_eq!(vm.globals["copy_equal"].decode(), Variant::True);
+    }
+
+    #[test]
+    fn builder_unary() {
+        let mut builder = IrBuilder::new();
+
+        let neg = builder.neg(builder.neg(builder.number(2.5)));
+        builder.bind(Binding::global("neg"), neg);
+
+        let not = builder.not(builder.nil());
+        builder.bind(Binding::global("not"), not);
+
+        let unary = builder.unary(UnaryOp::Not, builder.number(0.0));
+        builder.bind(Binding::global("unary"), unary);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["neg"], 2.5.into());
+        assert_eq!(vm.globals["not"].decode(), Variant::True);
+        assert_eq!(vm.globals["unary"].decode(), Variant::False);
+    }
+
+    #[test]
+    fn yield_and_resume() {
+        let mut builder = IrBuilder::new();
+
+        let count = builder.function(Binding::local("count", 0, 0), &["start"], |builder| {
+            let start = builder.var(Binding::local("start", 1, 1));
+            builder.emit(builder.yield_(start.clone()));
+
+            let next = builder.binary(start, BinaryOp::Add, builder.number(1.0));
+            builder.emit(builder.yield_(next));
+
+            builder.ret(Some(builder.string("done")))
+        });
+
+        builder.emit(count);
+
+        let call = builder.call(builder.var(Binding::local("count", 0, 0)), vec![builder.number(10.0)], None);
+        builder.bind(Binding::global("result"), call);
+
+        let program = builder.build();
+
+        let mut vm = VM::new();
+
+        let state = vm.exec_resumable(&program).unwrap().unwrap();
+        assert_eq!(state.value, 10.0.into());
+        assert!(vm.frames.is_empty() && vm.stack.is_empty());
+
+        // The VM is free for other work while the program is suspended
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("other"), builder.string("other"));
+
+        vm.exec(&builder.build(), false).unwrap();
+        vm.collect();
+
+        let state = vm.resume(state).unwrap().unwrap();
+        assert_eq!(state.value, 11.0.into());
+
+        assert!(vm.resume(state).unwrap().is_none());
+        assert_eq!(vm.globals["result"].display_with(&vm.heap, DisplayPolicy::Full), "\"done\"");
+
+        let error = vm.exec(&program, false).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Message("can't yield from a program not run with `exec_resumable`".into()));
+    }
+
+    #[test]
+    fn yield_from_method() {
+        use std::rc::Rc;
+        use std::cell::Cell;
+
+        let mut builder = IrBuilder::new();
+
+        // Drops the dict's reference to itself before yielding, leaving only the suspended frame
+        let run = builder.method(Binding::global("run"), &[], |builder| {
+            let this = builder.var(Binding::local("self", 1, 1));
+
+            builder.emit(builder.set_element(this.clone(), builder.string("run"), builder.nil()));
+            builder.emit(builder.yield_(builder.binary(this.clone(), BinaryOp::Index, builder.string("count"))));
+
+            let count = builder.binary(this, BinaryOp::Index, builder.string("count"));
+            builder.ret(Some(builder.binary(count, BinaryOp::Add, builder.number(1.0))))
+        });
+
+        builder.emit(run);
+
+        let counter = builder.dict(
+            vec![builder.string("count"), builder.string("run")],
+            vec![builder.number(41.0), builder.var(Binding::global("run"))],
+        );
+
+        builder.bind(Binding::global("counter"), counter);
+        builder.bind(Binding::global("run"), builder.nil());
+
+        let invoke = builder.invoke(builder.var(Binding::global("counter")), "run", vec![]);
+        builder.bind(Binding::global("result"), invoke);
+
+        let mut vm = VM::new();
+
+        let state = vm.exec_resumable(&builder.build()).unwrap().unwrap();
+        assert_eq!(state.value, 41.0.into());
+
+        let freed = Rc::new(Cell::new(false));
+
+        for handle in vm.heap.handles().collect::<Vec<_>>() {
+            if let Some(Object::Closure(_)) = vm.heap.get(handle) {
+                let freed = freed.clone();
+                vm.heap.set_finalizer(handle, move |_| freed.set(true));
+            }
+        }
+
+        vm.collect();
+        assert!(!freed.get());
+
+        assert!(vm.resume(state).unwrap().is_none());
+        assert_eq!(vm.globals["result"], 42.0.into());
+    }
+
+    #[test]
+    fn list_literal_capacity() {
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list((0 .. 100).map(|i| builder.number(i as f64)).collect());
+        builder.bind(Binding::global("list"), list);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let list = vm.heap.get(vm.globals["list"].as_object().unwrap()).unwrap().as_list().unwrap();
+
+        // Growing element by element would have overshot to the next power of two
+        assert_eq!(list.content.capacity(), 100);
+        assert_eq!(list.content[99], 99.0.into());
+    }
+
+    #[test]
+    fn mutual_recursion() {
+        // Each function answers for n == 0 and defers to the other one for n - 1
+        fn parity(builder: &mut IrBuilder, name: Binding, other: Binding, at_zero: bool) -> ExprNode {
+            builder.function(name, &["n"], move |builder| {
+                let n = builder.var(Binding::local("n", 1, 1));
+                let zero = builder.binary(n.clone(), BinaryOp::Equal, builder.number(0.0));
+
+                let rest = builder.binary(n, BinaryOp::Sub, builder.number(1.0));
+                let call = builder.call(builder.var(other.clone()), vec![rest], None);
+
+                let result = builder.ternary(zero, builder.bool(at_zero), Some(call));
+                builder.ret(Some(result))
+            })
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let even = parity(&mut builder, Binding::local("even", 0, 0), Binding::local("odd", 1, 0), true);
+        builder.emit(even);
+
+        let odd = parity(&mut builder, Binding::local("odd", 0, 0), Binding::local("even", 1, 0), false);
+        builder.emit(odd);
+
+        let call = builder.call(builder.var(Binding::local("even", 0, 0)), vec![builder.number(10.0)], None);
+        builder.bind(Binding::global("local_even"), call);
+
+        let call = builder.call(builder.var(Binding::local("odd", 0, 0)), vec![builder.number(7.0)], None);
+        builder.bind(Binding::global("local_odd"), call);
+
+        let even = parity(&mut builder, Binding::global("even"), Binding::global("odd"), true);
+        builder.emit(even);
+
+        let odd = parity(&mut builder, Binding::global("odd"), Binding::global("even"), false);
+        builder.emit(odd);
+
+        let call = builder.call(builder.var(Binding::global("even")), vec![builder.number(7.0)], None);
+        builder.bind(Binding::global("global_even"), call);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["local_even"].decode(), Variant::True);
+        assert_eq!(vm.globals["local_odd"].decode(), Variant::True);
+        assert_eq!(vm.globals["global_even"].decode(), Variant::False);
+
+        // A nested block's own `odd` leaves the hoisted one alone
+        let mut builder = IrBuilder::new();
+
+        let even = parity(&mut builder, Binding::local("even", 0, 0), Binding::local("odd", 1, 0), true);
+        builder.emit(even);
+
+        let block = {
+            let mut inner = IrBuilder::new();
+
+            let odd = inner.function(Binding::local("odd", 0, 0), &[], |builder| {
+                builder.ret(Some(builder.string("shadow")))
+            });
+            inner.emit(odd);
+
+            inner.bind(Binding::global("shadow"), inner.var(Binding::local("odd", 0, 0)));
+
+            Expr::Block(inner.build()).node(TypeInfo::nil())
+        };
+
+        builder.emit(block);
+
+        let odd = parity(&mut builder, Binding::local("odd", 0, 0), Binding::local("even", 1, 0), false);
+        builder.emit(odd);
+
+        let call = builder.call(builder.var(Binding::local("even", 0, 0)), vec![builder.number(3.0)], None);
+        builder.bind(Binding::global("outer_even"), call);
+
+        let call = builder.call(builder.var(Binding::global("shadow")), vec![], None);
+        builder.bind(Binding::global("shadow"), call);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["shadow"].display_with(&vm.heap, DisplayPolicy::Full), "\"shadow\"");
+        assert_eq!(vm.globals["outer_even"].decode(), Variant::False);
+    }
+
+    #[test]
+    fn empty_falsy_policy() {
+        let mut builder = IrBuilder::new();
+
+        let cases = vec![
+            ("empty_list", builder.list(vec![])),
+            ("list", builder.list(vec![builder.number(1.0)])),
+            ("empty_string", builder.string("")),
+            ("empty_dict", builder.empty_dict()),
+            ("zero", builder.number(0.0)),
+        ];
+
+        for (name, value) in cases {
+            let branch = builder.ternary(value, builder.string("then"), Some(builder.string("else")));
+            builder.bind(Binding::global(name), branch);
+        }
+
+        let empty_or = builder.binary(builder.list(vec![]), BinaryOp::Or, builder.number(2.0));
+        builder.bind(Binding::global("empty_or"), empty_or);
+
+        let not_empty = builder.not(builder.string(""));
+        builder.bind(Binding::global("not_empty"), not_empty);
+
+        let program = builder.build();
+        let branch = |vm: &VM, name: &str| vm.globals[name].display_with(&vm.heap, DisplayPolicy::Summary);
+
+        let mut vm = VM::new();
+        vm.exec(&program, false).unwrap();
+
+        assert_eq!(branch(&vm, "empty_list"), "then");
+        assert_eq!(vm.globals["not_empty"].decode(), Variant::False);
+
+        vm.set_truth_policy(TruthPolicy::EmptyFalsy);
+        vm.exec(&program, false).unwrap();
+
+        assert_eq!(branch(&vm, "empty_list"), "else");
+        assert_eq!(branch(&vm, "list"), "then");
+        assert_eq!(branch(&vm, "empty_string"), "else");
+        assert_eq!(branch(&vm, "empty_dict"), "else");
+        assert_eq!(branch(&vm, "zero"), "then");
+        assert_eq!(vm.globals["empty_or"], 2.0.into());
+        assert_eq!(vm.globals["not_empty"].decode(), Variant::True);
+    }
+
+    #[test]
+    fn fused_global_calls() {
+        fn double(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            Ok((context.get_arg(1).as_float() * 2.0).into())
+        }
+
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("i", 0, 0), builder.number(0.0));
+        builder.bind(Binding::global("total"), builder.number(0.0));
+
+        let body = builder.while_loop(
+            |builder| builder.binary(builder.var(Binding::local("i", 0, 0)), BinaryOp::Lt, builder.number(10.0)),
+            |builder| {
+                let i = builder.var(Binding::local("i", 0, 0));
+                let total = builder.var(Binding::global("total"));
+
+                let doubled = builder.call_named("double", vec![i.clone()], None);
+                builder.mutate(total.clone(), builder.binary(total, BinaryOp::Add, doubled));
+
+                builder.mutate(i.clone(), builder.binary(i, BinaryOp::Add, builder.number(1.0)))
+            },
+        );
+
+        builder.emit(body);
+
+        let mut vm = VM::new();
+        vm.add_native("double", double, 1);
+        vm.set_op_counting(true);
+
+        let program = builder.build();
+        vm.exec(&program, false).unwrap();
+
+        let counts = vm.op_counts();
+
+        assert_eq!(vm.globals["total"], 90.0.into());
+        assert_eq!(counts["CallGlobal"], 10);
+        assert_eq!(counts.get("Call"), None);
+
+        let function = Compiler::new(&mut vm.heap).compile(&program).unwrap();
+        assert!(Disassembler::new(function.chunk(), &vm.heap).listing().contains("CALL_GLOBAL_1\tdouble"));
+
+        let mut builder = IrBuilder::new();
+        builder.emit(builder.call_named("missing", vec![builder.number(1.0)], None));
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Message("undefined global variable: `missing`".into()));
+    }
+
+    #[test]
+    fn display_handle() {
+        let mut vm = VM::new();
+
+        let list = vm.new_list(vec![1.0.into(), 2.0.into()]);
+        let handle = list.as_object().unwrap();
+
+        assert_eq!(format!("{}", WithHeap::new(&vm.heap, handle)), "<list [2]>");
+        assert_eq!(WithHeap::new(&vm.heap, handle).to_string(), list.with_heap(&vm.heap).to_string());
+    }
+
+    #[test]
+    fn nested_set_element() {
+        let mut builder = IrBuilder::new();
+
+        let row = |builder: &IrBuilder, a: f64, b: f64| builder.list(vec![builder.number(a), builder.number(b)]);
+
+        let matrix = builder.list(vec![row(&builder, 1.0, 2.0), row(&builder, 3.0, 4.0)]);
+        builder.bind(Binding::global("m"), matrix);
+
+        let dict = builder.dict(vec![builder.string("k")], vec![builder.number(1.0)]);
+        let cube = builder.list(vec![builder.list(vec![dict])]);
+        builder.bind(Binding::global("cube"), cube);
+
+        let m = builder.var(Binding::global("m"));
+        let first = builder.binary(m.clone(), BinaryOp::Index, builder.number(0.0));
+
+        // m[0][1] = 9
+        builder.emit(builder.set_element(first.clone(), builder.number(1.0), builder.number(9.0)));
+
+        builder.bind(Binding::global("read"), builder.binary(first, BinaryOp::Index, builder.number(1.0)));
+
+        // cube[0][0]["k"] = 5, the collection being indexed through a path
+        let cube = builder.var(Binding::global("cube"));
+        let inner = builder.binary(builder.binary(cube, BinaryOp::Index, builder.number(0.0)), BinaryOp::Index, builder.number(0.0));
+
+        builder.emit(builder.set_element(inner, builder.string("k"), builder.number(5.0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        let display = |name: &str| vm.globals[name].display_with(&vm.heap, DisplayPolicy::Full);
+
+        assert_eq!(vm.globals["read"], 9.0.into());
+        assert_eq!(display("m"), "[[1, 9], [3, 4]]");
+        assert_eq!(display("cube"), "[[{\"k\": 5}]]");
+    }
+
+    #[test]
+    fn disassemble_without_heap() {
+        let mut chunk = Chunk::new("detached".into());
+
+        {
+            let mut heap = Heap::new();
+            let idx = chunk.string_constant(&mut heap, "answer").unwrap();
+
+            chunk.write(Op::Immediate, 1);
+            chunk.write_u64(42.0f64.to_bits());
+            chunk.write(Op::DefineGlobal, 1);
+            chunk.write_byte(idx as u8);
+
+            let mut inner = FunctionBuilder::new("inner", 0);
+            inner.set_upvalue_count(1);
+
+            let inner = heap.insert(Object::Function(inner.build())).into_handle();
+            let idx = chunk.add_constant(inner.into()).unwrap();
+
+            chunk.write(Op::Closure, 1);
+            chunk.write_byte(idx as u8);
+            chunk.write_byte(1);
+            chunk.write_byte(0);
+            chunk.write_byte(0);
+            chunk.write(Op::Return, 1);
+        }
+
+        // The heap holding the name is gone, the listing doesn't need it
+        let listing = Disassembler::new_no_heap(&chunk).listing();
+
+        assert!(listing.contains("FLOAT\t42"));
+        assert!(listing.contains("DEFINE_GLOBAL\t<obj>"));
+
+        // Nor the function, but then the upvalue operands can't be told apart from instructions
+        assert!(listing.contains("CLOSURE\t<obj> (upvalue operands unknown, stopping)"));
+        assert!(!listing.contains("RETURN"));
+
+        assert_eq!(chunk.validate(&Heap::new()), Err(ValidationError::BadClosure(11)));
+    }
+
+    #[test]
+    fn loop_warning() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+        use std::time::{ Duration, Instant };
+
+        let mut builder = IrBuilder::new();
+
+        let forever = builder.while_(builder.bool(true), |_| {});
+        builder.emit(forever);
+
+        let warnings = Rc::new(RefCell::new(Vec::new()));
+        let sink = warnings.clone();
+
+        let mut vm = VM::new();
+        vm.set_loop_warning(1000, move |warning| sink.borrow_mut().push(warning.to_string()));
+
+        let deadline = Instant::now() + Duration::from_millis(50);
+        let error = vm.exec_with_deadline(&builder.build(), deadline).unwrap_err();
+
+        assert_eq!(error.kind, ErrorKind::Timeout);
+
+        // Reported once, however long it ran
+        let warnings = warnings.borrow();
+
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].contains("in <zub> ran 1000 times"));
+    }
+
+    #[test]
+    fn boxed_handles() {
+        use super::vm::gc::tag::{ TaggedHandle, Tag };
+
+        // NaN-boxing is only supported with 64-bit pointers, anything else fails to compile
+        assert_eq!(std::mem::size_of::<*mut Object>(), 8);
+
+        let mut heap = Heap::new();
+        let handles = (0..64)
+            .map(|i| heap.insert_temp(Object::String(i.to_string())))
+            .collect::<Vec<_>>();
+
+        for handle in handles {
+            match TaggedHandle::from_handle(handle).decode() {
+                Tag::Handle(decoded) => assert_eq!(decoded, handle),
+                _ => panic!("expected a handle back"),
+            }
+        }
+    }
+
+    #[test]
+    fn native_heap_access() {
+        // Sums every number in arbitrarily nested lists
+        fn deep_sum(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let heap = context.heap();
+
+            let mut sum = 0.0;
+            let mut pending = vec![context.get_arg(1)];
+
+            while let Some(value) = pending.pop() {
+                match value.decode() {
+                    Variant::Float(n) => sum += n,
+                    Variant::Obj(handle) => {
+                        if let Some(list) = heap.get(handle).and_then(|o| o.as_list()) {
+                            pending.extend(list.content.iter().cloned())
+                        }
+                    },
+                    _ => (),
+                }
+            }
+
+            Ok(sum.into())
+        }
+
+        fn tag(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let handle = context.get_arg(1).as_object().unwrap();
+
+            if let Some(Object::List(list)) = context.heap_mut().get_mut(handle) {
+                list.content.push(Value::nil())
+            }
+
+            Ok(Value::nil())
+        }
+
+        let mut builder = IrBuilder::new();
+
+        let nested = builder.list(vec![
+            builder.number(1.0),
+            builder.list(vec![builder.number(2.0), builder.list(vec![builder.number(3.0)])]),
+            builder.list(vec![]),
+            builder.number(4.0),
+        ]);
+        builder.bind(Binding::global("nested"), nested);
+
+        let call = builder.call(builder.var(Binding::global("tag")), vec![builder.var(Binding::global("nested"))], None);
+        builder.emit(call);
+
+        let call = builder.call(builder.var(Binding::global("deep_sum")), vec![builder.var(Binding::global("nested"))], None);
+        builder.bind(Binding::global("sum"), call);
+
+        let mut vm = VM::new();
+        vm.add_native("deep_sum", deep_sum, 1);
+        vm.add_native("tag", tag, 1);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["sum"], 10.0.into());
+
+        let nested = vm.globals["nested"].as_object().unwrap();
+        assert_eq!(vm.heap.get(nested).unwrap().as_list().unwrap().content.len(), 5);
+    }
+
+    #[test]
+    fn invoke_method() {
+        let mut builder = IrBuilder::new();
+
+        let add = builder.method(Binding::local("add", 0, 0), &["n"], |builder| {
+            let count = builder.binary(builder.var(Binding::local("self", 1, 1)), BinaryOp::Index, builder.string("count"));
+            let sum = builder.binary(count, BinaryOp::Add, builder.var(Binding::local("n", 1, 1)));
+
+            builder.ret(Some(sum))
+        });
+        builder.emit(add);
+
+        let counter = builder.dict(
+            vec![builder.string("count"), builder.string("add")],
+            vec![builder.number(40.0), builder.var(Binding::local("add", 0, 0))],
+        );
+        builder.bind(Binding::global("counter"), counter);
+
+        let invoke = builder.invoke(builder.var(Binding::global("counter")), "add", vec![builder.number(2.0)]);
+        builder.bind(Binding::global("result"), invoke);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["result"], 42.0.into());
+
+        let mut builder = IrBuilder::new();
+        let invoke = builder.invoke(builder.var(Binding::global("counter")), "missing", vec![]);
+        builder.emit(invoke);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Message("no method `missing` on dict".into()));
+
+        let mut builder = IrBuilder::new();
+        let invoke = builder.invoke(builder.number(1.0), "add", vec![]);
+        builder.emit(invoke);
+
+        assert!(vm.exec(&builder.build(), false).is_err());
+
+        // Too many arguments for the instruction fail to compile
+        let mut builder = IrBuilder::new();
+        let args = (0 .. 9).map(|n| builder.number(n as f64)).collect();
+        let invoke = builder.invoke(builder.var(Binding::global("counter")), "add", args);
+        builder.emit(invoke);
+
+        let error = vm.exec(&builder.build(), false).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Compile(CompileError::TooManyArguments(9)));
+    }
+
+    #[test]
+    fn program_result() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("a"), builder.number(40.0));
+
+        let sum = builder.binary(builder.var(Binding::global("a")), BinaryOp::Add, builder.number(2.0));
+        builder.emit(sum);
+
+        let mut vm = VM::new();
+
+        assert_eq!(vm.exec_returning(&builder.build(), false).unwrap(), 42.0.into());
+        assert!(vm.stack.is_empty());
+
+        // Ending on a statement gives nil
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("b"), builder.number(1.0));
+
+        assert_eq!(vm.exec_returning(&builder.build(), false).unwrap(), Value::nil());
+
+        // Locals below the result are dropped with it
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::local("x", 0, 0), builder.string("hello"));
+
+        let last = builder.var(Binding::local("x", 0, 0));
+        builder.emit(last);
+
+        let value = vm.exec_returning(&builder.build(), false).unwrap();
+
+        assert_eq!(value.with_heap(&vm.heap).to_string(), "hello");
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn list_search_natives() {
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list(vec![
+            builder.number(1.0),
+            builder.string("two"),
+            builder.list(vec![builder.number(3.0)]),
+        ]);
+        builder.bind(Binding::global("list"), list);
+
+        let search = |builder: &mut IrBuilder, native: &str, result: &str, needle: ExprNode| {
+            let call = builder.call_named(native, vec![builder.var(Binding::global("list")), needle], None);
+            builder.bind(Binding::global(result), call);
+        };
+
+        // Elements that are objects are compared by content
+        let needle = builder.list(vec![builder.number(3.0)]);
+        search(&mut builder, "contains", "has_list", needle);
+        let needle = builder.string("two");
+        search(&mut builder, "index_of", "two_at", needle);
+        let needle = builder.list(vec![builder.number(3.0)]);
+        search(&mut builder, "index_of", "list_at", needle);
+        let needle = builder.number(4.0);
+        search(&mut builder, "contains", "has_four", needle);
+        let needle = builder.list(vec![builder.number(3.0), builder.number(3.0)]);
+        search(&mut builder, "index_of", "missing_at", needle);
+
+        // The string forms still work
+        let call = builder.call_named("index_of", vec![builder.string("hello"), builder.string("llo")], None);
+        builder.bind(Binding::global("llo_at"), call);
+
+        let call = builder.call_named("reverse", vec![builder.var(Binding::global("list"))], None);
+        builder.emit(call);
+
+        let mut vm = VM::new();
+        vm.add_std_string();
+        vm.add_std_list();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["has_list"], true.into());
+        assert_eq!(vm.globals["two_at"], 1.0.into());
+        assert_eq!(vm.globals["list_at"], 2.0.into());
+        assert_eq!(vm.globals["has_four"], false.into());
+        assert_eq!(vm.globals["missing_at"], (-1.0).into());
+        assert_eq!(vm.globals["llo_at"], 2.0.into());
+
+        assert_eq!(
+            vm.globals["list"].display_with(&vm.heap, DisplayPolicy::Full),
+            "[[3], \"two\", 1]"
+        );
+    }
+
+    #[test]
+    fn native_argument_errors() {
+        let run = |build: &dyn Fn(&mut IrBuilder)| {
+            let mut builder = IrBuilder::new();
+            build(&mut builder);
+
+            let mut vm = VM::new();
+            vm.add_std_dict();
+            vm.add_std_string();
+            vm.add_std_list();
+
+            match vm.exec(&builder.build(), false).unwrap_err().kind {
+                ErrorKind::Message(message) => message,
+                kind => panic!("unexpected error {:?}", kind),
+            }
+        };
+
+        let call = |name: &'static str, args: fn(&IrBuilder) -> Vec<ExprNode>| {
+            run(&move |builder: &mut IrBuilder| {
+                let call = builder.call_named(name, args(builder), None);
+                builder.emit(call)
+            })
+        };
+
+        assert_eq!(
+            call("dict_len", |b| vec![b.number(1.0)]),
+            "native `dict_len` expects a dict as argument 1, not number"
+        );
+        assert_eq!(
+            call("dict_merge", |b| vec![b.dict(vec![], vec![]), b.string("x")]),
+            "native `dict_merge` expects a dict as argument 2, not string"
+        );
+        assert_eq!(
+            call("contains", |b| vec![b.nil(), b.number(1.0)]),
+            "native `contains` expects a list or string as argument 1, not nil"
+        );
+        assert_eq!(
+            call("index_of", |b| vec![b.string("abc"), b.number(1.0)]),
+            "native `index_of` expects a string as argument 2, not number"
+        );
+        assert_eq!(
+            call("total_cmp", |b| vec![b.number(1.0), b.bool(true)]),
+            "native `total_cmp` expects a number as argument 2, not boolean"
+        );
+
+        // Invoked natives are named too, though the receiver takes their stack slot
+        let invoked = run(&|builder: &mut IrBuilder| {
+            let dict = builder.dict(vec![builder.string("len")], vec![builder.var(Binding::global("dict_len"))]);
+            let invoke = builder.invoke(dict, "len", vec![builder.number(5.0)]);
+            builder.emit(invoke)
+        });
+
+        assert_eq!(invoked, "native `dict_len` expects a dict as argument 1, not number");
+    }
+
+    #[test]
+    fn nested_function_constants_survive() {
+        fn gc(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            context.vm.collect();
+            Ok(Value::nil())
+        }
+
+        let mut builder = IrBuilder::new();
+
+        // The string is only referenced by the constants of `inner`, itself a constant of `outer`
+        let outer = builder.function(Binding::local("outer", 0, 0), &[], |builder| {
+            let inner = builder.function(Binding::local("inner", 1, 1), &[], |builder| {
+                builder.ret(Some(builder.string("buried in inner")))
+            });
+            builder.emit(inner);
+
+            let gc = builder.call_named("gc", vec![], None);
+            builder.emit(gc);
+
+            let call = builder.call(builder.var(Binding::local("inner", 1, 1)), vec![], None);
+            builder.ret(Some(call))
+        });
+        builder.emit(outer);
+
+        // A method running while nothing else refers to it any more
+        let method = builder.method(Binding::local("method", 0, 0), &[], |builder| {
+            let clear = builder.call_named("dict_clear", vec![builder.var(Binding::local("self", 1, 1))], None);
+            builder.emit(clear);
+
+            let gc = builder.call_named("gc", vec![], None);
+            builder.emit(gc);
+
+            builder.ret(Some(builder.string("buried in method")))
+        });
+        builder.emit(method);
+
+        let call = builder.call(builder.var(Binding::local("outer", 0, 0)), vec![], None);
+        builder.bind(Binding::global("inner"), call);
+
+        let dict = builder.dict(vec![builder.string("method")], vec![builder.var(Binding::local("method", 0, 0))]);
+        builder.bind(Binding::global("object"), dict);
+
+        let mut vm = VM::new();
+        vm.add_native("gc", gc, 0);
+        vm.add_std_dict();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        // Only the dict refers to the method now, until it clears itself
+        let mut builder = IrBuilder::new();
+
+        let invoke = builder.invoke(builder.var(Binding::global("object")), "method", vec![]);
+        builder.bind(Binding::global("method"), invoke);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        for (name, expected) in &[("inner", "buried in inner"), ("method", "buried in method")] {
+            let handle = vm.globals[*name].as_object().unwrap();
+            assert_eq!(vm.heap.get(handle).and_then(|o| o.as_string()).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn compile_without_running() {
+        let mut builder = IrBuilder::new();
+
+        let sum = builder.binary(builder.number(1.0), BinaryOp::Add, builder.number(2.0));
+        builder.bind(Binding::global("sum"), sum);
+
+        let mut vm = VM::new();
+        let function = vm.compile_only(&builder.build()).unwrap();
+
+        assert!(!vm.globals.contains_key("sum"));
+
+        let listing = Disassembler::new(function.chunk(), &vm.heap).listing();
+        let ops = listing.lines()
+            .filter_map(|line| line.split(" | ").nth(1))
+            .collect::<Vec<_>>();
+
+        assert_eq!(ops, vec![
+            "FLOAT\t1 (+1)",
+            "FLOAT\t2 (+1)",
+            "ADD (-1)",
+            "DEFINE_GLOBAL\tsum (-1)",
+            "NIL (+1)",
+            "RETURN (-1)",
+        ]);
+
+        // Running it later does what `exec` would have
+        vm.exec_function(function, false).unwrap();
+        assert_eq!(vm.globals["sum"], 3.0.into());
+    }
+
+    #[test]
+    fn do_while_loop() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("count", 0, 0), builder.number(0.0));
+
+        let increment = |builder: &mut IrBuilder| {
+            let count = builder.var(Binding::local("count", 0, 0));
+            let next = builder.binary(count.clone(), BinaryOp::Add, builder.number(1.0));
+
+            builder.mutate(count, next)
+        };
+
+        // Runs once though the condition never holds
+        let once = builder.do_while(increment, builder.bool(false));
+        builder.emit(once);
+
+        builder.bind(Binding::global("once"), builder.var(Binding::local("count", 0, 0)));
+
+        let below = builder.binary(builder.var(Binding::local("count", 0, 0)), BinaryOp::Lt, builder.number(5.0));
+        let counting = builder.do_while(increment, below);
+        builder.emit(counting);
+
+        builder.bind(Binding::global("counted"), builder.var(Binding::local("count", 0, 0)));
+
+        let broken = builder.do_while(|builder| {
+            builder.bind(Binding::local("x", 0, 0), builder.number(1.0));
+            builder.break_()
+        }, builder.bool(true));
+        builder.emit(broken);
+
+        builder.bind(Binding::global("after"), builder.var(Binding::local("count", 0, 0)));
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["once"], 1.0.into());
+        assert_eq!(vm.globals["counted"], 5.0.into());
+        assert_eq!(vm.globals["after"], 5.0.into());
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn host_map_keys() {
+        use std::collections::HashMap;
+
+        let mut heap = Heap::new();
+
+        let a = Value::object(heap.insert_temp(Object::String("key".into())));
+        let b = Value::object(heap.insert_temp(Object::String("key".into())));
+        let c = Value::object(heap.insert_temp(Object::String("other".into())));
+
+        assert!(!a.is_same(&b));
+
+        let mut memo = HashMap::new();
+
+        memo.insert(HashableValue::new(a, &heap).unwrap(), 1);
+        memo.insert(HashableValue::new(b, &heap).unwrap(), 2);
+        memo.insert(HashableValue::new(c, &heap).unwrap(), 3);
+        memo.insert(HashableValue::new(1.0.into(), &heap).unwrap(), 4);
+        memo.insert(HashableValue::new(Value::nil(), &heap).unwrap(), 5);
+
+        assert_eq!(memo.len(), 4);
+        assert_eq!(memo[&HashableValue::new(a, &heap).unwrap()], 2);
+        assert_eq!(memo[&HashableValue::new(1.0.into(), &heap).unwrap()], 4);
+
+        // The key made first stays in the map
+        assert!(memo.keys().any(|key| key.value().is_same(&a)));
+
+        let list = Value::object(heap.insert_temp(Object::List(List::new(vec![]))));
+        assert!(HashableValue::new(list, &heap).is_none());
+    }
+
+    #[test]
+    fn expression_statement_value() {
+        let sum = || {
+            let builder = IrBuilder::new();
+            builder.binary(builder.number(1.0), BinaryOp::Add, builder.number(1.0))
+        };
+
+        let mut vm = VM::new();
+
+        assert_eq!(vm.eval_expression(sum()).unwrap(), 2.0.into());
+
+        // As a statement, with the value popped at the end
+        let mut builder = IrBuilder::new();
+        builder.emit(sum());
+        builder.emit(Expr::Pop.node(TypeInfo::nil()));
+
+        let program = builder.build();
+
+        assert_eq!(vm.exec_returning(&program, false).unwrap(), 2.0.into());
+
+        let function = vm.compile_only(&program).unwrap();
+        let listing = Disassembler::new(function.chunk(), &vm.heap).listing();
+
+        assert!(listing.contains("POP"));
+        assert!(listing.ends_with("net stack effect: +0\n"));
+
+        vm.exec(&program, false).unwrap();
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn heap_finalizers() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let finalized = Rc::new(RefCell::new(Vec::new()));
+
+        let mut heap = Heap::new();
+
+        let temp = heap.insert_temp(Object::List(List::new(vec![1.0.into()])));
+        let kept = heap.insert(Object::List(List::new(vec![2.0.into()])));
+
+        for handle in [temp, kept.handle()] {
+            let sink = finalized.clone();
+
+            heap.set_finalizer(handle, move |object| {
+                if let Object::List(list) = object {
+                    sink.borrow_mut().push(list.content[0].as_float())
+                }
+            })
+        }
+
+        heap.clean();
+        heap.clean();
+
+        assert_eq!(*finalized.borrow(), vec![1.0]);
+        assert!(heap.contains(kept.handle()));
+
+        // Whatever is left is finalized with the heap
+        drop(kept);
+        drop(heap);
+
+        assert_eq!(*finalized.borrow(), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn display_cache() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let mut builder = IrBuilder::new();
+
+        let list = builder.list(vec![builder.number(1.0), builder.list(vec![builder.number(2.0)])]);
+        builder.bind(Binding::global("list"), list);
+
+        let mut vm = VM::new();
+        vm.exec(&builder.build(), false).unwrap();
+
+        vm.heap.set_display_cache(true);
+
+        let list = vm.globals["list"];
+        let handle = list.as_object().unwrap();
+
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Full), "[1, [2]]");
+
+        // A change the heap doesn't see proves the rendering came from the cache
+        unsafe {
+            if let Object::List(list) = handle.get_mut_unchecked() {
+                list.content.push(3.0.into())
+            }
+        }
+
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Full), "[1, [2]]");
+
+        // Scripts that only touch upvalues leave it be
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("n", 0, 0), builder.number(0.0));
+
+        let bump = builder.function(Binding::local("bump", 0, 0), &[], |builder| {
+            for _ in 0 .. 3 {
+                let n = builder.var(Binding::local("n", 1, 0));
+                let next = builder.binary(n.clone(), BinaryOp::Add, builder.number(1.0));
+
+                builder.mutate(n, next);
+            }
+
+            builder.ret(None)
+        });
+
+        builder.emit(bump);
+
+        let call = builder.call(builder.var(Binding::local("bump", 0, 0)), vec![], None);
+        builder.emit(call);
+        builder.emit(Expr::Pop.node(TypeInfo::nil()));
+
+        builder.bind(Binding::global("n"), builder.var(Binding::local("n", 0, 0)));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["n"], 3.0.into());
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Full), "[1, [2]]");
+
+        // Changing the nested list from a script invalidates it
+        let mut builder = IrBuilder::new();
+
+        let inner = builder.binary(builder.var(Binding::global("list")), BinaryOp::Index, builder.number(1.0));
+        let set = builder.set_element(inner, builder.number(0.0), builder.number(4.0));
+        builder.emit(set);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Full), "[1, [4], 3]");
+
+        // Changing another list leaves it be, and printing in full goes through the cache too
+        let other = vm.new_list(vec![1.0.into()]);
+        vm.globals.insert("other".into(), other);
+
+        unsafe {
+            if let Object::List(list) = handle.get_mut_unchecked() {
+                list.content.push(5.0.into())
+            }
+        }
+
+        let printed = Rc::new(RefCell::new(String::new()));
+        let output = printed.clone();
+
+        vm.set_print_hook(move |text| output.borrow_mut().push_str(text));
+        vm.set_print_policy(DisplayPolicy::Full);
+
+        let mut builder = IrBuilder::new();
+
+        let set = builder.set_element(builder.var(Binding::global("other")), builder.number(0.0), builder.number(2.0));
+        builder.emit(set);
+        builder.print(builder.var(Binding::global("list")));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(*printed.borrow(), "[1, [4], 3]\n");
+        assert_eq!(format!("{:#}", list.with_heap(&vm.heap)), "[1, [4], 3]");
+        assert_eq!(format!("{}", list.with_heap(&vm.heap)), "<list [4]>");
+
+        vm.heap.set_display_cache(false);
+        assert_eq!(list.display_with(&vm.heap, DisplayPolicy::Full), "[1, [4], 3, 5]");
+    }
+
+    #[test]
+    fn list_natives() {
+        fn nothing(_: &mut CallContext) -> Result<Value, RuntimeError> {
+            Ok(Value::nil())
+        }
+
+        let mut vm = VM::new();
+
+        vm.add_native("zero", nothing, 0);
+        vm.add_native("two", nothing, 2);
+        vm.add_native("one", nothing, 1);
+
+        let mut builder = IrBuilder::new();
+        builder.bind(Binding::global("not_native"), builder.string("value"));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.native_names(), vec![("one", 1), ("two", 2), ("zero", 0)]);
+    }
+
+    #[test]
+    fn set_element_errors() {
+        let set = |target: fn(&IrBuilder) -> ExprNode, index: fn(&IrBuilder) -> ExprNode| {
+            let mut builder = IrBuilder::new();
+
+            let set = builder.set_element(target(&builder), index(&builder), builder.number(0.0));
+            builder.emit(set);
+
+            VM::new().exec(&builder.build(), false).map_err(|e| e.kind)
+        };
+
+        let message = |text: &str| Err(ErrorKind::Message(text.into()));
+
+        let list = |b: &IrBuilder| b.list(vec![b.number(1.0)]);
+        let dict = |b: &IrBuilder| b.empty_dict();
+
+        assert_eq!(set(|b| b.number(1.0), |b| b.number(0.0)), message("can't set an element of number"));
+        assert_eq!(set(|b| b.string("s"), |b| b.number(0.0)), message("can't set an element of string"));
+        assert_eq!(set(list, |b| b.string("key")), message("can't index list with string"));
+        assert_eq!(set(list, |b| b.number(1.0)), message("index 1 out of bounds for list of length 1"));
+        assert_eq!(set(dict, |b| b.list(vec![])), message("can't use list as a dict key"));
+
+        assert!(set(list, |b| b.number(0.0)).is_ok());
+        assert!(set(dict, |b| b.string("key")).is_ok());
+    }
+
+    #[test]
+    fn nan_boxing_round_trip() {
+        use super::vm::gc::tag::{ TaggedHandle, Tag, PAYLOAD };
+
+        // xorshift64*, deterministic so failures reproduce
+        let mut state = 0x2545_f491_4f6c_dd1du64;
+        let mut next = move || {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+        };
+
+        let check_float = |n: f64| {
+            match Value::float(n).decode() {
+                Variant::Float(m) if n.is_nan() => assert!(m.is_nan(), "{:#x} decoded as {}", n.to_bits(), m),
+                Variant::Float(m) => assert_eq!(m.to_bits(), n.to_bits()),
+                other => panic!("float {:#x} decoded as {:?}", n.to_bits(), other),
+            }
+        };
+
+        // Bit patterns overlapping the tag and handle encodings, which only NaNs can have
+        let edges = [
+            0.0, -0.0, f64::INFINITY, f64::NEG_INFINITY, f64::MIN_POSITIVE, f64::MAX,
+            f64::from_bits(0x7ffc_0000_0000_0000), // the tag prefix itself
+            f64::from_bits(0x7ffc_0000_0000_0001), // the true tag
+            f64::from_bits(0xfffc_0000_0000_0008), // a handle
+            f64::from_bits(0xffff_ffff_ffff_ffff),
+            f64::from_bits(0x7ff0_0000_0000_0001), // a signalling NaN
+        ];
+
+        for &n in edges.iter() {
+            check_float(n)
+        }
+
+        for _ in 0 .. 100_000 {
+            let bits = next();
+
+            check_float(f64::from_bits(bits));
+            // Mostly NaNs, with random sign and payload
+            check_float(f64::from_bits(bits | 0x7ff0_0000_0000_0000));
+        }
+
+        assert_eq!(Value::truelit().decode(), Variant::True);
+        assert_eq!(Value::falselit().decode(), Variant::False);
+        assert_eq!(Value::nil().decode(), Variant::Nil);
+
+        // Any aligned address within the payload comes back as the same handle
+        for _ in 0 .. 100_000 {
+            let address = next() & PAYLOAD & !7;
+
+            let raw = unsafe { TaggedHandle::<Object>::from_raw(address) };
+            let handle = match unsafe { TaggedHandle::<Object>::from_raw(address | !PAYLOAD) }.decode() {
+                Tag::Handle(handle) => handle,
+                other => panic!("address {:#x} decoded as {:?}", address, other),
+            };
+
+            match TaggedHandle::from_handle(handle).decode() {
+                Tag::Handle(decoded) => assert_eq!(decoded, handle),
+                other => panic!("handle {:#x} decoded as {:?}", address, other),
+            }
+            assert_eq!(raw.to_raw(), address);
+        }
+    }
+
+    #[test]
+    fn block_expression() {
+        let mut builder = IrBuilder::new();
+
+        // x = { let a = 40; let b = 2; a + b }
+        let block = builder.value_block(|builder| {
+            builder.bind(Binding::local("a", 0, 0), builder.number(40.0));
+            builder.bind(Binding::local("b", 0, 0), builder.number(2.0));
+
+            builder.binary(builder.var(Binding::local("a", 0, 0)), BinaryOp::Add, builder.var(Binding::local("b", 0, 0)))
+        });
+        builder.bind(Binding::global("x"), block);
+
+        // A local captured by a closure is closed over as the block ends
+        let block = builder.value_block(|builder| {
+            builder.bind(Binding::local("a", 0, 0), builder.number(1.0));
+
+            let get = builder.function(Binding::local("get", 0, 0), &[], |builder| {
+                builder.ret(Some(builder.var(Binding::local("a", 1, 0))))
+            });
+            builder.emit(get);
+
+            builder.var(Binding::local("get", 0, 0))
+        });
+        builder.bind(Binding::global("get"), block);
+
+        // Locals declared after a block still find their slots
+        builder.bind(Binding::local("c", 0, 0), builder.number(3.0));
+        builder.bind(Binding::global("c"), builder.var(Binding::local("c", 0, 0)));
+
+        let call = builder.call(builder.var(Binding::global("get")), vec![], None);
+        builder.bind(Binding::global("got"), call);
+
+        let mut vm = VM::new();
+
+        // The slot holding the block's value is hidden from debug info
+        let program = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+        assert!(program.locals().iter().all(|local| !local.name.trim().is_empty()));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["x"], 42.0.into());
+        assert_eq!(vm.globals["got"], 1.0.into());
+        assert_eq!(vm.globals["c"], 3.0.into());
+    }
+
+    #[test]
+    fn nan_comparisons() {
+        use self::BinaryOp::*;
+
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::global("nan"), builder.number(f64::NAN));
+
+        let ops = [("gt", Gt), ("lt", Lt), ("ge", GtEqual), ("le", LtEqual), ("eq", Equal)];
+
+        for (name, op) in ops.iter() {
+            let left = builder.binary(builder.var(Binding::global("nan")), op.clone(), builder.number(1.0));
+            builder.bind(Binding::global(&format!("{}_left", name)), left);
+
+            let right = builder.binary(builder.number(1.0), op.clone(), builder.var(Binding::global("nan")));
+            builder.bind(Binding::global(&format!("{}_right", name)), right);
+        }
+
+        let ge = builder.binary(builder.number(2.0), GtEqual, builder.number(2.0));
+        builder.bind(Binding::global("ge_equal"), ge);
+
+        let list = builder.list(vec![
+            builder.number(3.0),
+            builder.var(Binding::global("nan")),
+            builder.number(-1.0),
+            builder.number(2.0),
+        ]);
+        builder.bind(Binding::global("list"), list);
+
+        let sort = builder.call_named("sort_by", vec![builder.var(Binding::global("list")), builder.var(Binding::global("total_cmp"))], None);
+        builder.emit(sort);
+
+        let mut vm = VM::new();
+        vm.add_std_list();
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        for (name, _) in ops.iter() {
+            assert_eq!(vm.globals[&format!("{}_left", name)], false.into(), "NaN {}", name);
+            assert_eq!(vm.globals[&format!("{}_right", name)], false.into(), "{} NaN", name);
+        }
+
+        assert_eq!(vm.globals["ge_equal"], true.into());
+
+        assert_eq!(
+            vm.globals["list"].display_with(&vm.heap, DisplayPolicy::Full),
+            "[-1, 2, 3, NaN]"
+        );
+    }
+
+    #[test]
+    fn flush_buffered_output() {
+        use std::rc::Rc;
+        use std::cell::RefCell;
+
+        let mut builder = IrBuilder::new();
+
+        builder.write(builder.string("> "));
+        builder.write(builder.number(1.0));
+
+        let pending = Rc::new(RefCell::new(String::new()));
+        let visible = Rc::new(RefCell::new(String::new()));
+
+        let mut vm = VM::new();
+
+        let sink = pending.clone();
+        vm.set_print_hook(move |text| sink.borrow_mut().push_str(text));
+
+        let (from, to) = (pending.clone(), visible.clone());
+        vm.set_flush_hook(move || to.borrow_mut().push_str(&from.borrow_mut().split_off(0)));
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(*visible.borrow(), "");
+
+        vm.flush_output();
+
+        assert_eq!(*visible.borrow(), "> 1");
+        assert_eq!(*pending.borrow(), "");
+
+        // Without hooks stdout itself is flushed
+        VM::new().flush_output();
+    }
+
+    #[test]
+    fn always_returns() {
+        let mut builder = IrBuilder::new();
+
+        let sign = builder.function(Binding::global("sign"), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            let negative = builder.binary(n, BinaryOp::Lt, builder.number(0.0));
+
+            let branch = builder.if_(
+                negative,
+                |builder| builder.ret(Some(builder.number(-1.0))),
+                Some(|builder| builder.ret(Some(builder.number(1.0)))),
+            );
+
+            builder.emit(branch)
+        });
+
+        builder.emit(sign);
+
+        // Nothing is returned when n isn't negative
+        let check = builder.function(Binding::global("check"), &["n"], |builder| {
+            let n = builder.var(Binding::local("n", 1, 1));
+            let negative = builder.binary(n, BinaryOp::Lt, builder.number(0.0));
+
+            let branch = builder.if_(negative, |builder| builder.ret(Some(builder.number(-1.0))), None);
+            builder.emit(branch)
+        });
+
+        builder.emit(check);
+
+        let mut vm = VM::new();
+        let program = Compiler::new(&mut vm.heap).compile(&builder.build()).unwrap();
+
+        assert!(program.always_returns());
+
+        let functions = program.chunk().constants()
+            .filter_map(|constant| constant.as_object())
+            .filter_map(|handle| vm.heap.get(handle).unwrap().as_function())
+            .map(|function| (function.name().to_string(), function.always_returns()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(functions, vec![("sign".to_string(), true), ("check".to_string(), false)]);
+    }
+
+    #[test]
+    fn unhashable_dict_keys() {
+        let run = |build: fn(&mut IrBuilder)| {
+            let mut builder = IrBuilder::new();
+            build(&mut builder);
+
+            VM::new().exec(&builder.build(), false).map_err(|e| e.kind)
+        };
+
+        let message = Err(ErrorKind::Message("can't use list as a dict key".into()));
+
+        let index = run(|builder| {
+            let key = builder.list(vec![builder.number(1.0)]);
+            let get = builder.binary(builder.empty_dict(), BinaryOp::Index, key);
+            builder.bind(Binding::global("x"), get)
+        });
+
+        let literal = run(|builder| {
+            let dict = builder.dict(vec![builder.list(vec![])], vec![builder.number(1.0)]);
+            builder.bind(Binding::global("x"), dict)
+        });
+
+        assert_eq!(index, message);
+        assert_eq!(literal, message);
+
+        // Front-ends only name methods with strings, so the list constant is put there by hand
+        let mut vm = VM::new();
+        let name = vm.new_list(Vec::new());
+
+        let mut function = FunctionBuilder::new("invoke", 0);
+
+        {
+            let chunk = function.chunk_mut();
+            let idx = chunk.add_constant(name).unwrap();
+
+            chunk.write(Op::Dict, 1);
+            chunk.write_byte(0);
+            chunk.write(Op::Invoke(idx as u8, 0), 1);
+            chunk.write(Op::Return, 1);
+        }
+
+        let error = vm.exec_function(function.build(), false).unwrap_err();
+        assert_eq!(Err(error.kind), message);
+    }
+
+    #[test]
+    fn corrupt_bytecode() {
+        // Each chunk starts with a `NIL`, which gives it line info for the backtrace
+        let run = |code: &[u8]| {
+            let mut function = FunctionBuilder::new("corrupt", 0);
+            function.chunk_mut().write(Op::Nil, 1);
+
+            for &byte in code {
+                function.chunk_mut().write_byte(byte)
+            }
+
+            VM::new().exec_function(function.build(), false).unwrap_err().kind
+        };
+
+        let message = |text: &str| ErrorKind::Message(text.into());
+
+        // A jump missing the second byte of its target
+        assert_eq!(run(&[0x0c, 0x00]), message("truncated instruction at ip 1"));
+        // An immediate float cut short
+        assert_eq!(run(&[0x13, 0x00, 0x00, 0x00]), message("truncated instruction at ip 1"));
+
+        assert_eq!(run(&[0x2a]), message("unknown opcode 0x2a at ip 1"));
+        assert_eq!(run(&[0x01, 0x07, 0x00]), message("invalid constant index 7"));
+    }
+
+    #[test]
+    fn validate_unused_opcodes() {
+        // 0x2a to 0x2f sit between `SetElement` and `Index` but aren't instructions
+        let heap = Heap::new();
+
+        for op in 0x2a ..= 0x2f {
+            let mut chunk = Chunk::new("unused".into());
+
+            chunk.write(Op::Nil, 1);
+            chunk.write_byte(op);
+            chunk.write(Op::Return, 1);
+
+            assert_eq!(chunk.validate(&heap), Err(ValidationError::UnknownOp(1)));
+        }
+    }
+
+    #[test]
+    fn native_recovers_from_call_error() {
+        let mut builder = IrBuilder::new();
+
+        builder.bind(Binding::local("a", 0, 0), builder.number(1.0));
+
+        // Fails with a partly evaluated sum on its stack
+        let bad = builder.function(Binding::local("bad", 0, 0), &[], |builder| {
+            let sum = builder.binary(builder.number(1.0), BinaryOp::Add, builder.var(Binding::global("missing")));
+            builder.ret(Some(sum))
+        });
+
+        builder.emit(bad);
+
+        let call = builder.call_named("attempt", vec![builder.var(Binding::local("bad", 0, 0))], None);
+        let result = builder.binary(builder.var(Binding::local("a", 0, 0)), BinaryOp::Add, call);
+
+        builder.bind(Binding::global("result"), result);
+        builder.bind(Binding::global("after"), builder.var(Binding::local("a", 0, 0)));
+
+        let call = builder.call_named("spread", vec![builder.var(Binding::local("bad", 0, 0))], None);
+        builder.bind(Binding::global("spread"), call);
+
+        fn attempt(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let f = context.get_arg(1);
+
+            match context.call(f, &[]) {
+                Ok(value) => Ok(value),
+                Err(_) => Ok(2.0.into()),
+            }
+        }
+
+        fn spread(context: &mut CallContext) -> Result<Value, RuntimeError> {
+            let f = context.get_arg(1);
+
+            match context.call(f, &[Value::nil(); 256]) {
+                Ok(_) => Ok(Value::nil()),
+                Err(error) => Ok(context.new_string(&error.kind.to_string())),
+            }
+        }
+
+        let mut vm = VM::new();
+        vm.add_native("attempt", attempt, 1);
+        vm.add_native("spread", spread, 1);
+
+        vm.exec(&builder.build(), false).unwrap();
+
+        assert_eq!(vm.globals["result"], 3.0.into());
+        assert_eq!(vm.globals["after"], 1.0.into());
+        assert_eq!(
+            vm.globals["spread"].with_heap(&vm.heap).to_string(),
+            "can't call with 256 arguments, at most 255 are allowed"
+        );
+    }
+
+    #[test]
+    fn global_call_argument_rebinds_callee() {
+        let mut builder = IrBuilder::new();
+
+        let one = builder.function(Binding::global("pick"), &["x"], |builder| builder.ret(Some(builder.number(1.0))));
+        builder.emit(one);
+
+        let two = builder.function(Binding::global("two"), &["x"], |builder| builder.ret(Some(builder.number(2.0))));
+        builder.emit(two);
+
+        // Rebinds `pick` while the arguments of a call to it are evaluated
+        let swap = builder.function(Binding::global("swap"), &[], |builder| {
+            builder.mutate(builder.var(Binding::global("pick")), builder.var(Binding::global("two")));
+            builder.ret(Some(builder.number(0.0)))
+        });
+
+        builder.emit(swap);
+
+        let rebinding = builder.call_named("pick", vec![builder.call_named("swap", vec![], None)], None);
+        builder.bind(Binding::global("first"), rebinding);
+
+        let plain = builder.call_named("pick", vec![builder.number(0.0)], None);
+        builder.bind(Binding::global("second"), plain);
+
+        let mut vm = VM::new();
+        vm.set_op_counting(true);
+        vm.exec(&builder.build(), false).unwrap();
+
+        // The callee was looked up before the argument changed it, like any other call
+        assert_eq!(vm.globals["first"], 1.0.into());
+        assert_eq!(vm.globals["second"], 2.0.into());
+
+        // Only the calls of `swap` and with a plain argument are fused
+        let counts = vm.op_counts();
+
+        assert_eq!(counts["CallGlobal"], 2);
+        assert_eq!(counts["Call"], 1);
+    }
 }
@@ -1,14 +1,21 @@
 use super::*;
 use gc::trace::{ Trace, Tracer };
 
+use std::collections::HashMap;
+
 #[derive(Debug, Clone)]
 pub struct Chunk {
     code: Vec<u8>,
     name: String,
     constants: Vec<Value>,
+    constant_slots: HashMap<Value, u16>,
     lines: Vec<Line>,
+    spans: Vec<(usize, Option<Span>)>,
 }
 
+/// A range of source positions, in whatever unit the front-end uses, e.g. byte offsets.
+pub type Span = (u32, u32);
+
 impl Trace<Object> for Chunk {
     fn trace(&self, tracer: &mut Tracer<Object>) {
         self.constants.trace(tracer);
@@ -27,7 +34,9 @@ impl Chunk {
             code: Vec::new(),
             name,
             constants: Vec::new(),
-            lines: Vec::new()
+            constant_slots: HashMap::new(),
+            lines: Vec::new(),
+            spans: Vec::new(),
         }
     }
 
@@ -48,24 +57,40 @@ impl Chunk {
         (0..8).for_each(|i| self.write_byte(((val >> i * 8) & 0xFF) as u8))
     }
 
+    /// Overwrite `len` bytes starting at `idx` with `Nop`s, removing instructions without shifting
+    /// the offsets of the code following them.
+    pub fn pad_nop(&mut self, idx: usize, len: usize) {
+        let mut nop = Vec::new();
+        Op::Nop.write(&mut nop);
+
+        for byte in self.code[idx .. idx + len].iter_mut() {
+            *byte = nop[0]
+        }
+    }
+
+    /// Add a constant, reusing the slot of an equal one. Equality is that of `Value`, so `0.0`
+    /// and `-0.0` get separate slots, as merging them would change the result of e.g. `1 / -0.0`,
+    /// while all NaNs share one. Fails once the constant would need an index past 16 bits.
     #[inline]
-    pub fn add_constant(&mut self, constant: Value) -> u8 {
-        for (i, c) in self.constants.iter().enumerate() {
-            if *c == constant {
-                return i as u8;
-            }
+    pub fn add_constant(&mut self, constant: Value) -> Result<u16, CompileError> {
+        if let Some(&slot) = self.constant_slots.get(&constant) {
+            return Ok(slot)
         }
 
-        if self.constants.len() == 1028 {
-            panic!("A chunk cannot have more than 1028 constants");
+        if self.constants.len() > u16::MAX as usize {
+            return Err(CompileError::ConstantTooFar(self.constants.len()))
         }
 
+        let slot = self.constants.len() as u16;
+
         self.constants.push(constant);
-        self.constants.len() as u8 - 1
+        self.constant_slots.insert(constant, slot);
+
+        Ok(slot)
     }
 
     #[inline]
-    pub fn string_constant(&mut self, heap: &mut Heap<Object>, string: &str) -> u8 {
+    pub fn string_constant(&mut self, heap: &mut Heap<Object>, string: &str) -> Result<u16, CompileError> {
         for (i, c) in self.constants().enumerate() {
             let obj = c
                 .as_object()
@@ -74,7 +99,7 @@ impl Chunk {
 
             if let Some(s) = obj {
                 if s == string {
-                    return i as u8
+                    return Ok(i as u16)
                 }
             }
         }
@@ -109,10 +134,35 @@ impl Chunk {
     }
 
     #[inline]
-    pub fn get_constant(&self, idx: u8) -> Option<&Value> {
+    pub fn get_constant(&self, idx: u16) -> Option<&Value> {
         self.constants.get(idx as usize)
     }
 
+    /// Attribute the code written from now on to a source span, or to none.
+    pub fn set_span(&mut self, span: Option<Span>) {
+        if self.span(self.code.len()) == span {
+            return
+        }
+
+        match self.spans.last_mut() {
+            Some(last) if last.0 == self.code.len() => last.1 = span,
+            _ => self.spans.push((self.code.len(), span)),
+        }
+    }
+
+    /// The source span of the code at `offset`, if the front-end gave one.
+    pub fn span(&self, offset: usize) -> Option<Span> {
+        let idx = self.spans.partition_point(|&(start, _)| start <= offset);
+
+        idx.checked_sub(1).and_then(|idx| self.spans[idx].1)
+    }
+
+    /// The offsets at which the source span changes, each followed by the span of the code from
+    /// there on.
+    pub fn source_map(&self) -> &[(usize, Option<Span>)] {
+        &self.spans
+    }
+
     pub fn line(&self, offset: usize) -> usize {
         let idx =
             self.lines
@@ -127,39 +177,195 @@ impl Chunk {
         self.code[idx]
     }
 
+    // Operands are little-endian
     #[inline]
     pub fn read_u16(&self, idx: usize) -> u16 {
-        let mut t = 0u16;
-        let size = ::std::mem::size_of::<u16>();
-        
-        unsafe {
-            ::std::ptr::copy_nonoverlapping(
-                &self.code[idx],
-                &mut t as *mut u16 as *mut u8,
-                size);
-        }
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(&self.code[idx .. idx + 2]);
 
-        t.to_le()
+        u16::from_le_bytes(bytes)
     }
 
     #[inline]
     pub fn read_u64(&self, idx: usize) -> u64 {
-        let mut t = 0u64;
-        let size = ::std::mem::size_of::<u64>();
-        
-        unsafe {
-            ::std::ptr::copy_nonoverlapping(
-                &self.code[idx],
-                &mut t as *mut u64 as *mut u8,
-                size);
-        }
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(&self.code[idx .. idx + 8]);
 
-        t.to_le()
+        u64::from_le_bytes(bytes)
     }
 
     pub fn name(&self) -> &str {
         &self.name
     }
+
+    /// Length in bytes of the instruction at `ip`, operands included.
+    /// The heap is needed to look up how many upvalue operands follow a closure.
+    pub fn instruction_len(&self, ip: usize, heap: &Heap<Object>) -> Result<usize, ValidationError> {
+        let operand = |offset: usize| {
+            self.code.get(ip + offset).cloned().ok_or(ValidationError::Truncated(ip))
+        };
+
+        let len = match self.read_byte(ip) {
+            0x00 | 0x02 ..= 0x0b | 0x0e | 0x14 ..= 0x1f | 0x21 | 0x27 | 0x29 | 0x30 ..= 0x32 | 0x34 | 0x3a | 0x3d | 0x3e => 1,
+            0x01 | 0x0f ..= 0x12 | 0x22 | 0x23 | 0x25 | 0x26 | 0x28 | 0x33 | 0x39 => 2,
+            0x0c | 0x0d | 0x20 | 0x35 | 0x36 | 0x38 | 0x3b | 0x3c | 0x3f ..= 0x41 => 3,
+            0x43 => 4,
+            0x13 => 9,
+
+            // The function constant knows how many upvalue pairs follow
+            0x24 | 0x42 => {
+                let (idx, len) = if self.code[ip] == 0x24 {
+                    (operand(1)? as u16, 2)
+                } else {
+                    (u16::from_le_bytes([operand(1)?, operand(2)?]), 3)
+                };
+
+                let upvalues = self.get_constant(idx)
+                    .and_then(|c| c.as_object())
+                    .and_then(|o| heap.get(o))
+                    .and_then(|o| o.as_function().map(|f| f.upvalue_count()))
+                    .ok_or(ValidationError::BadClosure(ip))?;
+
+                len + upvalues * 3
+            },
+
+            // The base, the number of keys and a target for each, plus the default
+            0x37 => 10 + (operand(9)? as usize + 1) * 2,
+
+            _ => return Err(ValidationError::UnknownOp(ip)),
+        };
+
+        if ip + len > self.code.len() {
+            return Err(ValidationError::Truncated(ip))
+        }
+
+        Ok(len)
+    }
+
+    /// Walk the code, checking that every instruction is complete and that every jump lands at
+    /// the start of an instruction, or right at the end of the chunk. Closures' function constants
+    /// are looked up on `heap`.
+    pub fn validate(&self, heap: &Heap<Object>) -> Result<(), ValidationError> {
+        let mut starts = vec![false; self.code.len() + 1];
+        let mut jumps = Vec::new();
+
+        let mut ip = 0;
+
+        while ip < self.code.len() {
+            let len = self.instruction_len(ip, heap)?;
+            starts[ip] = true;
+
+            match self.code[ip] {
+                0x0c | 0x0d => jumps.push((ip, self.read_u16(ip + 1) as usize)),
+
+                // Relative to the end of the instruction
+                0x20 => jumps.push((ip, (ip + 3).wrapping_sub(self.read_u16(ip + 1) as usize))),
+
+                0x37 => {
+                    for slot in (ip + 10 .. ip + len).step_by(2) {
+                        jumps.push((ip, self.read_u16(slot) as usize))
+                    }
+                },
+
+                _ => (),
+            }
+
+            ip += len;
+        }
+
+        starts[self.code.len()] = true;
+
+        for (ip, target) in jumps {
+            if !starts.get(target).cloned().unwrap_or(false) {
+                return Err(ValidationError::MisalignedJump { ip, target })
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether some path through the code runs past its last instruction instead of ending in a
+    /// `Return`. Code that doesn't decode is taken to fall off the end.
+    pub fn falls_off_end(&self, heap: &Heap<Object>) -> bool {
+        let mut seen = vec![false; self.code.len()];
+        let mut pending = vec![0];
+
+        while let Some(ip) = pending.pop() {
+            if ip >= self.code.len() {
+                return true
+            }
+
+            if seen[ip] {
+                continue
+            }
+
+            seen[ip] = true;
+
+            let len = match self.instruction_len(ip, heap) {
+                Ok(len) => len,
+                Err(_) => return true,
+            };
+
+            match self.code[ip] {
+                // Return
+                0x00 => (),
+                // Jump
+                0x0c => pending.push(self.read_u16(ip + 1) as usize),
+                // JumpIfFalse
+                0x0d => {
+                    pending.push(self.read_u16(ip + 1) as usize);
+                    pending.push(ip + len)
+                },
+                // Loop, relative to the end of the instruction
+                0x20 => pending.push((ip + 3).wrapping_sub(self.read_u16(ip + 1) as usize)),
+                // JumpTable, which always takes one of its targets
+                0x37 => {
+                    for slot in (ip + 10 .. ip + len).step_by(2) {
+                        pending.push(self.read_u16(slot) as usize)
+                    }
+                },
+                _ => pending.push(ip + len),
+            }
+        }
+
+        false
+    }
+
+    /// Net number of values the instruction at `ip` pushes onto the stack, negative when it pops
+    /// more than it pushes.
+    pub fn stack_effect(&self, ip: usize) -> Option<isize> {
+        let operand = || self.read_byte(ip + 1) as isize;
+
+        let effect = match self.read_byte(ip) {
+            // Return, Print, Pop, CloseUpValue, DefineGlobal, Write, JumpTable
+            0x00 | 0x02 | 0x0e | 0x21 | 0x25 | 0x34 | 0x37 | 0x41 => -1,
+            // Binary operators and Index
+            0x03 ..= 0x06 | 0x09 ..= 0x0b | 0x27 | 0x30 | 0x31 | 0x3d | 0x3e => -1,
+            // Not, Neg, jumps, stores that keep their value on the stack, Nop, Yield
+            0x07 | 0x08 | 0x0c | 0x0d | 0x10 | 0x12 | 0x20 | 0x23 | 0x32 | 0x36 | 0x3a | 0x40 => 0,
+            // Constants, loads and closures
+            0x01 | 0x0f | 0x11 | 0x13 ..= 0x16 | 0x22 | 0x24 | 0x35 | 0x38 | 0x3f | 0x42 => 1,
+            // The callee and arguments are replaced by the result
+            call @ 0x17 ..= 0x1f => -((call - 0x17) as isize),
+            0x26 => 1 - operand(),
+            0x28 => 1 - operand() * 2,
+            0x29 => -3,
+            0x33 | 0x39 => -operand(),
+            // Only the arguments are on the stack beforehand
+            0x3b => 1 - self.read_byte(ip + 2) as isize,
+            // The receiver and arguments are replaced by the result
+            0x3c => -(self.read_byte(ip + 2) as isize),
+            0x43 => -(self.read_byte(ip + 3) as isize),
+            _ => return None,
+        };
+
+        Some(effect)
+    }
+
+    /// Rename this chunk, as shown by the disassembler and in backtraces.
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into()
+    }
 }
 
 pub struct Constants<'c> {
@@ -192,6 +398,7 @@ impl AsRef<[u8]> for Chunk {
 pub enum Op {
     Return,
     Constant(u8),
+    ConstantLong(u16),
     Nil,
     True,
     False,
@@ -201,12 +408,21 @@ pub enum Op {
     GetGlobal,
     DefineGlobal,
     SetGlobal,
+    // The global instructions with a 16-bit constant index
+    GetGlobalLong,
+    DefineGlobalLong,
+    SetGlobalLong,
     GetUpValue,
     SetUpValue,
+    GetUpValueLong,
+    SetUpValueLong,
 
     Equal,
     Less,
     Greater,
+    // Not the same as negating `Less` and `Greater`, which would make comparisons with NaN true
+    GreaterEqual,
+    LessEqual,
 
     Add,
     Sub,
@@ -219,6 +435,7 @@ pub enum Op {
     Neg,
 
     Print,
+    Write,
     Jump,
     JumpIfFalse,
     Loop,
@@ -226,6 +443,7 @@ pub enum Op {
     
     Call(u8),
     Closure,
+    ClosureLong,
     CloseUpValue,
 
     List,
@@ -233,6 +451,22 @@ pub enum Op {
     SetElement,
 
     Index,
+    IndexPath,
+
+    JumpTable,
+
+    PopN(u8),
+
+    Yield,
+
+    // A call of the global named by the constant, without loading it first
+    CallGlobal(u8, u8),
+
+    // A call of the function stored under the constant's name in the dict below the arguments
+    Invoke(u8, u8),
+    InvokeLong(u16, u8),
+
+    Nop,
 }
 
 impl Op {
@@ -277,15 +511,96 @@ impl Op {
             SetElement => buf.push(0x29),
             Index => buf.push(0x30),
             Pow => buf.push(0x31),
+            Nop => buf.push(0x32),
+            IndexPath => buf.push(0x33),
+            Write => buf.push(0x34),
+            GetUpValueLong => buf.push(0x35),
+            SetUpValueLong => buf.push(0x36),
+            JumpTable => buf.push(0x37),
+            ConstantLong(idx) => { buf.push(0x38); buf.extend_from_slice(&idx.to_le_bytes()); }
+            PopN(count) => { buf.push(0x39); buf.push(count); }
+            Yield => buf.push(0x3a),
+            CallGlobal(idx, arity) => { buf.push(0x3b); buf.push(idx); buf.push(arity); }
+            Invoke(idx, arity) => { buf.push(0x3c); buf.push(idx); buf.push(arity); }
+            GreaterEqual => buf.push(0x3d),
+            LessEqual => buf.push(0x3e),
+            GetGlobalLong => buf.push(0x3f),
+            SetGlobalLong => buf.push(0x40),
+            DefineGlobalLong => buf.push(0x41),
+            ClosureLong => buf.push(0x42),
+            InvokeLong(idx, arity) => { buf.push(0x43); buf.extend_from_slice(&idx.to_le_bytes()); buf.push(arity); }
         }
     }
 }
 
+/// The name of the `Op` an opcode byte encodes. Calls of every arity share the name `Call`.
+pub fn op_name(byte: u8) -> Option<&'static str> {
+    let name = match byte {
+        0x00 => "Return",
+        0x01 => "Constant",
+        0x02 => "Print",
+        0x03 => "Add",
+        0x04 => "Sub",
+        0x05 => "Mul",
+        0x06 => "Div",
+        0x07 => "Not",
+        0x08 => "Neg",
+        0x09 => "Equal",
+        0x0a => "Greater",
+        0x0b => "Less",
+        0x0c => "Jump",
+        0x0d => "JumpIfFalse",
+        0x0e => "Pop",
+        0x0f => "GetGlobal",
+        0x10 => "SetGlobal",
+        0x11 => "GetLocal",
+        0x12 => "SetLocal",
+        0x13 => "Immediate",
+        0x14 => "Nil",
+        0x15 => "True",
+        0x16 => "False",
+        0x17 ..= 0x1f => "Call",
+        0x20 => "Loop",
+        0x21 => "CloseUpValue",
+        0x22 => "GetUpValue",
+        0x23 => "SetUpValue",
+        0x24 => "Closure",
+        0x25 => "DefineGlobal",
+        0x26 => "List",
+        0x27 => "Rem",
+        0x28 => "Dict",
+        0x29 => "SetElement",
+        0x30 => "Index",
+        0x31 => "Pow",
+        0x32 => "Nop",
+        0x33 => "IndexPath",
+        0x34 => "Write",
+        0x35 => "GetUpValueLong",
+        0x36 => "SetUpValueLong",
+        0x37 => "JumpTable",
+        0x38 => "ConstantLong",
+        0x39 => "PopN",
+        0x3a => "Yield",
+        0x3b => "CallGlobal",
+        0x3c => "Invoke",
+        0x3d => "GreaterEqual",
+        0x3e => "LessEqual",
+        0x3f => "GetGlobalLong",
+        0x40 => "SetGlobalLong",
+        0x41 => "DefineGlobalLong",
+        0x42 => "ClosureLong",
+        0x43 => "InvokeLong",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
 macro_rules! decode_op {
     ($op:expr, $this:ident) => {
         match $op {
             0x00 => $this.ret(),
-            0x01 => { let idx = $this.read_byte(); $this.constant(idx); }
+            0x01 => $this.constant(),
             0x02 => $this.print(),
             0x03 => $this.add(),
             0x04 => $this.sub(),
@@ -298,7 +613,7 @@ macro_rules! decode_op {
             0x0b => $this.lt(),
             0x0c => $this.jmp(),
             0x0d => $this.jze(),
-            0x0e => { $this.pop(); },
+            0x0e => $this.op_pop(),
             0x0f => $this.get_global(),
             0x10 => $this.set_global(),
             0x11 => $this.get_local(),
@@ -322,9 +637,25 @@ macro_rules! decode_op {
             0x29 => $this.set_element(),
             0x30 => $this.index(),
             0x31 => $this.pow(),
-            _ => {
-                panic!("Unknown op {}", $op);
-            }
+            0x32 => $this.nop(),
+            0x33 => $this.index_path(),
+            0x34 => $this.write(),
+            0x35 => $this.get_upvalue_long(),
+            0x36 => $this.set_upvalue_long(),
+            0x37 => $this.jump_table(),
+            0x38 => $this.constant_long(),
+            0x39 => $this.pop_n(),
+            0x3a => $this.op_yield(),
+            0x3b => $this.call_global(),
+            0x3c => $this.invoke(),
+            0x3d => $this.ge(),
+            0x3e => $this.le(),
+            0x3f => $this.get_global_long(),
+            0x40 => $this.set_global_long(),
+            0x41 => $this.define_global_long(),
+            0x42 => $this.closure_long(),
+            0x43 => $this.invoke_long(),
+            op => $this.unknown_op(op),
         }
     }
 }
\ No newline at end of file
@@ -2,35 +2,90 @@ use super::*;
 use gc::trace::{ Trace, Tracer };
 use colored::Colorize;
 
+/// Write to the captured listing when there is one, and to stderr otherwise.
+macro_rules! out {
+    ($dis:expr, $($arg:tt)*) => {{
+        let text = format!($($arg)*);
+
+        match $dis.out {
+            Some(ref mut out) => out.push_str(&text),
+            None => eprint!("{}", text),
+        }
+    }};
+}
+
 pub struct Disassembler<'c> {
     offset: usize,
     line: usize,
     chunk: &'c Chunk,
-    heap: &'c Heap<Object>,
+    heap: Option<&'c Heap<Object>>,
+    effect: isize,
+    out: Option<String>,
 }
 
 impl<'c> Disassembler<'c> {
     pub fn new(chunk: &'c Chunk, heap: &'c Heap<Object>) -> Self {
+        Disassembler {
+            heap: Some(heap),
+            ..Disassembler::new_no_heap(chunk)
+        }
+    }
+
+    /// Disassemble without access to the heap the chunk's constants live on, showing object
+    /// constants as `<obj>`. The number of upvalue operands following a closure is only known to
+    /// its function, so the listing stops at the first closure.
+    pub fn new_no_heap(chunk: &'c Chunk) -> Self {
         Disassembler {
             offset: 0,
             line: 0,
             chunk,
-            heap,
+            heap: None,
+            effect: 0,
+            out: None,
         }
     }
 
-    pub fn disassemble(mut self) {
+    /// Disassemble the chunk into a string instead of printing it, without colors.
+    pub fn listing(mut self) -> String {
+        self.out = Some(String::new());
+        self.disassemble_into();
+
+        self.out.unwrap_or_default()
+    }
+
+    /// Print the chunk, each instruction followed by its stack effect. Returns the sum of those
+    /// effects, which for straight-line code is the number of values left on the stack.
+    pub fn disassemble(mut self) -> isize {
+        self.disassemble_into();
+
+        self.effect
+    }
+
+    fn disassemble_into(&mut self) {
         let bytes = self.chunk.as_ref();
 
-        println!();
+        self.newline();
         let name = format!("== {} ==", self.chunk.name());
-        eprint!("{}", name.cyan());
+
+        if self.out.is_some() {
+            out!(self, "{}", name);
+        } else {
+            out!(self, "{}", name.cyan());
+        }
 
         while self.offset < bytes.len() {
             self.disassemble_instruction();
         }
 
-        println!();
+        self.newline();
+        out!(self, "net stack effect: {:+}\n", self.effect);
+    }
+
+    fn newline(&mut self) {
+        match self.out {
+            Some(ref mut out) => out.push('\n'),
+            None => println!(),
+        }
     }
 
     fn disassemble_instruction(&mut self) {
@@ -39,194 +94,295 @@ impl<'c> Disassembler<'c> {
         } else {
             self.line = line;
         }
+        let effect = self.chunk.stack_effect(self.offset).unwrap_or(0);
+
         let inst = self.read_byte();
-        println!();
+        self.newline();
         let off = format!("{:04} | ", self.offset);
 
-        eprint!("{}", off.blue());
+        if self.out.is_some() {
+            out!(self, "{}", off);
+        } else {
+            out!(self, "{}", off.blue());
+        }
         decode_op!(inst, self);
+
+        out!(self, " ({:+})", effect);
+        self.effect += effect;
     }
 
-    fn constant(&mut self, idx: u8) {
+    fn constant(&mut self) {
+        let idx = self.read_byte();
+        let val = self.chunk.get_constant(idx as u16);
+        out!(self, "CONSTANT\t{}\t{:?}", idx, val);
+    }
+
+    fn constant_long(&mut self) {
+        let idx = self.read_u16();
         let val = self.chunk.get_constant(idx);
-        eprint!("CONSTANT\t{}\t{:?}", idx, val);
-    }
-
-    fn ret(&self) { eprint!("RETURN"); }
-    fn print(&self) { eprint!("PRINT"); }
-    fn add(&self) { eprint!("ADD"); }
-    fn sub(&self) { eprint!("SUB"); }
-    fn mul(&self) { eprint!("MUL"); }
-    fn rem(&self) { eprint!("REM"); }
-    fn pow(&self) { eprint!("POW"); }
-    fn div(&self) { eprint!("DIV"); }
-    fn neg(&self) { eprint!("NEG"); }
-    fn not(&self) { eprint!("NOT"); }
-    fn eq(&self) { eprint!("EQ"); }
-    fn gt(&self) { eprint!("GT"); }
-    fn lt(&self) { eprint!("LT"); }
-    fn pop(&self) { eprint!("POP"); }
+        out!(self, "CONSTANT_LONG\t{}\t{:?}", idx, val);
+    }
+
+    fn ret(&mut self) { out!(self, "RETURN"); }
+    fn print(&mut self) { out!(self, "PRINT"); }
+    fn write(&mut self) { out!(self, "WRITE"); }
+    fn add(&mut self) { out!(self, "ADD"); }
+    fn sub(&mut self) { out!(self, "SUB"); }
+    fn mul(&mut self) { out!(self, "MUL"); }
+    fn rem(&mut self) { out!(self, "REM"); }
+    fn pow(&mut self) { out!(self, "POW"); }
+    fn div(&mut self) { out!(self, "DIV"); }
+    fn neg(&mut self) { out!(self, "NEG"); }
+    fn not(&mut self) { out!(self, "NOT"); }
+    fn eq(&mut self) { out!(self, "EQ"); }
+    fn gt(&mut self) { out!(self, "GT"); }
+    fn lt(&mut self) { out!(self, "LT"); }
+    fn ge(&mut self) { out!(self, "GE"); }
+    fn le(&mut self) { out!(self, "LE"); }
+    fn op_pop(&mut self) { out!(self, "POP"); }
+    fn pop_n(&mut self) { let count = self.read_byte(); out!(self, "POP_N\t{}", count); }
+    fn nop(&mut self) { out!(self, "NOP"); }
+    fn op_yield(&mut self) { out!(self, "YIELD"); }
 
     fn list(&mut self) {
-        eprint!("LIST");
+        out!(self, "LIST");
         self.read_byte();
     }
 
     fn index(&mut self) {}
 
+    fn jump_table(&mut self) {
+        self.offset += 8;
+        let count = self.read_byte();
+
+        out!(self, "JUMP_TABLE\t{}", count);
+
+        for _ in 0 ..= count {
+            let target = self.read_u16();
+            out!(self, " {}", target);
+        }
+    }
+
+    fn index_path(&mut self) {
+        out!(self, "INDEX_PATH");
+        self.read_byte();
+    }
+
     fn dict(&mut self) {
-        eprint!("DICT");
+        out!(self, "DICT");
         self.read_byte();
     }
 
     fn set_element(&mut self) {
-        eprint!("SET_ELEMENT")
+        out!(self, "SET_ELEMENT")
     }
 
 
     fn jmp(&mut self) {
         let offset = self.offset - 1;
         let ip = self.read_u16();
-        eprint!("JUMP\t{} -> {}", offset, ip);
+        out!(self, "JUMP\t{} -> {}", offset, ip);
     }
 
     fn jze(&mut self) {
         let offset = self.offset - 1;
         let ip = self.read_u16();
-        eprint!("JUMP_IF_FALSE\t{} -> {}", offset, ip);
+        out!(self, "JUMP_IF_FALSE\t{} -> {}", offset, ip);
     }
 
     fn op_loop(&mut self) {
         let sub = self.read_u16() as usize;
-        eprint!("LOOP\t{} -> {}", self.offset, self.offset - sub);
+        out!(self, "LOOP\t{} -> {}", self.offset, self.offset - sub);
     }
 
     fn get_global(&mut self) {
         let val = self.read_constant();
-        eprint!("GET_GLOBAL\t{}", val.with_heap(self.heap));
+        out!(self, "GET_GLOBAL\t{}", self.render(val));
     }
 
     fn set_global(&mut self) {
         let val = self.read_constant();
-        eprint!("SET_GLOBAL\t{}", val.with_heap(self.heap));
+        out!(self, "SET_GLOBAL\t{}", self.render(val));
     }
 
     fn define_global(&mut self) {
         let val = self.read_constant();
-        eprint!("DEFINE_GLOBAL\t{}", val.with_heap(self.heap));
+        out!(self, "DEFINE_GLOBAL\t{}", self.render(val));
+    }
+
+    fn get_global_long(&mut self) {
+        let val = self.read_constant_long();
+        out!(self, "GET_GLOBAL_LONG\t{}", self.render(val));
+    }
+
+    fn set_global_long(&mut self) {
+        let val = self.read_constant_long();
+        out!(self, "SET_GLOBAL_LONG\t{}", self.render(val));
+    }
+
+    fn define_global_long(&mut self) {
+        let val = self.read_constant_long();
+        out!(self, "DEFINE_GLOBAL_LONG\t{}", self.render(val));
     }
 
     fn get_local(&mut self) {
         let val = self.read_byte();
-        eprint!("GET_LOCAL\t{}", val);
+        out!(self, "GET_LOCAL\t{}", val);
     }
 
     fn set_local(&mut self) {
         let val = self.read_byte();
-        eprint!("SET_LOCAL\t{}", val);
+        out!(self, "SET_LOCAL\t{}", val);
     }
 
     fn immediate(&mut self) {
+        // Decoded exactly as the VM does, so the listing shows the value that will be pushed
+        let raw = self.chunk.read_u64(self.offset);
         self.offset += 8;
-        let b1 = self.chunk.get(self.offset - 8) as u64;
-        let b2 = self.chunk.get(self.offset - 7) as u64;
-        let b3 = self.chunk.get(self.offset - 6) as u64;
-        let b4 = self.chunk.get(self.offset - 5) as u64;
-        let b5 = self.chunk.get(self.offset - 4) as u64;
-        let b6 = self.chunk.get(self.offset - 3) as u64;
-        let b7 = self.chunk.get(self.offset - 2) as u64;
-        let b8 = self.chunk.get(self.offset - 1) as u64;
-        let raw = b1   +
-            (b2 << 8)  +
-            (b3 << 16) +
-            (b4 << 24) +
-            (b5 << 32) +
-            (b6 << 40) +
-            (b7 << 48) +
-            (b8 << 56);
+
         let val = unsafe { Value::from_raw(raw) };
-        eprint!("FLOAT\t{}", val.with_heap(self.heap));
+        out!(self, "FLOAT\t{}", self.render(val));
+    }
+
+    fn imm_nil(&mut self) {
+        out!(self, "NIL");
     }
 
-    fn imm_nil(&self) {
-        eprint!("NIL");
+    fn imm_true(&mut self) {
+        out!(self, "TRUE");
     }
 
-    fn imm_true(&self) {
-        eprint!("TRUE");
+    fn imm_false(&mut self) {
+        out!(self, "FALSE");
     }
 
-    fn imm_false(&self) {
-        eprint!("FALSE");
+    fn call(&mut self, arity: u8) {
+        out!(self, "CALL_{}", arity);
     }
 
-    fn call(&self, arity: u8) {
-        eprint!("CALL_{}", arity);
+    fn call_global(&mut self) {
+        let idx = self.read_byte();
+        let arity = self.read_byte();
+        let val = self.chunk.get_constant(idx as u16).expect("invalid constant segment index");
+        out!(self, "CALL_GLOBAL_{}\t{}", arity, self.render(*val));
     }
 
-    fn invoke(&mut self, arity: u8) {
+    fn invoke(&mut self) {
         let idx = self.read_byte();
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprint!("INVOKE_{} {}", arity, val.with_heap(&self.heap));
+        let arity = self.read_byte();
+        let val = self.chunk.get_constant(idx as u16).expect("invalid constant segment index");
+        out!(self, "INVOKE_{} {}", arity, self.render(*val));
     }
 
-    fn close_upvalue(&self) {
-        eprint!("CLOSE_UPVALUE");
+    fn invoke_long(&mut self) {
+        let val = self.read_constant_long();
+        let arity = self.read_byte();
+        out!(self, "INVOKE_LONG_{} {}", arity, self.render(val));
+    }
+
+    fn unknown_op(&mut self, op: u8) {
+        out!(self, "UNKNOWN\t{:#04x}", op);
+    }
+
+    fn close_upvalue(&mut self) {
+        out!(self, "CLOSE_UPVALUE");
     }
 
     fn get_upvalue(&mut self) {
         let index = self.read_byte();
-        eprint!("GET_UPVALUE\t{}", index);
+        out!(self, "GET_UPVALUE\t{}", index);
     }
 
     fn set_upvalue(&mut self) {
         let index = self.read_byte();
-        eprint!("SET_UPVALE\t{}", index);
+        out!(self, "SET_UPVALE\t{}", index);
+    }
+
+    fn get_upvalue_long(&mut self) {
+        let index = self.read_u16();
+        out!(self, "GET_UPVALUE_LONG\t{}", index);
+    }
+
+    fn set_upvalue_long(&mut self) {
+        let index = self.read_u16();
+        out!(self, "SET_UPVALUE_LONG\t{}", index);
     }
 
     fn closure(&mut self) {
         let val = self.read_constant();
-        let count = val
-            .as_object()
-            .and_then(|o| self.heap.get(o))
-            .and_then(|o| o.as_function())
-            .expect("closure argument to be a function")
-            .upvalue_count();
-
-        print!("CLOSURE\t{} ", val.with_heap(self.heap));
-        println!();
-
-        if let Variant::Obj(cl) = val.with_heap(self.heap).item.decode() {
-            unsafe {
-                let closure = cl.get_unchecked().as_function().unwrap();
-
-                let dis = Disassembler::new(closure.chunk(), &self.heap);
-                dis.disassemble()
+
+        out!(self, "CLOSURE\t{} ", self.render(val));
+        self.closure_upvalues(val)
+    }
+
+    fn closure_long(&mut self) {
+        let val = self.read_constant_long();
+
+        out!(self, "CLOSURE_LONG\t{} ", self.render(val));
+        self.closure_upvalues(val)
+    }
+
+    // The function's own listing, followed by skipping its upvalue operands
+    fn closure_upvalues(&mut self, val: Value) {
+        let function = match (self.heap, val.as_object()) {
+            (Some(heap), Some(handle)) => heap.get(handle).and_then(|o| o.as_function()).map(|f| (heap, f)),
+            _ => None,
+        };
+
+        let (heap, function) = match function {
+            Some(function) => function,
+            None => {
+                out!(self, "(upvalue operands unknown, stopping)");
+                self.offset = self.chunk.len();
+
+                return
             }
+        };
+
+        self.newline();
+
+        let dis = Disassembler::new(function.chunk(), heap);
+
+        if self.out.is_some() {
+            let listing = dis.listing();
+            out!(self, "{}", listing);
+        } else {
+            dis.disassemble();
         }
 
-        for _ in 0..count {
+        for _ in 0 .. function.upvalue_count() {
             let _is_local = self.read_byte() > 0;
-            let _index = self.read_byte();
+            let _index = self.read_u16();
         }
     }
 
     fn class(&mut self, idx: u8) {
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
+        let val = self.chunk.get_constant(idx as u16).expect("invalid constant segment index");
         let methods = self.read_byte();
-        eprint!("CLASS\t{}\t{}\t({} method(s))", idx, val.with_heap(&self.heap), methods);
+        out!(self, "CLASS\t{}\t{}\t({} method(s))", idx, self.render(*val), methods);
     }
 
     fn get_property(&mut self) {
         let idx = self.read_byte();
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprint!("GET_PROPERTY\t{}\t{}", idx, val.with_heap(&self.heap));
+        let val = self.chunk.get_constant(idx as u16).expect("invalid constant segment index");
+        out!(self, "GET_PROPERTY\t{}\t{}", idx, self.render(*val));
     }
 
     fn set_property(&mut self) {
         let idx = self.read_byte();
-        let val = self.chunk.get_constant(idx).expect("invalid constant segment index");
-        eprint!("SET_PROPERTY\t{}\t{}", idx, val.with_heap(&self.heap));
+        let val = self.chunk.get_constant(idx as u16).expect("invalid constant segment index");
+        out!(self, "SET_PROPERTY\t{}\t{}", idx, self.render(*val));
+    }
+
+    fn render(&self, value: Value) -> String {
+        match (self.heap, value.decode()) {
+            (Some(heap), _) => value.with_heap(heap).to_string(),
+            (None, Variant::Obj(_)) => "<obj>".to_string(),
+            (None, Variant::Float(n)) => n.to_string(),
+            (None, Variant::True) => "true".to_string(),
+            (None, Variant::False) => "false".to_string(),
+            (None, Variant::Nil) => "nil".to_string(),
+        }
     }
 
     fn read_byte(&mut self) -> u8 {
@@ -243,6 +399,11 @@ impl<'c> Disassembler<'c> {
 
     fn read_constant(&mut self) -> Value {
         let idx = self.read_byte();
+        *self.chunk.get_constant(idx as u16).expect("invalid constant segment index")
+    }
+
+    fn read_constant_long(&mut self) -> Value {
+        let idx = self.read_u16();
         *self.chunk.get_constant(idx).expect("invalid constant segment index")
     }
 }
@@ -0,0 +1,100 @@
+use std::fmt;
+
+use super::super::compiler::CompileError;
+use super::chunk::Span;
+
+/// A frame of the call stack at the point a runtime error was raised.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    pub name: String,
+    pub line: usize,
+    pub ip: usize,
+    /// The source span of the instruction being run, when the front-end attached spans to the IR.
+    pub span: Option<Span>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    /// A generic error raised by an instruction or a native function.
+    Message(String),
+    /// Natives and script functions called back into each other more than the given number of
+    /// times.
+    NativeRecursion(usize),
+    /// The stack grew past the configured number of slots.
+    StackOverflow(usize),
+    /// Execution ran past its deadline.
+    Timeout,
+    /// The program passed to `exec` failed to compile.
+    Compile(CompileError),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ErrorKind::*;
+
+        match self {
+            Message(ref message) => write!(f, "{}", message),
+            NativeRecursion(limit) => write!(f, "native call recursion exceeded the limit of {}", limit),
+            StackOverflow(limit) => write!(f, "stack overflow, exceeded {} slots", limit),
+            Timeout => write!(f, "execution timed out"),
+            Compile(ref error) => write!(f, "{}", error),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub backtrace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: ErrorKind, backtrace: Vec<TraceFrame>) -> Self {
+        RuntimeError {
+            kind,
+            backtrace,
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[error]: {}.", self.kind)?;
+
+        for frame in self.backtrace.iter() {
+            write!(f, "\n         at [line {}] in {}", frame.line, frame.name)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// A structural problem found by `Chunk::validate`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationError {
+    /// The byte at this offset isn't an opcode.
+    UnknownOp(usize),
+    /// The operands of the instruction at this offset run past the end of the chunk.
+    Truncated(usize),
+    /// The closure at this offset doesn't refer to a function constant.
+    BadClosure(usize),
+    /// The jump at `ip` lands at `target`, which isn't the start of an instruction.
+    MisalignedJump { ip: usize, target: usize },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ValidationError::*;
+
+        match self {
+            UnknownOp(ip) => write!(f, "unknown opcode at {}", ip),
+            Truncated(ip) => write!(f, "truncated instruction at {}", ip),
+            BadClosure(ip) => write!(f, "closure at {} doesn't refer to a function", ip),
+            MisalignedJump { ip, target } => write!(f, "jump at {} lands mid-instruction at {}", ip, target),
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
@@ -3,6 +3,8 @@ pub mod tag;
 
 use std::{
     cmp::{PartialEq, Eq},
+    cell::RefCell,
+    mem::MaybeUninit,
     rc::Rc,
     hash::{Hash, Hasher},
 };
@@ -11,6 +13,28 @@ use trace::*;
 
 type Generation = usize;
 
+/// Cleanup run on an object as it's swept, see `Heap::set_finalizer`.
+pub type Finalizer<T> = Rc<dyn Fn(&mut T)>;
+
+// Renderings of objects by handle, whose generation tells apart a later object at the same
+// address. Changing or freeing an object drops its rendering along with those that include it
+#[derive(Clone)]
+struct DisplayCache<T> {
+    entries: HashMap<Handle<T>, Rc<str>>,
+    // The cached objects whose renderings each object shows up in, itself included
+    dependents: HashMap<Handle<T>, HashSet<Handle<T>>>,
+}
+
+impl<T> DisplayCache<T> {
+    fn invalidate(&mut self, handle: &Handle<T>) {
+        if let Some(dependents) = self.dependents.remove(handle) {
+            for dependent in dependents.iter() {
+                self.entries.remove(dependent);
+            }
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Heap<T> {
     last_sweep: usize,
@@ -18,6 +42,9 @@ pub struct Heap<T> {
     obj_counter: Generation,
     objects: HashSet<Handle<T>>,
     rooted: HashMap<Handle<T>, Rc<()>>,
+    finalizers: HashMap<Handle<T>, Finalizer<T>>,
+    bytes: usize,
+    display_cache: Option<RefCell<DisplayCache<T>>>,
 }
 
 impl<T> Default for Heap<T> {
@@ -28,6 +55,9 @@ impl<T> Default for Heap<T> {
             obj_counter: 0,
             objects: HashSet::default(),
             rooted: HashMap::default(),
+            finalizers: HashMap::default(),
+            bytes: 0,
+            display_cache: None,
         }
     }
 }
@@ -38,6 +68,23 @@ impl<T: Trace<T>> Heap<T> {
         Self::default()
     }
 
+    /// Create an empty heap with room for `capacity` objects before its bookkeeping has to grow.
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            object_sweeps: HashMap::with_capacity(capacity),
+            objects: HashSet::with_capacity(capacity),
+            rooted: HashMap::with_capacity(capacity),
+            finalizers: HashMap::default(),
+            display_cache: None,
+            ..Self::default()
+        }
+    }
+
+    /// Number of objects the heap can hold without reallocating its bookkeeping.
+    pub fn capacity(&self) -> usize {
+        self.objects.capacity()
+    }
+
     fn new_generation(&mut self) -> Generation {
         self.obj_counter += 1;
         self.obj_counter
@@ -48,10 +95,16 @@ impl<T: Trace<T>> Heap<T> {
     pub fn insert_temp(&mut self, object: T) -> Handle<T> {
         let ptr = Box::into_raw(Box::new(object));
 
+        self.track(ptr)
+    }
+
+    fn track(&mut self, ptr: *mut T) -> Handle<T> {
         let gen = self.new_generation();
         let handle = Handle { gen, ptr };
         self.objects.insert(handle);
 
+        self.bytes += unsafe { (&*ptr).size() };
+
         handle
     }
 
@@ -69,6 +122,22 @@ impl<T: Trace<T>> Heap<T> {
         }
     }
 
+    /// Like `insert`, but the object is written by `f` straight into its heap allocation, rather
+    /// than being built elsewhere and moved there.
+    ///
+    /// # Safety
+    ///
+    /// If `f` returns without fully initialising the slot, calling this function results in
+    /// undefined behaviour.
+    pub unsafe fn insert_with(&mut self, f: impl FnOnce(&mut MaybeUninit<T>)) -> Rooted<T> {
+        let mut slot = Box::new(MaybeUninit::<T>::uninit());
+        f(&mut slot);
+
+        let handle = self.track(Box::into_raw(slot) as *mut T);
+
+        self.make_rooted(handle)
+    }
+
     /// Upgrade a handle (that will be cleared by the garbage collector) into a rooted handle (that
     /// will not).
     pub fn make_rooted(&mut self, handle: impl AsRef<Handle<T>>) -> Rooted<T> {
@@ -84,11 +153,28 @@ impl<T: Trace<T>> Heap<T> {
         }
     }
 
+    /// Create a weak handle to an object, which does not keep it alive but can be checked for
+    /// liveness later on.
+    pub fn downgrade(&self, handle: impl AsRef<Handle<T>>) -> WeakHandle<T> {
+        let handle = handle.as_ref();
+        debug_assert!(self.contains(handle));
+
+        WeakHandle {
+            handle: *handle,
+        }
+    }
+
     /// Count the number of heap-allocated objects in this heap
     pub fn len(&self) -> usize {
         self.objects.len()
     }
 
+    /// Estimate the bytes taken up by the objects on this heap, payloads included. Objects are
+    /// measured when inserted and again on every collection, so growth in between isn't counted.
+    pub fn estimated_bytes(&self) -> usize {
+        self.bytes
+    }
+
     /// Return true if the heap contains the specified handle
     pub fn contains(&self, handle: impl AsRef<Handle<T>>) -> bool {
         let handle = handle.as_ref();
@@ -119,6 +205,8 @@ impl<T: Trace<T>> Heap<T> {
     /// Get a mutable reference to a heap object
     pub fn get_mut(&mut self, handle: impl AsRef<Handle<T>>) -> Option<&mut T> {
         let handle = handle.as_ref();
+        self.touch(handle);
+
         if self.contains(handle) {
             Some(unsafe { &mut *handle.ptr })
         } else {
@@ -134,15 +222,117 @@ impl<T: Trace<T>> Heap<T> {
     pub fn get_mut_unchecked(&mut self, handle: impl AsRef<Handle<T>>) -> &mut T {
         let handle = handle.as_ref();
         debug_assert!(self.contains(handle));
+        self.touch(handle);
+
         unsafe { &mut *handle.ptr }
     }
 
+    // The object may be about to change, so cached renderings showing it are dropped
+    fn touch(&self, handle: &Handle<T>) {
+        if let Some(ref cache) = self.display_cache {
+            cache.borrow_mut().invalidate(handle)
+        }
+    }
+
+    /// Remember how objects were rendered by `cached_display`, until they or objects they contain
+    /// are next changed through the heap. Worth it when the same large collections are displayed
+    /// over and over. Changes made through `Handle::get_mut_unchecked` go unnoticed.
+    pub fn set_display_cache(&mut self, enabled: bool) {
+        self.display_cache = if enabled {
+            Some(RefCell::new(DisplayCache { entries: HashMap::default(), dependents: HashMap::default() }))
+        } else {
+            None
+        }
+    }
+
+    /// The rendering of the object by `render`, from the display cache when it's enabled and the
+    /// object was rendered before.
+    pub fn cached_display(&self, handle: impl AsRef<Handle<T>>, render: impl FnOnce() -> String) -> Rc<str> {
+        let handle = *handle.as_ref();
+
+        let cache = match self.display_cache {
+            Some(ref cache) => cache,
+            None => return render().into(),
+        };
+
+        if let Some(text) = cache.borrow().entries.get(&handle) {
+            return text.clone()
+        }
+
+        // Rendering may display other objects, so the cache can't stay borrowed meanwhile
+        let text: Rc<str> = render().into();
+
+        let mut cache = cache.borrow_mut();
+
+        for shown in self.reachable(handle) {
+            cache.dependents.entry(shown).or_default().insert(handle);
+        }
+
+        cache.entries.insert(handle, text.clone());
+
+        text
+    }
+
+    // The object and every object it references, directly or not
+    fn reachable(&self, handle: Handle<T>) -> HashSet<Handle<T>> {
+        let mut found = HashSet::new();
+        let mut pending = vec![handle];
+
+        while let Some(handle) = pending.pop() {
+            if found.insert(handle) {
+                pending.extend(self.references(handle))
+            }
+        }
+
+        found
+    }
+
+    /// Run `finalizer` on the object right before it's freed, whether by a collection or by dropping
+    /// the heap, e.g. to close a resource it wraps at a known point. Replaces any finalizer the
+    /// object had before, and does nothing for objects not on this heap.
+    pub fn set_finalizer(&mut self, handle: impl AsRef<Handle<T>>, finalizer: impl Fn(&mut T) + 'static) {
+        let handle = *handle.as_ref();
+
+        if self.contains(handle) {
+            self.finalizers.insert(handle, Rc::new(finalizer));
+        }
+    }
+
+    /// Iterate over the handles of all objects currently on this heap.
+    pub fn handles(&self) -> impl Iterator<Item=Handle<T>> + '_ {
+        self.objects.iter().cloned()
+    }
+
+    /// Collect the handles directly referenced by a heap object, as discovered by its `Trace`
+    /// implementation.
+    pub fn references(&self, handle: impl AsRef<Handle<T>>) -> Vec<Handle<T>> {
+        let handle = handle.as_ref();
+        let mut edges = Vec::new();
+
+        if let Some(object) = self.get(handle) {
+            let mut object_sweeps = HashMap::new();
+            let mut tracer = Tracer {
+                new_sweep: self.last_sweep + 1,
+                object_sweeps: &mut object_sweeps,
+                objects: &self.objects,
+                edges: Some(&mut edges),
+                pending: Vec::new(),
+            };
+
+            object.trace(&mut tracer);
+        }
+
+        edges
+    }
+
     pub fn clean_excluding(&mut self, excluding: impl IntoIterator<Item=Handle<T>>) {
         let new_sweep = self.last_sweep + 1;
         let mut tracer = Tracer {
             new_sweep,
             object_sweeps: &mut self.object_sweeps,
             objects: &self.objects,
+            edges: None,
+            pending: Vec::new(),
         };
 
         // Mark
@@ -150,7 +340,6 @@ impl<T: Trace<T>> Heap<T> {
             .retain(|handle, rc| {
                 if Rc::strong_count(rc) > 1 {
                     tracer.mark(*handle);
-                    unsafe { (&*handle.ptr).trace(&mut tracer); }
                     true
                 } else {
                     false
@@ -160,13 +349,16 @@ impl<T: Trace<T>> Heap<T> {
         excluding
             .into_iter()
             .filter(|handle| objects.contains(&handle))
-            .for_each(|handle| {
-                tracer.mark(handle);
-                unsafe { (&*handle.ptr).trace(&mut tracer); }
-            });
+            .for_each(|handle| tracer.mark(handle));
+
+        tracer.mark_pending();
 
-        // Sweep
+        // Sweep, measuring the survivors afresh
         let object_sweeps = &mut self.object_sweeps;
+        let finalizers = &mut self.finalizers;
+        let mut display_cache = self.display_cache.as_ref().map(|cache| cache.borrow_mut());
+        let mut bytes = 0;
+
         self.objects
             .retain(|handle| {
                 if object_sweeps
@@ -174,14 +366,25 @@ impl<T: Trace<T>> Heap<T> {
                     .map(|sweep| *sweep == new_sweep)
                     .unwrap_or(false)
                 {
+                    bytes += unsafe { (&*handle.ptr).size() };
                     true
                 } else {
                     object_sweeps.remove(handle);
+
+                    if let Some(ref mut cache) = display_cache {
+                        cache.invalidate(handle);
+                    }
+
+                    if let Some(finalizer) = finalizers.remove(handle) {
+                        finalizer(unsafe { &mut *handle.ptr })
+                    }
+
                     drop(unsafe { Box::from_raw(handle.ptr) });
                     false
                 }
             });
 
+        self.bytes = bytes;
         self.last_sweep = new_sweep;
     }
 
@@ -193,6 +396,10 @@ impl<T: Trace<T>> Heap<T> {
 
 impl<T> Drop for Heap<T> {
     fn drop(&mut self) {
+        for (handle, finalizer) in self.finalizers.drain() {
+            finalizer(unsafe { &mut *handle.ptr })
+        }
+
         for handle in &self.objects {
             drop(unsafe { Box::from_raw(handle.ptr) });
         }
@@ -248,6 +455,31 @@ impl<T> From<Rooted<T>> for Handle<T> {
     }
 }
 
+/// A handle that doesn't keep its object alive. Since handles carry their generation, an upgrade
+/// never resolves to a newer object reusing the same allocation.
+#[derive(Debug)]
+pub struct WeakHandle<T> {
+    handle: Handle<T>,
+}
+
+impl<T: Trace<T>> WeakHandle<T> {
+    /// Get a handle to the object if it hasn't been collected yet.
+    pub fn upgrade(&self, heap: &Heap<T>) -> Option<Handle<T>> {
+        if heap.contains(self.handle) {
+            Some(self.handle)
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Copy for WeakHandle<T> {}
+impl<T> Clone for WeakHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
 #[derive(Debug)]
 pub struct Rooted<T> {
     rc: Rc<()>,
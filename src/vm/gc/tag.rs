@@ -1,5 +1,13 @@
 use super::Handle;
 
+use std::hash::{ Hash, Hasher };
+
+// Values are NaN-boxed into the pointer field of a handle: floats, tags and addresses all share
+// its 64 bits, with addresses kept in the lower 50 bits below the tag. That needs pointers to be
+// 64 bits wide, and user-space addresses to stay under 2^50, as they do on x86-64 and aarch64.
+#[cfg(not(target_pointer_width = "64"))]
+compile_error!("zub's NaN-boxed values only support 64-bit targets");
+
 #[derive(Debug)]
 pub struct TaggedHandle<T> {
     handle: Handle<T>,
@@ -15,6 +23,9 @@ pub enum Tag<T> {
 const QNAN: u64 = 0x7ffc000000000000;
 const SIGN: u64 = 1 << 63;
 
+/// The bits of a boxed value left for an object's address.
+pub const PAYLOAD: u64 = !(QNAN | SIGN);
+
 impl<T> TaggedHandle<T> {
     pub unsafe fn from_raw(raw: u64) -> Self {
         TaggedHandle {
@@ -30,6 +41,11 @@ impl<T> TaggedHandle<T> {
     }
 
     pub fn from_handle(handle: Handle<T>) -> Self {
+        debug_assert!(
+            handle.ptr as u64 & !PAYLOAD == 0,
+            "object address {:p} doesn't fit in a boxed value", handle.ptr
+        );
+
         let u = (handle.ptr as u64) | QNAN | SIGN;
         TaggedHandle{
             handle: Handle {
@@ -40,6 +56,10 @@ impl<T> TaggedHandle<T> {
     }
 
     pub fn from_float(float: f64) -> Self {
+        // NaNs may carry any payload, including ones matching the tag or handle patterns, so
+        // they're all stored as the one canonical NaN
+        let float = if float.is_nan() { f64::NAN } else { float };
+
         TaggedHandle {
             handle: Handle {
                 gen: 0,
@@ -63,7 +83,7 @@ impl<T> TaggedHandle<T> {
             return Tag::Float(unsafe { ::std::mem::transmute(u) });
         }
         if (u & (QNAN | SIGN)) == (QNAN | SIGN) {
-            let ptr = u & (!(QNAN | SIGN)); // only keep lower 51 bits
+            let ptr = u & PAYLOAD; // only keep lower 50 bits
             return Tag::Handle(Handle {
                 gen: self.handle.gen,
                 ptr: ptr as *mut T,
@@ -88,6 +108,12 @@ impl<T> PartialEq<Self> for TaggedHandle<T> {
 }
 impl<T> Eq for TaggedHandle<T> {}
 
+impl<T> Hash for TaggedHandle<T> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.handle.hash(state)
+    }
+}
+
 impl<T> From<Handle<T>> for TaggedHandle<T> {
     fn from(handle: Handle<T>) -> Self {
         Self::from_handle(handle)
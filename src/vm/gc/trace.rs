@@ -3,21 +3,47 @@ use super::*;
 
 pub trait Trace<T: Trace<T>> {
     fn trace(&self, tracer: &mut Tracer<T>);
+
+    /// Estimated number of bytes taken up by the object, including what it owns outside of its
+    /// own allocation. The collector runs based on the total of these.
+    fn size(&self) -> usize {
+        std::mem::size_of_val(self)
+    }
 }
 
 pub struct Tracer<'a, T: Trace<T>> {
     pub(crate) new_sweep: usize,
     pub(crate) object_sweeps: &'a mut HashMap<Handle<T>, usize>,
     pub(crate) objects: &'a HashSet<Handle<T>>,
+    pub(crate) edges: Option<&'a mut Vec<Handle<T>>>,
+    // Marked objects whose references are yet to be traced, kept here instead of recursing so
+    // deeply nested objects can't overflow the native stack
+    pub(crate) pending: Vec<Handle<T>>,
 }
 
 impl<'a, T: Trace<T>> Tracer<'a, T> {
     pub(crate) fn mark(&mut self, handle: Handle<T>) {
+        // When collecting edges, only record direct references instead of marking
+        if let Some(ref mut edges) = self.edges {
+            if self.objects.contains(&handle) {
+                edges.push(handle);
+            }
+
+            return
+        }
+
         let sweep = self.object_sweeps
             .entry(handle)
             .or_insert(self.new_sweep - 1);
         if *sweep != self.new_sweep && self.objects.contains(&handle) {
             *sweep = self.new_sweep;
+            self.pending.push(handle);
+        }
+    }
+
+    /// Trace everything reachable from the objects marked so far.
+    pub(crate) fn mark_pending(&mut self) {
+        while let Some(handle) = self.pending.pop() {
             unsafe { (&*handle.ptr).trace(self); }
         }
     }
@@ -4,6 +4,8 @@ pub mod chunk;
 pub mod vm;
 pub mod gc;
 pub mod disassembler;
+pub mod error;
+pub mod natives;
 
 use super::compiler::*;
 use super::ir::*;
@@ -13,4 +15,5 @@ pub use self::value::*;
 pub use self::chunk::*;
 pub use self::vm::*;
 pub use self::gc::*;
-pub use self::disassembler::*;
\ No newline at end of file
+pub use self::disassembler::*;
+pub use self::error::*;
\ No newline at end of file
@@ -0,0 +1,401 @@
+use super::*;
+
+use im_rc::hashmap::HashMap;
+
+use std::cmp::Ordering;
+
+impl VM {
+    /// Register the dict natives: `dict_len`, `dict_is_empty`, `dict_clear`, `dict_merge` and
+    /// `get_or`.
+    pub fn add_std_dict(&mut self) {
+        self.add_native("dict_len", dict_len, 1);
+        self.add_native("dict_is_empty", dict_is_empty, 1);
+        self.add_native("dict_clear", dict_clear, 1);
+        self.add_native("dict_merge", dict_merge, 2);
+        self.add_native("get_or", get_or, 3);
+    }
+
+    /// Register the string natives: `starts_with`, `ends_with`, `contains` and `index_of`. The
+    /// latter two are shared with `add_std_list`, and search lists too.
+    pub fn add_std_string(&mut self) {
+        self.add_native("starts_with", starts_with, 2);
+        self.add_native("ends_with", ends_with, 2);
+        self.add_native("contains", contains, 2);
+        self.add_native("index_of", index_of, 2);
+    }
+
+    /// Register the string builder natives: `sb_new`, `sb_append` and `sb_build`. Appending to a
+    /// builder grows it in place, where `+` allocates a new string for every step.
+    pub fn add_std_string_builder(&mut self) {
+        self.add_native("sb_new", sb_new, 0);
+        self.add_native("sb_append", sb_append, 2);
+        self.add_native("sb_build", sb_build, 1);
+    }
+
+    /// Register the list natives: `concat_lists`, `extend`, `sort`, `sort_by`, `reverse`,
+    /// `contains`, `index_of` and `total_cmp`. `contains` and `index_of` are shared with
+    /// `add_std_string`, and search strings too.
+    pub fn add_std_list(&mut self) {
+        self.add_native("concat_lists", concat_lists, 2);
+        self.add_native("extend", extend, 2);
+        self.add_native("sort", sort, 1);
+        self.add_native("sort_by", sort_by, 2);
+        self.add_native("reverse", reverse, 1);
+        self.add_native("contains", contains, 2);
+        self.add_native("index_of", index_of, 2);
+        self.add_native("total_cmp", total_cmp, 2);
+    }
+}
+
+// Names the native and the position of the offending argument, counting from 1
+fn arg_error(context: &CallContext, idx: usize, expected: &str) -> RuntimeError {
+    context.error(&format!(
+        "native `{}` expects {} as argument {}, not {}",
+        context.name(), expected, idx, context.vm.type_name(context.get_arg(idx))
+    ))
+}
+
+fn dict_arg(context: &CallContext, idx: usize) -> Result<Handle<Object>, RuntimeError> {
+    let handle = context.get_arg(idx)
+        .as_object()
+        .filter(|o| context.vm.heap.get(o).and_then(|o| o.as_dict()).is_some());
+
+    handle.ok_or_else(|| arg_error(context, idx, "a dict"))
+}
+
+fn list_arg(context: &CallContext, idx: usize) -> Result<Handle<Object>, RuntimeError> {
+    let handle = context.get_arg(idx)
+        .as_object()
+        .filter(|o| context.vm.heap.get(o).and_then(|o| o.as_list()).is_some());
+
+    handle.ok_or_else(|| arg_error(context, idx, "a list"))
+}
+
+fn string_arg<'a>(context: &'a CallContext, idx: usize) -> Result<&'a str, RuntimeError> {
+    let string = context.get_arg(idx)
+        .as_object()
+        .and_then(|o| context.vm.heap.get(o))
+        .and_then(|o| o.as_string());
+
+    match string {
+        Some(string) => Ok(string),
+        None => Err(arg_error(context, idx, "a string")),
+    }
+}
+
+fn string_builder_arg(context: &CallContext, idx: usize) -> Result<Handle<Object>, RuntimeError> {
+    let handle = context.get_arg(idx)
+        .as_object()
+        .filter(|o| context.vm.heap.get(o).and_then(|o| o.as_string_builder()).is_some());
+
+    handle.ok_or_else(|| arg_error(context, idx, "a string builder"))
+}
+
+fn dict_content<'a>(context: &'a CallContext, dict: Handle<Object>) -> &'a HashMap<HashValue, Value> {
+    &context.vm.heap.get(dict).unwrap().as_dict().unwrap().content
+}
+
+fn list_content<'a>(context: &'a CallContext, list: Handle<Object>) -> &'a Vec<Value> {
+    &context.vm.heap.get(list).unwrap().as_list().unwrap().content
+}
+
+fn dict_len(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let dict = dict_arg(context, 1)?;
+    let len = context.vm.heap.get(dict).unwrap().as_dict().unwrap().content.len();
+
+    Ok((len as f64).into())
+}
+
+fn dict_is_empty(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let dict = dict_arg(context, 1)?;
+    let empty = context.vm.heap.get(dict).unwrap().as_dict().unwrap().content.is_empty();
+
+    Ok(empty.into())
+}
+
+fn dict_clear(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let dict = dict_arg(context, 1)?;
+
+    if let Object::Dict(ref mut dict) = context.vm.heap.get_mut_unchecked(dict) {
+        dict.content = Default::default()
+    }
+
+    Ok(Value::nil())
+}
+
+// A new dict with the entries of both, those of `b` taking precedence. The maps share their
+// structure, so this doesn't copy every entry.
+fn dict_merge(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let a = dict_arg(context, 1)?;
+    let b = dict_arg(context, 2)?;
+
+    let content = dict_content(context, b).clone().union(dict_content(context, a).clone());
+
+    Ok(context.vm.allocate(Object::Dict(Dict::new(content))).into())
+}
+
+fn get_or(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let dict = dict_arg(context, 1)?;
+    let key = context.get_arg(2);
+
+    let key = context.vm.hash_key(key)?;
+    let value = dict_content(context, dict).get(&key).cloned();
+
+    Ok(value.unwrap_or_else(|| context.get_arg(3)))
+}
+
+// Both arguments stay on the stack while the result is allocated, so the collector can't take
+// their elements
+fn concat_lists(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let a = list_arg(context, 1)?;
+    let b = list_arg(context, 2)?;
+
+    let content = list_content(context, a).iter()
+        .chain(list_content(context, b).iter())
+        .cloned()
+        .collect();
+
+    Ok(context.vm.new_list(content))
+}
+
+fn extend(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let a = list_arg(context, 1)?;
+    let b = list_arg(context, 2)?;
+
+    // Cloned first, as `b` may be `a` itself
+    let content = list_content(context, b).clone();
+
+    if let Object::List(ref mut list) = context.vm.heap.get_mut_unchecked(a) {
+        list.content.extend(content)
+    }
+
+    Ok(Value::nil())
+}
+
+// Sorts numbers or strings in place, NaN after every other number
+fn sort(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let list = list_arg(context, 1)?;
+    let mut content = list_content(context, list).clone();
+
+    let vm = &*context.vm;
+
+    merge_sort(&mut content, &mut |a, b| {
+        if let (Variant::Float(x), Variant::Float(y)) = (a.decode(), b.decode()) {
+            return Ok(x.total_cmp(&y))
+        }
+
+        a.cmp_with(&b, &vm.heap).ok_or_else(|| vm.incomparable(a, b))
+    })?;
+
+    set_list_content(context, list, content);
+
+    Ok(Value::nil())
+}
+
+// Sorts in place by a script function returning a negative number, zero or a positive number,
+// like `a - b` for ascending order
+fn sort_by(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let list = list_arg(context, 1)?;
+    let compare = context.get_arg(2);
+
+    let mut content = list_content(context, list).clone();
+
+    // The comparator may change the list while it's being sorted, so a copy keeps the elements
+    // alive in the meantime
+    context.new_list(content.clone());
+
+    merge_sort(&mut content, &mut |a, b| {
+        let order = context.call(compare, &[a, b])?;
+
+        match order.decode() {
+            Variant::Float(n) => Ok(n.partial_cmp(&0.0).unwrap_or(Ordering::Equal)),
+            _ => Err(context.error(&format!(
+                "native `sort_by` expects argument 2 to return a number, not {}",
+                context.vm.type_name(order)
+            ))),
+        }
+    })?;
+
+    set_list_content(context, list, content);
+
+    Ok(Value::nil())
+}
+
+// A comparator for `sort_by` ordering all numbers, NaN after everything else. The comparison
+// operators follow IEEE 754 instead, where NaN is unordered and every comparison with it is false.
+fn total_cmp(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let number = |idx| match context.get_arg(idx).decode() {
+        Variant::Float(n) => Ok(n),
+        _ => Err(arg_error(context, idx, "a number")),
+    };
+
+    Ok((number(1)?.total_cmp(&number(2)?) as i8 as f64).into())
+}
+
+fn reverse(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let list = list_arg(context, 1)?;
+
+    if let Object::List(ref mut list) = context.vm.heap.get_mut_unchecked(list) {
+        list.content.reverse()
+    }
+
+    Ok(Value::nil())
+}
+
+// Index of the first element deeply equal to the value
+fn list_position(context: &CallContext, list: Handle<Object>, value: Value) -> Option<usize> {
+    list_content(context, list).iter()
+        .position(|&element| deep_equal(&context.vm.heap, element, value))
+}
+
+// Strings by content, lists element by element and dicts entry by entry, anything else by
+// identity. Pairs met again while comparing, as in collections containing themselves, are taken
+// to be equal rather than compared forever.
+fn deep_equal(heap: &Heap<Object>, a: Value, b: Value) -> bool {
+    let mut seen = std::collections::HashSet::new();
+    let mut pending = vec![(a, b)];
+
+    while let Some((a, b)) = pending.pop() {
+        let (x, y) = match (a.decode(), b.decode()) {
+            (Variant::Obj(x), Variant::Obj(y)) => (x, y),
+            (x, y) => if x == y { continue } else { return false },
+        };
+
+        if x == y || !seen.insert((a, b)) {
+            continue
+        }
+
+        match (heap.get(x), heap.get(y)) {
+            (Some(Object::String(x)), Some(Object::String(y))) => if x != y { return false },
+
+            (Some(Object::List(x)), Some(Object::List(y))) => {
+                if x.content.len() != y.content.len() {
+                    return false
+                }
+
+                pending.extend(x.content.iter().cloned().zip(y.content.iter().cloned()))
+            },
+
+            (Some(Object::Dict(x)), Some(Object::Dict(y))) => {
+                if x.content.len() != y.content.len() {
+                    return false
+                }
+
+                for (key, value) in x.content.iter() {
+                    match y.get(key) {
+                        Some(&other) => pending.push((*value, other)),
+                        None => return false,
+                    }
+                }
+            },
+
+            _ => return false,
+        }
+    }
+
+    true
+}
+
+fn set_list_content(context: &mut CallContext, list: Handle<Object>, content: Vec<Value>) {
+    if let Object::List(ref mut list) = context.vm.heap.get_mut_unchecked(list) {
+        list.content = content
+    }
+}
+
+// A stable sort stopping at the first failed comparison. Unlike the sorts of the standard
+// library, it can't panic on comparators that aren't a total order, which scripts may well pass.
+fn merge_sort(values: &mut Vec<Value>, compare: &mut dyn FnMut(Value, Value) -> Result<Ordering, RuntimeError>) -> Result<(), RuntimeError> {
+    if values.len() < 2 {
+        return Ok(())
+    }
+
+    let mut right = values.split_off(values.len() / 2);
+    let mut left = values.split_off(0);
+
+    merge_sort(&mut left, compare)?;
+    merge_sort(&mut right, compare)?;
+
+    let (mut i, mut j) = (0, 0);
+
+    while i < left.len() && j < right.len() {
+        if compare(right[j], left[i])? == Ordering::Less {
+            values.push(right[j]);
+            j += 1
+        } else {
+            values.push(left[i]);
+            i += 1
+        }
+    }
+
+    values.extend_from_slice(&left[i ..]);
+    values.extend_from_slice(&right[j ..]);
+
+    Ok(())
+}
+
+fn starts_with(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let s = string_arg(context, 1)?;
+    let prefix = string_arg(context, 2)?;
+
+    Ok(s.starts_with(prefix).into())
+}
+
+fn ends_with(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let s = string_arg(context, 1)?;
+    let suffix = string_arg(context, 2)?;
+
+    Ok(s.ends_with(suffix).into())
+}
+
+fn contains(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    if let Ok(list) = list_arg(context, 1) {
+        return Ok(list_position(context, list, context.get_arg(2)).is_some().into())
+    }
+
+    let s = string_arg(context, 1).map_err(|_| arg_error(context, 1, "a list or string"))?;
+    let needle = string_arg(context, 2)?;
+
+    Ok(s.contains(needle).into())
+}
+
+// The index counts characters rather than bytes in strings, -1 if the needle isn't found
+fn index_of(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    if let Ok(list) = list_arg(context, 1) {
+        let index = list_position(context, list, context.get_arg(2)).map_or(-1.0, |i| i as f64);
+
+        return Ok(index.into())
+    }
+
+    let s = string_arg(context, 1).map_err(|_| arg_error(context, 1, "a list or string"))?;
+    let needle = string_arg(context, 2)?;
+
+    let index = match s.find(needle) {
+        Some(byte) => s[.. byte].chars().count() as f64,
+        None => -1.0,
+    };
+
+    Ok(index.into())
+}
+
+fn sb_new(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    Ok(context.vm.allocate(Object::StringBuilder(String::new())).into())
+}
+
+// Appends any value the way `print` shows it, returning the builder for chaining
+fn sb_append(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let sb = string_builder_arg(context, 1)?;
+    let fragment = format!("{}", context.get_arg_with_heap(2));
+
+    if let Object::StringBuilder(ref mut buffer) = context.vm.heap.get_mut_unchecked(sb) {
+        buffer.push_str(&fragment)
+    }
+
+    Ok(context.get_arg(1))
+}
+
+fn sb_build(context: &mut CallContext) -> Result<Value, RuntimeError> {
+    let sb = string_builder_arg(context, 1)?;
+    let string = context.vm.heap.get(sb).unwrap().as_string_builder().unwrap().clone();
+
+    Ok(context.vm.allocate(Object::String(string)).into())
+}
@@ -2,6 +2,7 @@ use super::super::gc::{ *, tag::*, trace::* };
 use super::*;
 
 use std::fmt::{Debug, Display};
+use std::mem;
 use std::rc::Rc;
 use std::cell::RefCell;
 
@@ -26,7 +27,8 @@ pub enum Object {
     NativeFunction(NativeFunction),
     Closure(Closure),
     List(List),
-    Dict(Dict)
+    Dict(Dict),
+    StringBuilder(String), // mutable, unlike strings
 }
 
 impl Object {
@@ -36,7 +38,7 @@ impl Object {
     impl_as!(as_list, List);
     impl_as!(as_dict, Dict);
 
-    pub fn native_fn(name: &str, arity: u8, function: fn(&mut Heap<Object>, &[Value]) -> Value) -> Self {
+    pub fn native_fn(name: &str, arity: u8, function: NativeFn) -> Self {
         Object::NativeFunction(
             NativeFunction {
                 name: name.into(),
@@ -46,6 +48,14 @@ impl Object {
         )
     }
 
+    pub fn as_string_builder(&self) -> Option<&String> {
+        if let Object::StringBuilder(ref o) = *self {
+            Some(o)
+        } else {
+            None
+        }
+    }
+
     pub fn as_closure_mut(&mut self) -> Option<&mut Closure> {
         if let Object::Closure(ref mut o) = *self {
             Some(o)
@@ -60,7 +70,7 @@ impl Trace<Self> for Object {
         use self::Object::*;
         
         match self {
-            String(_) => {},
+            String(_) | StringBuilder(_) => {},
             Function(f) => f.trace(tracer),
             NativeFunction(_) => {},
             Closure(c) => c.trace(tracer),
@@ -68,6 +78,21 @@ impl Trace<Self> for Object {
             Dict(d) => d.trace(tracer)
         }
     }
+
+    fn size(&self) -> usize {
+        use self::Object::*;
+
+        let payload = match self {
+            String(s) | StringBuilder(s) => s.capacity(),
+            Function(f) => f.chunk.len() + f.chunk.constants().count() * mem::size_of::<Value>(),
+            NativeFunction(_) => 0,
+            Closure(c) => c.upvalues.len() * mem::size_of::<UpValue>(),
+            List(l) => l.content.capacity() * mem::size_of::<Value>(),
+            Dict(d) => d.content.len() * mem::size_of::<(HashValue, Value)>(),
+        };
+
+        mem::size_of::<Object>() + payload
+    }
 }
 
 impl Debug for Object {
@@ -81,6 +106,7 @@ impl Debug for Object {
             Closure(ref cl) => write!(f, "<closure {:?}>", cl.function),
             List(ref ls) => write!(f, "<list [{:?}]>", ls.content.len()),
             Dict(ref dict) => write!(f, "<dict [{:?}]>", dict.content.len()),
+            StringBuilder(ref s) => write!(f, "<string builder {:?}>", s),
         }
     }
 }
@@ -96,23 +122,44 @@ impl<'h, 'a> Display for WithHeap<'h, &'a Object> {
             Closure(ref cl) => write!(f, "<fn {}>", cl.function.name),
             List(ref ls) => write!(f, "<list [{}]>", ls.content.len()),
             Dict(ref ls) => write!(f, "<dict [{}]>", ls.content.len()),
+            StringBuilder(ref s) => write!(f, "<string builder [{}]>", s.len()),
         }
     }
 }
 
+// Shown like the same object held in a value, failing for a handle no longer on the heap
+impl<'h> Display for WithHeap<'h, Handle<Object>> {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        let object = self.heap.get(self.item).ok_or(::std::fmt::Error)?;
+        write!(f, "{}", self.with(object))
+    }
+}
+
+/// Debug information on a local variable: its stack slot in the frame, and the range of code it's
+/// in scope for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LocalInfo {
+    pub name: String,
+    pub slot: u8,
+    pub start: usize,
+    pub end: usize,
+}
+
 #[derive(Debug)]
 pub struct FunctionBuilder {
     name: String,
     pub chunk: Chunk,
     arity: u8,
     upvalue_count: usize,
+    locals: Vec<LocalInfo>,
+    always_returns: bool,
 }
 
 impl FunctionBuilder {
     pub fn new(name: &str, arity: u8) -> Self {
         let name: String = name.into();
         let chunk = Chunk::new(name.clone());
-        FunctionBuilder { name, arity, chunk, upvalue_count: 0 }
+        FunctionBuilder { name, arity, chunk, upvalue_count: 0, locals: Vec::new(), always_returns: false }
     }
 
     pub fn name(&self) -> &str {
@@ -127,7 +174,40 @@ impl FunctionBuilder {
         self.upvalue_count = count;
     }
 
-    pub fn build(self) -> Function {
+    pub fn set_always_returns(&mut self, always_returns: bool) {
+        self.always_returns = always_returns;
+    }
+
+    /// Record a local coming into scope at the current end of the chunk.
+    pub fn open_local(&mut self, name: &str, slot: u8) {
+        let start = self.chunk.len();
+
+        self.locals.push(
+            LocalInfo {
+                name: name.into(),
+                slot,
+                start,
+                end: usize::MAX,
+            }
+        )
+    }
+
+    /// Record the local in `slot` going out of scope at the current end of the chunk.
+    pub fn close_local(&mut self, slot: u8) {
+        let end = self.chunk.len();
+
+        if let Some(local) = self.locals.iter_mut().rev().find(|l| l.slot == slot && l.end == usize::MAX) {
+            local.end = end
+        }
+    }
+
+    pub fn build(mut self) -> Function {
+        let end = self.chunk.len();
+
+        for local in self.locals.iter_mut() {
+            local.end = local.end.min(end)
+        }
+
         Function::new(self)
     }
 }
@@ -138,6 +218,8 @@ pub struct Function {
     chunk: Chunk,
     arity: u8,
     upvalue_count: usize,
+    locals: Vec<LocalInfo>,
+    always_returns: bool,
 }
 
 impl Function {
@@ -147,6 +229,8 @@ impl Function {
             arity: builder.arity,
             chunk: builder.chunk,
             upvalue_count: builder.upvalue_count,
+            locals: builder.locals,
+            always_returns: builder.always_returns,
         }
     }
 
@@ -154,13 +238,49 @@ impl Function {
         &self.name
     }
 
+    // Renames the chunk along with the function, e.g. to give anonymous functions a useful name
+    pub fn set_name(&mut self, name: &str) {
+        self.name = name.into();
+        self.chunk.set_name(name)
+    }
+
     pub fn chunk(&self) -> &Chunk {
         &self.chunk
     }
 
+    pub fn chunk_mut(&mut self) -> &mut Chunk {
+        &mut self.chunk
+    }
+
     pub fn upvalue_count(&self) -> usize {
         self.upvalue_count
     }
+
+    /// Size of the function's bytecode in bytes.
+    pub fn code_size(&self) -> usize {
+        self.chunk.len()
+    }
+
+    /// Number of constants the function's bytecode refers to.
+    pub fn constant_count(&self) -> usize {
+        self.chunk.constants().count()
+    }
+
+    /// Every named local of the function, in order of declaration.
+    pub fn locals(&self) -> &[LocalInfo] {
+        &self.locals
+    }
+
+    /// Number of stack slots the locals of a call take up, including the reserved first slot.
+    pub fn local_count(&self) -> usize {
+        self.locals.iter().map(|l| l.slot as usize + 1).max().unwrap_or(1)
+    }
+
+    /// Whether every path through the function ends in a `Return`. When it doesn't, a call can run
+    /// off the end of the body, which is a runtime error.
+    pub fn always_returns(&self) -> bool {
+        self.always_returns
+    }
 }
 
 impl Trace<Object> for Function {
@@ -169,11 +289,13 @@ impl Trace<Object> for Function {
     }
 }
 
+pub type NativeFn = fn(&mut CallContext) -> Result<Value, RuntimeError>;
+
 #[derive(Clone)]
 pub struct NativeFunction {
     pub name: String,
     pub arity: u8,
-    pub function: fn(&mut Heap<Object>, &[Value]) -> Value,
+    pub function: NativeFn,
 }
 
 #[derive(Debug, Clone)]
@@ -243,7 +365,13 @@ impl Dict {
 
 impl Trace<Object> for Dict {
     fn trace(&self, tracer: &mut Tracer<Object>) {
-        self.content.values().for_each(|v| v.trace(tracer));
+        for (key, value) in self.content.iter() {
+            if let HashVariant::Object(ref handle) = key.variant {
+                handle.trace(tracer)
+            }
+
+            value.trace(tracer)
+        }
     }
 }
 
@@ -315,6 +443,10 @@ impl Closure {
         self.function.chunk()
     }
 
+    pub fn function(&self) -> &Function {
+        &self.function
+    }
+
     pub fn upvalue_count(&self) -> usize {
         self.upvalues.len()
     }
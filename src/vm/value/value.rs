@@ -1,10 +1,14 @@
 use super::super::gc::{ *, tag::*, trace::* };
 use super::*;
 
+use std::cmp::Ordering;
 use std::fmt::{Debug, Display};
 use std::mem;
 
-#[derive(Clone, Copy, PartialEq, Eq)]
+/// Values compare and hash by representation: objects by identity and floats by bit pattern. So
+/// `0.0` and `-0.0` are different values, while every NaN is stored as the same canonical NaN and
+/// equals itself. Decode to a `Variant` for numeric comparison.
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
 pub struct Value {
     handle: TaggedHandle<Object>,
 }
@@ -15,6 +19,23 @@ pub enum HashVariant {
     Int(i64),
     Str(String),
     Nil,
+    /// A function, hashed by identity rather than structure: two closures are only the same key
+    /// if they are the very same object.
+    Object(Handle<Object>),
+}
+
+impl HashVariant {
+    fn pretty(&self, heap: &Heap<Object>) -> String {
+        use self::HashVariant::*;
+
+        match *self {
+            Bool(b) => format!("{}", b),
+            Int(bits) => format!("{}", f64::from_bits(bits as u64)),
+            Str(ref s) => format!("{:?}", s),
+            Nil => "nil".into(),
+            Object(handle) => format!("{}", Value::object(handle).with_heap(heap)),
+        }
+    }
 }
 
 #[derive(Hash, Clone, PartialEq, Eq, Debug)]
@@ -22,6 +43,66 @@ pub struct HashValue {
     pub variant: HashVariant
 }
 
+/// A value usable as a key in the host's own maps and sets, e.g. to memoize script functions. It
+/// hashes and compares by content the way dict keys do, so equal strings are the same key
+/// wherever they were allocated. Lists, dicts and string builders may change, and aren't hashable.
+///
+/// The key holds on to its content, but not to the value: an object only referred to by the key
+/// may still be collected.
+#[derive(Clone, Debug)]
+pub struct HashableValue {
+    value: Value,
+    key: HashVariant,
+}
+
+impl HashableValue {
+    pub fn new(value: Value, heap: &Heap<Object>) -> Option<Self> {
+        let key = value.decode().try_to_hash(heap)?;
+
+        Some(HashableValue {
+            value,
+            key,
+        })
+    }
+
+    /// The value the key was made from.
+    pub fn value(&self) -> Value {
+        self.value
+    }
+}
+
+impl PartialEq for HashableValue {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl Eq for HashableValue {}
+
+impl std::hash::Hash for HashableValue {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.key.hash(state)
+    }
+}
+
+/// How much of a value `Value::display_with` shows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DisplayPolicy {
+    /// Collections as a summary like `<list [3]>`, the way `print` shows them by default.
+    Summary,
+    /// Collections with their contents, e.g. `[1, 2, [3]]`.
+    Full,
+}
+
+/// Which values conditions treat as false, besides `false` and `nil` which always are.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TruthPolicy {
+    /// Nothing else, every number and object is true.
+    Standard,
+    /// Empty strings, lists, dicts and string builders are false too.
+    EmptyFalsy,
+}
+
 #[derive(Debug, Clone, PartialEq)]
 pub enum Variant {
     Float(f64),
@@ -33,9 +114,14 @@ pub enum Variant {
 
 impl Variant {
     pub fn to_hash(&self, heap: &Heap<Object>) -> HashVariant {
+        self.try_to_hash(heap).expect("unhashable value")
+    }
+
+    // Strings are hashed by content and functions by identity, other objects aren't hashable
+    pub fn try_to_hash(&self, heap: &Heap<Object>) -> Option<HashVariant> {
         use self::Variant::*;
 
-        match *self {
+        let hash = match *self {
             Float(ref f) => {
                 unsafe {
                     HashVariant::Int(
@@ -47,12 +133,17 @@ impl Variant {
             True  => HashVariant::Bool(true),
             False => HashVariant::Bool(false),
 
-            Obj(ref n) => unsafe {
-                HashVariant::Str(heap.get_unchecked(n).as_string().unwrap().clone().to_string())
+            Obj(ref n) => match unsafe { heap.get_unchecked(n) } {
+                Object::String(ref s) => HashVariant::Str(s.clone()),
+                Object::Closure(_) | Object::Function(_) | Object::NativeFunction(_) => HashVariant::Object(*n),
+
+                _ => return None,
             },
 
             Nil => HashVariant::Nil,
-        }
+        };
+
+        Some(hash)
     }
 }
 
@@ -107,6 +198,135 @@ impl Value {
         WithHeap::new(heap, *self)
     }
 
+    /// Renders the value with every element of a list or dict on its own line, nested `indent`
+    /// spaces deeper than its parent. Collections containing themselves are cut short with `...`.
+    pub fn pretty(&self, heap: &Heap<Object>, indent: usize) -> String {
+        let mut out = String::new();
+        self.write_pretty(heap, Some(indent), 0, &mut Vec::new(), &mut out);
+
+        out
+    }
+
+    /// Renders the value as chosen by `policy`, either the summary used by `print` or the full
+    /// contents on a single line. Full renderings of lists and dicts come from the heap's display
+    /// cache when enabled, see `Heap::set_display_cache`.
+    pub fn display_with(&self, heap: &Heap<Object>, policy: DisplayPolicy) -> String {
+        let render = || {
+            let mut out = String::new();
+            self.write_pretty(heap, None, 0, &mut Vec::new(), &mut out);
+
+            out
+        };
+
+        match policy {
+            DisplayPolicy::Summary => format!("{}", self.with_heap(heap)),
+            DisplayPolicy::Full => match self.as_object() {
+                Some(handle) if matches!(heap.get(handle), Some(Object::List(_)) | Some(Object::Dict(_))) => {
+                    heap.cached_display(handle, render).to_string()
+                },
+
+                _ => render(),
+            },
+        }
+    }
+
+    // Without an indent everything is written on one line
+    fn write_pretty(&self, heap: &Heap<Object>, indent: Option<usize>, depth: usize, seen: &mut Vec<Handle<Object>>, out: &mut String) {
+        let handle = match self.as_object() {
+            Some(handle) => handle,
+            None => return out.push_str(&format!("{}", self.with_heap(heap))),
+        };
+
+        if seen.contains(&handle) {
+            return out.push_str("...")
+        }
+
+        let pad = |depth: usize| " ".repeat(depth * indent.unwrap_or(0));
+        let (open, separator, close) = if indent.is_some() { ("\n", ",\n", "\n") } else { ("", ", ", "") };
+
+        seen.push(handle);
+
+        match heap.get(handle) {
+            Some(Object::String(ref s)) => out.push_str(&format!("{:?}", s)),
+
+            Some(Object::List(ref list)) if !list.content.is_empty() => {
+                out.push('[');
+                out.push_str(open);
+
+                for (i, element) in list.content.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(separator)
+                    }
+
+                    out.push_str(&pad(depth + 1));
+                    element.write_pretty(heap, indent, depth + 1, seen, out);
+                }
+
+                out.push_str(close);
+                out.push_str(&pad(depth));
+                out.push(']')
+            },
+
+            Some(Object::List(_)) => out.push_str("[]"),
+
+            Some(Object::Dict(ref dict)) if !dict.content.is_empty() => {
+                // Hash order changes between runs, so keys are sorted to keep the output stable
+                let mut entries = dict.content.iter()
+                    .map(|(key, value)| (key.variant.pretty(heap), *value))
+                    .collect::<Vec<_>>();
+
+                entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+                out.push('{');
+                out.push_str(open);
+
+                for (i, (key, value)) in entries.iter().enumerate() {
+                    if i > 0 {
+                        out.push_str(separator)
+                    }
+
+                    out.push_str(&pad(depth + 1));
+                    out.push_str(key);
+                    out.push_str(": ");
+                    value.write_pretty(heap, indent, depth + 1, seen, out);
+                }
+
+                out.push_str(close);
+                out.push_str(&pad(depth));
+                out.push('}')
+            },
+
+            Some(Object::Dict(_)) => out.push_str("{}"),
+
+            _ => out.push_str(&format!("{}", self.with_heap(heap))),
+        }
+
+        seen.pop();
+    }
+
+    /// Whether both values are identical: equal numbers, booleans or nil, or the very same object.
+    /// Unlike script equality, strings with the same content in different allocations differ.
+    pub fn is_same(&self, other: &Value) -> bool {
+        self == other
+    }
+
+    /// Order two values the way the comparison instructions do: numbers and strings within their
+    /// own type. Values of other types, and NaN, are unordered.
+    pub fn cmp_with(&self, other: &Value, heap: &Heap<Object>) -> Option<Ordering> {
+        match (self.decode(), other.decode()) {
+            (Variant::Float(a), Variant::Float(b)) => a.partial_cmp(&b),
+
+            (Variant::Obj(a), Variant::Obj(b)) => {
+                let a = heap.get(a).and_then(Object::as_string)?;
+                let b = heap.get(b).and_then(Object::as_string)?;
+
+                Some(a.cmp(b))
+            },
+
+            _ => None,
+        }
+    }
+
     pub fn float(float: f64) -> Self {
         Value {
             handle: TaggedHandle::from_float(float),
@@ -132,6 +352,24 @@ impl Value {
         }
     }
 
+    /// Like `truthy`, but following `policy`, which may need to look inside objects.
+    pub fn truthy_with(&self, heap: &Heap<Object>, policy: TruthPolicy) -> bool {
+        if !self.truthy() {
+            return false
+        }
+
+        match (policy, self.as_object().and_then(|handle| heap.get(handle))) {
+            (TruthPolicy::EmptyFalsy, Some(object)) => match object {
+                Object::String(ref s) | Object::StringBuilder(ref s) => !s.is_empty(),
+                Object::List(ref list) => !list.content.is_empty(),
+                Object::Dict(ref dict) => !dict.content.is_empty(),
+                _ => true,
+            },
+
+            _ => true,
+        }
+    }
+
     pub fn nil() -> Self {
         Value {
             handle: TaggedHandle::from_tag(TAG_NIL),
@@ -171,15 +409,22 @@ impl Debug for Value {
     }
 }
 
-impl Into<Value> for f64 {
-    fn into(self) -> Value {
-        Value::float(self)
+impl From<f64> for Value {
+    fn from(float: f64) -> Self {
+        Value::float(float)
     }
 }
 
-impl Into<Value> for bool {
-    fn into(self) -> Value {
-        if self {
+// Numbers are floats, so integers past 2^53 lose precision
+impl From<i64> for Value {
+    fn from(int: i64) -> Self {
+        Value::float(int as f64)
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        if b {
             Value::truelit()
         } else {
             Value::falselit()
@@ -210,8 +455,14 @@ impl<'h> Display for WithHeap<'h, Value> {
             Variant::True => write!(f, "true"),
             Variant::Float(n) => write!(f, "{}", n),
             Variant::Obj(o) => {
-                let o = self.heap.get(o).ok_or(::std::fmt::Error)?;
-                write!(f, "{}", self.with(o))
+                let object = self.heap.get(o).ok_or(::std::fmt::Error)?;
+
+                // The alternate form `{:#}` shows lists and dicts in full, through the display cache
+                if f.alternate() && matches!(object, Object::List(_) | Object::Dict(_)) {
+                    return write!(f, "{}", self.item.display_with(self.heap, DisplayPolicy::Full))
+                }
+
+                write!(f, "{}", self.with(object))
             },
         }
     }
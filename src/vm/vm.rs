@@ -10,16 +10,27 @@ use super::*;
 use super::compiler::CompileState;
 
 use std::mem;
+use std::io::{ self, Write };
+use std::time::Instant;
+use std::cmp::Ordering;
 
 const STACK_SIZE:  usize = 4096;
 const HEAP_GROWTH: usize = 2;
 
 const GC_TRIGGER_COUNT: usize = 1024;
 
+const NATIVE_DEPTH_LIMIT: usize = 64;
+
+const DEADLINE_INTERVAL: usize = 1024;
+
+type PrintHook = Box<dyn FnMut(&str)>;
+
 pub struct CallFrame {
     closure: Handle<Object>,
     ip: usize,
     stack_start: usize,
+    // Where the instruction being run begins, for reporting truncated operands
+    op_start: usize,
 }
 
 impl CallFrame {
@@ -28,34 +39,37 @@ impl CallFrame {
             closure,
             ip: 0,
             stack_start,
+            op_start: 0,
         }
     }
 
-    pub fn read_byte(&mut self) -> u8 {
-        let ip = self.ip;
-        self.ip += 1;
-        self.with_chunk(|c| c.read_byte(ip))
+    // The readers give `None` for operands running past the end of the chunk
+    pub fn read_byte(&mut self) -> Option<u8> {
+        self.read_operand(1, Chunk::read_byte)
     }
 
-    pub fn read_u16(&mut self) -> u16 {
-        let ip = self.ip;
-        self.ip += 2;
-        self.with_chunk(|c| c.read_u16(ip))
+    pub fn read_u16(&mut self) -> Option<u16> {
+        self.read_operand(2, Chunk::read_u16)
     }
 
-    pub fn read_u64(&mut self) -> u64 {
+    pub fn read_u64(&mut self) -> Option<u64> {
+        self.read_operand(8, Chunk::read_u64)
+    }
+
+    fn read_operand<T>(&mut self, len: usize, read: impl FnOnce(&Chunk, usize) -> T) -> Option<T> {
         let ip = self.ip;
-        self.ip += 8;
-        self.with_chunk(|c| c.read_u64(ip))
+        self.ip += len;
+
+        self.with_chunk(|c| if ip + len <= c.len() { Some(read(c, ip)) } else { None })
     }
 
-    pub fn read_constant_at(&mut self, idx: u8) -> Value {
-        self.with_chunk(|c| *c.get_constant(idx).expect("invalid constant index"))
+    pub fn read_constant_at(&self, idx: u16) -> Option<Value> {
+        self.with_chunk(|c| c.get_constant(idx).cloned())
     }
 
-    pub fn read_constant(&mut self) -> Value {
-        let idx = self.read_byte();
-        self.read_constant_at(idx)
+    pub fn read_constant(&mut self) -> Option<Value> {
+        let idx = self.read_byte()?;
+        self.read_constant_at(idx as u16)
     }
 
     pub fn with_chunk<F, T>(&self, fun: F) -> T
@@ -71,8 +85,135 @@ impl CallFrame {
     }
 }
 
+/// The context a native function is called with, giving access to its arguments and to the VM
+/// that called it.
+pub struct CallContext<'vm> {
+    pub(crate) vm: &'vm mut VM,
+    native: Handle<Object>,
+    frame_start: usize,
+    arity: u8,
+}
+
+impl<'vm> CallContext<'vm> {
+    fn new(vm: &'vm mut VM, native: Handle<Object>, frame_start: usize, arity: u8) -> Self {
+        CallContext {
+            vm,
+            native,
+            frame_start,
+            arity,
+        }
+    }
+
+    /// The name the called native was registered under, e.g. to say which native failed.
+    pub fn name(&self) -> &str {
+        match self.vm.deref(self.native) {
+            Object::NativeFunction(native) => &native.name,
+            _ => unreachable!("natives are only called through native function objects"),
+        }
+    }
+
+    /// Get an argument of the call. Index 0 is the called native itself, so the actual arguments
+    /// start at 1.
+    #[inline]
+    pub fn get_arg(&self, idx: usize) -> Value {
+        self.vm.stack[self.frame_start + idx]
+    }
+
+    pub fn get_arg_with_heap(&self, idx: usize) -> WithHeap<'_, Value> {
+        self.with_heap(self.get_arg(idx))
+    }
+
+    pub fn with_heap(&self, value: Value) -> WithHeap<'_, Value> {
+        value.with_heap(&self.vm.heap)
+    }
+
+    /// The VM's heap, for dereferencing any handle the native comes across.
+    pub fn heap(&self) -> &Heap<Object> {
+        &self.vm.heap
+    }
+
+    /// Objects inserted directly into the heap aren't rooted while the native runs, prefer
+    /// `new_string` and friends for anything that must survive another allocation.
+    pub fn heap_mut(&mut self) -> &mut Heap<Object> {
+        &mut self.vm.heap
+    }
+
+    /// Number of values on the stack above the called native: its arguments followed by
+    /// anything pushed with `push`.
+    pub fn stack_len(&self) -> usize {
+        self.vm.stack.len() - self.frame_start - 1
+    }
+
+    /// Push an extra result. Once the native returns, the callee and arguments are removed from
+    /// the stack, leaving the pushed values in order with the return value on top of them.
+    pub fn push(&mut self, value: Value) {
+        self.vm.push(value)
+    }
+
+    /// Pop a value previously pushed by this native. Never pops the arguments.
+    pub fn pop(&mut self) -> Option<Value> {
+        if self.stack_len() > self.arity as usize {
+            self.vm.stack.pop()
+        } else {
+            None
+        }
+    }
+
+    /// The locals of the script function that called the native, with their current values.
+    pub fn caller_locals(&self) -> Vec<(String, Variant)> {
+        self.vm.current_locals()
+    }
+
+    /// Allocate a string, e.g. to return from the native. Objects allocated through the context
+    /// are kept alive until the native returns.
+    pub fn new_string(&mut self, string: &str) -> Value {
+        let value = self.vm.new_string(string);
+        self.keep(value)
+    }
+
+    /// Allocate a list, e.g. to return from the native. Like `new_string`, it is kept alive until
+    /// the native returns.
+    pub fn new_list(&mut self, content: Vec<Value>) -> Value {
+        let value = self.vm.new_list(content);
+        self.keep(value)
+    }
+
+    /// Allocate a dict from key-value pairs, kept alive until the native returns. Fails for keys
+    /// that can't be hashed, like `VM::new_dict`.
+    pub fn new_dict(&mut self, entries: Vec<(Value, Value)>) -> Result<Value, RuntimeError> {
+        let value = self.vm.new_dict(entries)?;
+        Ok(self.keep(value))
+    }
+
+    /// Call back into a function value, running it to completion and returning its result. The
+    /// result is kept alive until the native returns.
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        let value = self.vm.internal_call(callee, args)?;
+        Ok(self.keep(value))
+    }
+
+    fn keep(&mut self, value: Value) -> Value {
+        if let Some(handle) = value.as_object() {
+            self.vm.native_temps.push(handle)
+        }
+
+        value
+    }
+
+    /// Compile and run a separate program, e.g. an event handler, before returning to the calling
+    /// script. It shares the globals and heap of the VM.
+    pub fn exec(&mut self, atoms: &[ExprNode]) -> Result<(), RuntimeError> {
+        self.vm.exec(atoms, false)
+    }
+
+    /// Create an error carrying the backtrace of the script that called the native.
+    pub fn error(&self, err: &str) -> RuntimeError {
+        self.vm.runtime_error(err)
+    }
+}
+
 macro_rules! binary_op {
-    ($self:ident, $op:tt) => {
+    ($self:ident, $op:tt) => {{
         let b = $self.pop();
         let a = $self.pop();
 
@@ -80,10 +221,53 @@ macro_rules! binary_op {
             let c = a $op b;
             $self.push(c.into());
 
-            return
+            return Ok(())
         }
 
         // TODO: ERROR HERE
+        Ok(())
+    }}
+}
+
+// Watches for a backward jump running over and over with the stack at the same height, which
+// during development usually means a loop that never ends
+struct LoopWatch {
+    threshold: usize,
+    hook: Box<dyn FnMut(&str)>,
+    // The last loop seen, by frame depth and address, the stack height then and how many times
+    // in a row it ran
+    frame: usize,
+    ip: usize,
+    stack: usize,
+    count: usize,
+}
+
+/// A program suspended by `yield`, holding its call frames and stack until passed to `VM::resume`.
+/// Dropping it abandons the program.
+pub struct Suspended {
+    /// The value passed to `yield`.
+    pub value: Value,
+    frames: Vec<CallFrame>,
+    // Kept in a rooted list so the collector leaves the values alone in the meantime
+    stack: Rooted<Object>,
+    // Rooted apart from the stack, which doesn't hold the closures of methods called by `invoke`
+    closures: Vec<Rooted<Object>>,
+    upvalues: Vec<UpValue>,
+    base: usize,
+}
+
+// How a run of a top-level program ended
+enum Outcome {
+    Finished(Value),
+    Yielded(Suspended),
+}
+
+impl Outcome {
+    fn suspended(self) -> Option<Suspended> {
+        match self {
+            Outcome::Finished(_) => None,
+            Outcome::Yielded(state) => Some(state),
+        }
     }
 }
 
@@ -91,11 +275,35 @@ pub struct VM {
     pub heap: Heap<Object>,
     next_gc: usize,
 
+    /// Values of objects are handles, so a host reading a list from here shares it with scripts
+    /// and sees their later changes. `get_global_cloned` gives a snapshot instead.
     pub globals: HashMap<String, Value, FnvBuildHasher>,
     pub open_upvalues: Vec<UpValue>,
 
     pub stack: Vec<Value>,
     pub frames: Vec<CallFrame>,
+
+    native_depth: usize,
+    // Objects natives got hold of through their `CallContext`, rooted until the native returns
+    native_temps: Vec<Handle<Object>>,
+    stack_limit: usize,
+    strict_globals: bool,
+    truth_policy: TruthPolicy,
+
+    deadline: Option<Instant>,
+    deadline_interval: usize,
+    deadline_countdown: usize,
+
+    print_hook: Option<PrintHook>,
+    flush_hook: Option<Box<dyn FnMut()>>,
+    print_policy: DisplayPolicy,
+
+    op_counts: Option<Box<[u64; 256]>>,
+    loop_watch: Option<LoopWatch>,
+
+    // Whether the running program may yield, and whether it just did
+    resumable: bool,
+    yielded: bool,
 }
 
 impl VM {
@@ -106,61 +314,279 @@ impl VM {
             next_gc: GC_TRIGGER_COUNT,
             globals: HashMap::with_hasher(FnvBuildHasher::default()),
             frames:  Vec::with_capacity(256),
-            open_upvalues: Vec::with_capacity(16)
+            open_upvalues: Vec::with_capacity(16),
+            native_depth: 0,
+            native_temps: Vec::new(),
+            stack_limit: STACK_SIZE,
+            strict_globals: false,
+            truth_policy: TruthPolicy::Standard,
+            deadline: None,
+            deadline_interval: DEADLINE_INTERVAL,
+            deadline_countdown: DEADLINE_INTERVAL,
+            print_hook: None,
+            flush_hook: None,
+            print_policy: DisplayPolicy::Summary,
+            op_counts: None,
+            loop_watch: None,
+            resumable: false,
+            yielded: false,
+        }
+    }
+
+    /// Create a VM whose heap has room for `capacity` objects up front, for programs known to
+    /// allocate many objects.
+    pub fn with_heap_capacity(capacity: usize) -> Self {
+        VM {
+            heap: Heap::with_capacity(capacity),
+            ..VM::new()
         }
     }
 
-    pub fn exec_from(&mut self, atoms: &[ExprNode], locals: Vec<Local>, debug: bool) -> Vec<Local> {
+    pub fn exec_from(&mut self, atoms: &[ExprNode], locals: Vec<Local>, debug: bool) -> Result<Vec<Local>, RuntimeError> {
         let mut compiler = Compiler::new(&mut self.heap);
 
         let function = compiler.compile_from(atoms, locals);
         let locals = compiler.locals_cache;
 
+        let function = function.map_err(|e| self.error(ErrorKind::Compile(e)))?;
+
         if debug {
             let dis = Disassembler::new(function.chunk(), &self.heap);
             dis.disassemble();
         }
 
-        let closure = Closure::new(function, Vec::new());
-        let value = self.allocate(Object::Closure(closure)).into();
-
-        self.push(value);
-        self.call(0);
-
-        self.run();
+        self.run_function(function)?;
 
         if debug {
             f::dump_html(File::create("flamegraph.html").unwrap()).unwrap();
         }
 
-        locals
+        Ok(locals)
+    }
+
+    /// Evaluate a single expression, as a REPL would, returning its value.
+    pub fn eval_expression(&mut self, expr: ExprNode) -> Result<Value, RuntimeError> {
+        self.exec_returning(&[expr], false)
+    }
+
+    /// Compile a program without running it, e.g. for tooling that checks or caches programs. Its
+    /// string and function constants are allocated on this VM's heap, see `exec_function` on
+    /// keeping them alive until the function is run.
+    pub fn compile_only(&mut self, atoms: &[ExprNode]) -> Result<Function, CompileError> {
+        Compiler::new(&mut self.heap).compile(atoms)
+    }
+
+    pub fn exec(&mut self, atoms: &[ExprNode], debug: bool) -> Result<(), RuntimeError> {
+        let function = self.compile_only(atoms)
+            .map_err(|e| self.error(ErrorKind::Compile(e)))?;
+
+        self.exec_function(function, debug)
     }
 
-    pub fn exec(&mut self, atoms: &[ExprNode], debug: bool) {
+    /// Like `exec`, but returns the value of the program's last expression, or nil if it ends
+    /// with a statement. Like any value held by the host, an object returned this way is only
+    /// safe until the next collection unless rooted or stored somewhere the VM can see.
+    pub fn exec_returning(&mut self, atoms: &[ExprNode], debug: bool) -> Result<Value, RuntimeError> {
         let function = {
             let mut compiler = Compiler::new(&mut self.heap);
-            compiler.compile(atoms)
+            compiler.compile_returning(atoms)
         };
 
+        let function = function.map_err(|e| self.error(ErrorKind::Compile(e)))?;
+
         if debug {
             let dis = Disassembler::new(function.chunk(), &self.heap);
             dis.disassemble();
         }
 
-        let closure = Closure::new(function, Vec::new());
-        let value = self.allocate(Object::Closure(closure)).into();
+        let outcome = self.run_function(function)?;
 
-        self.push(value);
-        self.call(0);
+        if debug {
+            f::dump_html(File::create("flamegraph.html").unwrap()).unwrap();
+        }
 
-        self.run();
+        match outcome {
+            Outcome::Finished(value) => Ok(value),
+            Outcome::Yielded(_) => unreachable!("only resumable programs yield"),
+        }
+    }
+
+    /// Run a top-level function compiled beforehand, e.g. to run the same program many times
+    /// without recompiling it.
+    ///
+    /// The constants of the function live on the heap it was compiled with, which must be this
+    /// VM's, and are only traced while it runs. A function kept between runs needs its object
+    /// constants rooted, or the collector may free them in the meantime.
+    pub fn exec_function(&mut self, function: Function, debug: bool) -> Result<(), RuntimeError> {
+        if debug {
+            let dis = Disassembler::new(function.chunk(), &self.heap);
+            dis.disassemble();
+        }
+
+        self.run_function(function)?;
 
         if debug {
             f::dump_html(File::create("flamegraph.html").unwrap()).unwrap();
         }
+
+        Ok(())
+    }
+
+    /// Like `exec`, but the program may `yield`, suspending it and returning control to the host
+    /// along with the yielded value. Returns `None` once the program has run to completion.
+    pub fn exec_resumable(&mut self, atoms: &[ExprNode]) -> Result<Option<Suspended>, RuntimeError> {
+        let function = self.compile_only(atoms)
+            .map_err(|e| self.error(ErrorKind::Compile(e)))?;
+
+        self.resumable = true;
+        let result = self.run_function(function);
+        self.resumable = false;
+
+        result.map(Outcome::suspended)
+    }
+
+    /// Continue a suspended program from its `yield`, which evaluates to nil, until it yields
+    /// again or completes. Only possible from the top level, not from within a native.
+    pub fn resume(&mut self, state: Suspended) -> Result<Option<Suspended>, RuntimeError> {
+        if !self.frames.is_empty() || self.stack.len() != state.base {
+            return Err(self.runtime_error("can only resume a program from the top level"))
+        }
+
+        let Suspended { frames, stack, closures, upvalues, base, .. } = state;
+
+        if let Some(Object::List(ref mut list)) = self.heap.get_mut(stack.handle()) {
+            self.stack.append(&mut list.content)
+        }
+
+        if let Some(top) = self.stack.last_mut() {
+            *top = Value::nil()
+        }
+
+        // Back in the frames, the closures are roots again
+        self.frames = frames;
+        drop(closures);
+
+        self.open_upvalues.extend(upvalues);
+
+        self.resumable = true;
+        let result = self.run_until(0);
+        self.resumable = false;
+
+        if result.is_err() {
+            self.native_depth = 0
+        }
+
+        self.finish_run(0, base, result).map(Outcome::suspended)
+    }
+
+    /// Like `exec`, but fails with a timeout once `deadline` has passed.
+    pub fn exec_with_deadline(&mut self, atoms: &[ExprNode], deadline: Instant) -> Result<(), RuntimeError> {
+        self.deadline = Some(deadline);
+        self.deadline_countdown = self.deadline_interval;
+
+        let result = self.exec(atoms, false);
+
+        self.deadline = None;
+
+        result
+    }
+
+    /// Set how many instructions run between checks of the clock when running with a deadline.
+    pub fn set_deadline_interval(&mut self, instructions: usize) {
+        self.deadline_interval = instructions.max(1)
+    }
+
+    /// Set the number of stack slots calls may use before failing with a stack overflow. The
+    /// stack itself grows as needed.
+    pub fn set_stack_size(&mut self, size: usize) {
+        self.stack_limit = size
+    }
+
+    /// In strict mode, assigning to a global that was never defined is an error, rather than
+    /// defining it.
+    pub fn set_strict_globals(&mut self, strict: bool) {
+        self.strict_globals = strict
+    }
+
+    /// Choose which values conditions, `&&`, `||` and `!` treat as false. Standard by default,
+    /// where only `false` and `nil` are.
+    pub fn set_truth_policy(&mut self, policy: TruthPolicy) {
+        self.truth_policy = policy
+    }
+
+    fn truthy(&self, value: Value) -> bool {
+        value.truthy_with(&self.heap, self.truth_policy)
+    }
+
+    /// Count how many times each instruction is executed, from zero. Counting is off by default.
+    pub fn set_op_counting(&mut self, enabled: bool) {
+        self.op_counts = if enabled {
+            Some(Box::new([0; 256]))
+        } else {
+            None
+        }
     }
 
-    pub fn add_native(&mut self, name: &str, func: fn(&mut Heap<Object>, &[Value]) -> Value, arity: u8) {
+    /// Number of executions of each instruction that ran since counting was enabled, by `Op`
+    /// name.
+    pub fn op_counts(&self) -> HashMap<&'static str, u64> {
+        let mut counts = HashMap::new();
+
+        if let Some(ref op_counts) = self.op_counts {
+            for (byte, &count) in op_counts.iter().enumerate() {
+                if count > 0 {
+                    let name = op_name(byte as u8).unwrap_or("Unknown");
+                    *counts.entry(name).or_insert(0) += count
+                }
+            }
+        }
+
+        counts
+    }
+
+    /// Send everything printed by scripts to `hook` instead of stdout.
+    pub fn set_print_hook(&mut self, hook: impl FnMut(&str) + 'static) {
+        self.print_hook = Some(Box::new(hook))
+    }
+
+    /// Choose how `print` and `write` show lists and dicts, as a summary by default. Full renderings
+    /// come from the heap's display cache when it's enabled, see `Heap::set_display_cache`.
+    pub fn set_print_policy(&mut self, policy: DisplayPolicy) {
+        self.print_policy = policy
+    }
+
+    /// Called by `flush_output`, for print hooks that buffer what they're sent.
+    pub fn set_flush_hook(&mut self, hook: impl FnMut() + 'static) {
+        self.flush_hook = Some(Box::new(hook))
+    }
+
+    /// Make everything scripts printed so far visible, e.g. output of `write` without a trailing
+    /// newline before the host prints something of its own. Flushes stdout unless the output goes
+    /// to a print hook, in which case the flush hook is called, if any.
+    pub fn flush_output(&mut self) {
+        if let Some(ref mut hook) = self.flush_hook {
+            hook()
+        } else if self.print_hook.is_none() {
+            // Nothing to be done about a closed stdout, any more than `print!` would
+            let _ = io::stdout().flush();
+        }
+    }
+
+    /// A development aid: call `hook` with a warning once a loop has gone around `threshold` times
+    /// in a row without the stack growing or shrinking, as an accidentally infinite loop would.
+    /// Long but finite loops are reported too, it's only a hint. Off by default.
+    pub fn set_loop_warning(&mut self, threshold: usize, hook: impl FnMut(&str) + 'static) {
+        self.loop_watch = Some(LoopWatch {
+            threshold: threshold.max(1),
+            hook: Box::new(hook),
+            frame: 0,
+            ip: 0,
+            stack: 0,
+            count: 0,
+        })
+    }
+
+    pub fn add_native(&mut self, name: &str, func: NativeFn, arity: u8) {
         let function = self.allocate(
             Object::native_fn(name, arity, func)
         );
@@ -168,47 +594,431 @@ impl VM {
         self.globals.insert(name.into(), function.into());
     }
 
-    fn run(&mut self)  {
-        while !self.frames.is_empty() {
-            let inst = self.read_byte();
-            decode_op!(inst, self)
+    /// The names and arities of the natives bound to globals, sorted by name. A native bound to
+    /// several globals is listed once for each, under the name it was registered with.
+    pub fn native_names(&self) -> Vec<(&str, u8)> {
+        let mut natives = self.globals.values()
+            .filter_map(|value| value.as_object())
+            .filter_map(|handle| match self.heap.get(handle) {
+                Some(Object::NativeFunction(native)) => Some((native.name.as_str(), native.arity)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+
+        natives.sort();
+        natives
+    }
+
+    /// A deep copy of a global, so later changes by scripts to the lists, dicts and string builders
+    /// it holds don't show in it. Strings are immutable and shared rather than copied, and shared
+    /// or cyclic structure is preserved within the copy. The copy has the same rooting caveat as
+    /// `new_list`.
+    pub fn get_global_cloned(&mut self, name: &str) -> Option<Value> {
+        let value = *self.globals.get(name)?;
+
+        Some(self.deep_copy(value))
+    }
+
+    // Copies go on the heap without running the collector, which would free the parts of the copy
+    // not yet linked from anywhere. The structure is walked with an explicit work list rather than
+    // recursion, so arbitrarily deep nesting can't overflow the native stack.
+    fn deep_copy(&mut self, value: Value) -> Value {
+        let mut copies: HashMap<Handle<Object>, Handle<Object>> = HashMap::new();
+        let mut pending = vec![value];
+
+        // First give every reachable mutable collection an empty copy
+        while let Some(value) = pending.pop() {
+            let handle = match value.as_object() {
+                Some(handle) if !copies.contains_key(&handle) => handle,
+                _ => continue,
+            };
+
+            let placeholder = match self.deref(handle) {
+                Object::List(ref list) => {
+                    pending.extend(list.content.iter().cloned());
+                    Object::List(List::new(Vec::new()))
+                },
+
+                Object::Dict(ref dict) => {
+                    pending.extend(dict.content.values().cloned());
+                    Object::Dict(Dict::empty())
+                },
+
+                Object::StringBuilder(ref s) => Object::StringBuilder(s.clone()),
+
+                _ => continue,
+            };
+
+            copies.insert(handle, self.heap.insert_temp(placeholder));
+        }
+
+        let copied = |value: Value| match value.as_object().and_then(|handle| copies.get(&handle)) {
+            Some(&copy) => copy.into(),
+            None => value,
+        };
+
+        // Then fill in the copies, pointing them at each other instead of at the originals
+        for (&original, &copy) in copies.iter() {
+            if let Some(list) = self.deref(original).as_list() {
+                let content = list.content.iter().map(|&element| copied(element)).collect();
+
+                if let Object::List(ref mut copy) = self.deref_mut(copy) {
+                    copy.content = content
+                }
+            } else if let Some(dict) = self.deref(original).as_dict() {
+                let content = dict.content.iter()
+                    .map(|(key, element)| (key.clone(), copied(*element)))
+                    .collect();
+
+                if let Object::Dict(ref mut copy) = self.deref_mut(copy) {
+                    copy.content = content
+                }
+            }
+        }
+
+        copied(value)
+    }
+
+    /// Like `add_native`, but fails instead of replacing a global of the same name, whether
+    /// another native or one defined by a script.
+    pub fn try_add_native(&mut self, name: &str, func: NativeFn, arity: u8) -> Result<(), RuntimeError> {
+        if self.globals.contains_key(name) {
+            return Err(self.runtime_error(&format!("global `{}` is already defined", name)))
+        }
+
+        self.add_native(name, func, arity);
+
+        Ok(())
+    }
+
+    /// A decoded copy of the operand stack, bottom first. Object handles can be dereferenced
+    /// through `heap` for as long as the objects are alive.
+    ///
+    /// The stack is reset once an error escapes `exec`, so for post-mortem inspection take the
+    /// snapshot from a native, alongside the backtrace of the error it is about to raise.
+    pub fn stack_snapshot(&self) -> Vec<Variant> {
+        self.stack.iter().map(|value| value.decode()).collect()
+    }
+
+    /// The globals defined so far with their decoded values, sorted by name, e.g. for a REPL to
+    /// report what a program defined. Objects are referenced like in `stack_snapshot`.
+    pub fn globals_snapshot(&self) -> Vec<(String, Variant)> {
+        let mut globals = self.globals.iter()
+            .map(|(name, value)| (name.clone(), value.decode()))
+            .collect::<Vec<_>>();
+
+        globals.sort_by(|a, b| a.0.cmp(&b.0));
+
+        globals
+    }
+
+    /// The locals in scope in the innermost script function being run, with their current values,
+    /// e.g. for a debugger to show from a native. Locals are only known for compiled functions.
+    pub fn current_locals(&self) -> Vec<(String, Variant)> {
+        let frame = match self.frames.last() {
+            Some(frame) => frame,
+            None => return Vec::new(),
+        };
+
+        let closure = self.deref(frame.closure)
+            .as_closure()
+            .expect("closure reference by construction");
+
+        let mut locals = closure.function().locals().iter()
+            .filter(|local| local.start <= frame.ip && frame.ip < local.end)
+            .filter_map(|local| {
+                self.stack.get(frame.stack_start + local.slot as usize)
+                    .map(|value| (local.slot, local.name.clone(), value.decode()))
+            })
+            .collect::<Vec<_>>();
+
+        locals.sort_by_key(|&(slot, _, _)| slot);
+
+        locals.into_iter().map(|(_, name, value)| (name, value)).collect()
+    }
+
+    /// Allocate a string for passing to scripts. The same rooting caveat as `new_list` applies.
+    pub fn new_string(&mut self, string: &str) -> Value {
+        self.allocate(Object::String(string.into())).into()
+    }
+
+    /// Allocate a list for passing to scripts. Like any object created by the host, it is only
+    /// kept alive by the collector once reachable from the script, e.g. through a global.
+    pub fn new_list(&mut self, content: Vec<Value>) -> Value {
+        self.allocate(Object::List(List::new(content))).into()
+    }
+
+    /// Allocate a dict from key-value pairs, hashing keys the same way scripts do. Keys must be
+    /// numbers, booleans, nil, strings or functions. The same rooting caveat as `new_list` applies.
+    pub fn new_dict(&mut self, entries: Vec<(Value, Value)>) -> Result<Value, RuntimeError> {
+        let mut dict = Dict::empty();
+
+        for (key, value) in entries {
+            let key = self.hash_key(key)?;
+
+            dict.insert(key, value)
+        }
+
+        Ok(self.allocate(Object::Dict(dict)).into())
+    }
+
+    /// Render the live heap as a Graphviz DOT graph, with objects as nodes and references between
+    /// them (list elements, dict values, closure functions and upvalues) as edges.
+    pub fn dump_heap_graph(&self) -> String {
+        let ids = self.heap.handles()
+            .enumerate()
+            .map(|(i, handle)| (handle, i))
+            .collect::<HashMap<_, _>>();
+
+        let mut dot = String::from("digraph heap {\n");
+
+        for (handle, id) in ids.iter() {
+            let label = format!("{}", WithHeap::new(&self.heap, self.deref(*handle)))
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"");
+
+            dot.push_str(&format!("    n{} [label=\"{}\"];\n", id, label));
+        }
+
+        for (handle, id) in ids.iter() {
+            for reference in self.heap.references(handle) {
+                dot.push_str(&format!("    n{} -> n{};\n", id, ids[&reference]));
+            }
+        }
+
+        dot.push_str("}\n");
+        dot
+    }
+
+    /// Run a top-level function to completion. When called from a native, the program runs on top
+    /// of the frames and stack of the script that called it, which are left untouched whether it
+    /// succeeds or not.
+    fn run_function(&mut self, function: Function) -> Result<Outcome, RuntimeError> {
+        let depth = self.frames.len();
+        let base = self.stack.len();
+
+        let nested = depth > 0;
+
+        if nested {
+            if self.native_depth >= NATIVE_DEPTH_LIMIT {
+                return Err(self.error(ErrorKind::NativeRecursion(NATIVE_DEPTH_LIMIT)))
+            }
+
+            self.native_depth += 1;
+        }
+
+        let closure = Closure::new(function, Vec::new());
+        let value = self.allocate(Object::Closure(closure)).into();
+
+        self.push(value);
+
+        let result = self.call(0)
+            .and_then(|_| self.run_until(depth));
+
+        if nested {
+            self.native_depth -= 1;
+        } else if result.is_err() {
+            self.native_depth = 0;
+        }
+
+        self.finish_run(depth, base, result)
+    }
+
+    // Clean up after running down to `depth` frames, or set aside the frames and stack above it if
+    // the program yielded
+    fn finish_run(&mut self, depth: usize, base: usize, result: Result<(), RuntimeError>) -> Result<Outcome, RuntimeError> {
+        let in_window = |up: &UpValue| up.as_local().map(|i| i >= base).unwrap_or(false);
+
+        if result.is_err() {
+            self.unwind(depth, base);
+        } else if self.yielded {
+            self.yielded = false;
+
+            let frames = self.frames.split_off(depth);
+            let stack = self.stack.split_off(base);
+            let value = *stack.last().expect("yielded value on the stack");
+
+            let (upvalues, open) = mem::take(&mut self.open_upvalues)
+                .into_iter()
+                .partition(in_window);
+
+            self.open_upvalues = open;
+
+            let stack = self.heap.insert(Object::List(List::new(stack)));
+            let closures = frames.iter().map(|frame| self.heap.make_rooted(frame.closure)).collect();
+
+            return Ok(Outcome::Yielded(Suspended {
+                value,
+                frames,
+                stack,
+                closures,
+                upvalues,
+                base,
+            }))
         }
+
+        // The top-level return leaves the result of the program where its closure was
+        let value = self.stack.get(base).cloned().unwrap_or_else(Value::nil);
+
+        // It's dropped from the stack along with anything a failed run left behind
+        self.stack.truncate(base);
+
+        result.map(|_| Outcome::Finished(value))
+    }
+
+    // Drop the frames, stack slots and open upvalues a failed run left above `depth` and `base`
+    fn unwind(&mut self, depth: usize, base: usize) {
+        self.frames.truncate(depth);
+        self.open_upvalues.retain(|up| up.as_local().map(|i| i < base).unwrap_or(true));
+        self.stack.truncate(base);
+    }
+
+    /// Run until the call stack has unwound to the given number of frames.
+    fn run_until(&mut self, depth: usize) -> Result<(), RuntimeError> {
+        while self.frames.len() > depth && !self.yielded {
+            // Hand-built chunks may lack a trailing `Return`
+            if self.frame().ip >= self.frame().with_chunk(|c| c.len()) {
+                return Err(self.runtime_error("reached the end of the chunk without returning"))
+            }
+
+            if let Some(deadline) = self.deadline {
+                self.deadline_countdown -= 1;
+
+                if self.deadline_countdown == 0 {
+                    self.deadline_countdown = self.deadline_interval;
+
+                    if Instant::now() >= deadline {
+                        return Err(self.error(ErrorKind::Timeout))
+                    }
+                }
+            }
+
+            let frame = self.frame_mut();
+            frame.op_start = frame.ip;
+
+            let inst = self.read_byte()?;
+
+            if let Some(ref mut counts) = self.op_counts {
+                counts[inst as usize] += 1
+            }
+
+            decode_op!(inst, self)?;
+        }
+
+        Ok(())
+    }
+
+    /// Call a function value from outside the run loop, e.g. from a native, running it to
+    /// completion and returning its result.
+    pub(crate) fn internal_call(&mut self, callee: Value, args: &[Value]) -> Result<Value, RuntimeError> {
+        if self.native_depth >= NATIVE_DEPTH_LIMIT {
+            return Err(self.error(ErrorKind::NativeRecursion(NATIVE_DEPTH_LIMIT)))
+        }
+
+        if args.len() > u8::MAX as usize {
+            return Err(self.runtime_error(&format!("can't call with {} arguments, at most 255 are allowed", args.len())))
+        }
+
+        let depth = self.frames.len();
+        let base = self.stack.len();
+
+        self.push(callee);
+        self.stack.extend_from_slice(args);
+
+        self.native_depth += 1;
+
+        let result = self.call(args.len() as u8)
+            .and_then(|_| self.run_until(depth));
+
+        self.native_depth -= 1;
+
+        // The native may catch the error and carry on, so it must find the stack as it left it
+        if let Err(error) = result {
+            self.unwind(depth, base);
+
+            return Err(error)
+        }
+
+        // Only the return value is kept, any extra results pushed by a native are dropped
+        let value = self.pop();
+        self.stack.truncate(base);
+
+        Ok(value)
     }
 
     #[flame]
-    fn call_closure(&mut self, handle: Handle<Object>, arity: u8) {
+    fn call_closure(&mut self, handle: Handle<Object>, arity: u8) -> Result<(), RuntimeError> {
         let closure = self.deref(handle)
             .as_closure()
             .expect("redundant cast to succeed");
 
-        let last = self.stack.len();
-        let frame_start = if last < arity as usize { 0 } else { last - (arity + 1) as usize };
+        let frame_start = self.frame_start(arity);
 
         if closure.arity() != arity {
-            self.runtime_error(&format!("arity mismatch: {} != {} @ {}: {:#?}", closure.arity(), arity, closure.name(), self.stack))
+            return Err(self.runtime_error(&format!("arity mismatch: {} != {} @ {}: {:#?}", closure.arity(), arity, closure.name(), self.stack)))
         }
 
         let frame = CallFrame::new(handle, frame_start);
         self.frames.push(frame);
+
+        Ok(())
+    }
+
+    #[flame]
+    fn closure(&mut self) -> Result<(), RuntimeError> {
+        let value = self.read_constant()?;
+        self.closure_of(value)
     }
 
     #[flame]
-    fn closure(&mut self) {
-        let value = self.frame_mut().read_constant();
+    fn closure_long(&mut self) -> Result<(), RuntimeError> {
+        let value = self.read_constant_long()?;
+        self.closure_of(value)
+    }
+
+    fn closure_of(&mut self, value: Value) -> Result<(), RuntimeError> {
         let function = value.as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_function())
-            .cloned()
-            .expect("closure expected function argument");
+            .cloned();
+
+        let function = match function {
+            Some(function) => function,
+            None => return Err(self.runtime_error("malformed closure, constant is not a function"))
+        };
+
+        // The function decides how many `is_local, index` pairs follow, so make sure they're all
+        // there and sensible rather than reading into the next instructions
+        let count = function.upvalue_count();
+        let remaining = self.frame().with_chunk(|c| c.len()) - self.frame().ip;
+
+        if remaining < count * 3 {
+            return Err(self.malformed_closure(count))
+        }
 
         let mut upvalues = Vec::new();
 
-        for _ in 0 .. function.upvalue_count() {
-            let is_local = self.read_byte() > 0;
-            let idx = self.read_byte() as usize;
+        for _ in 0 .. count {
+            let is_local = match self.read_byte()? {
+                0 => false,
+                1 => true,
+                _ => return Err(self.malformed_closure(count))
+            };
+
+            let idx = self.read_u16()? as usize;
+
             let upvalue = if is_local {
+                // A function capturing itself refers to the slot the closure is about to take
+                if self.frame().stack_start + idx > self.stack.len() {
+                    return Err(self.malformed_closure(count))
+                }
+
                 self.capture_upvalue(idx)
             } else {
+                if idx >= self.current_closure().upvalue_count() {
+                    return Err(self.malformed_closure(count))
+                }
+
                 self.current_closure().get(idx)
             };
 
@@ -218,44 +1028,87 @@ impl VM {
         let closure = Closure::new(function, upvalues);
         let value = self.allocate(Object::Closure(closure)).into();
 
-        self.push(value)
+        self.push(value);
+
+        Ok(())
     }
 
     #[flame]
-    fn call(&mut self, arity: u8) {
+    fn call(&mut self, arity: u8) -> Result<(), RuntimeError> {
+        let callee = self.stack[self.frame_start(arity)];
+
+        self.call_value(callee, arity)
+    }
+
+    // Slot of the callee, below the arguments
+    fn frame_start(&self, arity: u8) -> usize {
         let last = self.stack.len();
 
-        let frame_start = if last < arity as usize { 0 } else { last - (arity + 1) as usize };
+        if last < arity as usize { 0 } else { last - (arity + 1) as usize }
+    }
+
+    // Calls `callee` with the arguments on top of the stack, whatever is in the slot below them
+    fn call_value(&mut self, callee: Value, arity: u8) -> Result<(), RuntimeError> {
+        // A single frame only ever grows the stack by a bounded amount, so checking the limit on
+        // calls is enough to catch runaway recursion
+        if self.stack.len() > self.stack_limit {
+            return Err(self.error(ErrorKind::StackOverflow(self.stack_limit)))
+        }
 
-        let callee = self.stack[frame_start].decode();
+        let frame_start = self.frame_start(arity);
+
+        let callee = callee.decode();
 
         if let Variant::Obj(handle) = callee {
             use self::Object::*;
 
             match unsafe { self.heap.get_unchecked(handle) } {
                 Closure(_) => {
-                    self.call_closure(handle, arity)
+                    return self.call_closure(handle, arity)
                 },
                 NativeFunction(ref native) => {
                     if native.arity != arity {
-                        self.runtime_error(&format!("arity mismatch: {} != {} @ ({} {})", native.arity, arity, native.name, native.arity))
+                        return Err(self.runtime_error(&format!(
+                            "native `{}` takes {} argument{} but was called with {}",
+                            native.name, native.arity, if native.arity == 1 { "" } else { "s" }, arity
+                        )))
                     }
 
-                    let value = (native.function)(&mut self.heap, &self.stack[frame_start..]);
+                    let function = native.function;
+                    let top = frame_start + arity as usize + 1;
+
+                    let temps = self.native_temps.len();
+
+                    // When invoked, the native sits in the receiver's dict rather than the stack,
+                    // and the native could take it out of there
+                    self.native_temps.push(handle);
 
-                    self.stack.drain(frame_start + 1..);
+                    let value = function(&mut CallContext::new(self, handle, frame_start, arity));
+                    self.native_temps.truncate(temps);
 
-                    self.stack.pop();
-                    self.stack.push(value);
+                    let value = value?;
+
+                    if self.stack.len() == top {
+                        // The usual case of a native leaving the stack alone, its result simply
+                        // takes the callee's slot
+                        self.stack[frame_start] = value;
+                        self.stack.truncate(frame_start + 1);
+                    } else {
+                        // Drop the callee and arguments, keeping whatever the native pushed
+                        self.stack.drain(frame_start .. top);
+                        self.stack.push(value);
+                    }
                 },
 
-                _ => self.runtime_error("bad call")
+                _ => return Err(self.runtime_error("bad call"))
             }
         }
+
+        Ok(())
     }
 
     #[flame]
-    fn ret(&mut self) {
+    fn ret(&mut self) -> Result<(), RuntimeError> {
         if let Some(frame) = self.frames.pop() {
             let return_value = self.pop();
 
@@ -265,8 +1118,10 @@ impl VM {
             
             self.stack.truncate(frame.stack_start);
             self.push(return_value);
+
+            Ok(())
         } else {
-            self.runtime_error("can't return from top-level");
+            Err(self.runtime_error("can't return from top-level"))
         }
     }
 
@@ -294,34 +1149,60 @@ impl VM {
     }
 
     #[flame]
-    fn set_upvalue(&mut self) {
+    fn set_upvalue(&mut self) -> Result<(), RuntimeError> {
+        let idx = self.read_byte()?;
+        self.set_upvalue_at(idx as usize)
+    }
+
+    #[flame]
+    fn set_upvalue_long(&mut self) -> Result<(), RuntimeError> {
+        let idx = self.read_u16()?;
+        self.set_upvalue_at(idx as usize)
+    }
+
+    fn set_upvalue_at(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let value = self.peek();
-        let idx = self.frame_mut().read_byte();
         let closure = self.current_closure();
-        let res = closure.get(idx as usize).set(value);
+        let res = closure.get(idx).set(value);
 
         if let Err(i) = res {
             self.stack[i] = value
         }
+
+        Ok(())
+    }
+
+    #[flame]
+    fn get_upvalue(&mut self) -> Result<(), RuntimeError> {
+        let idx = self.read_byte()?;
+        self.get_upvalue_at(idx as usize)
     }
 
     #[flame]
-    fn get_upvalue(&mut self) {
-        let idx = self.frame_mut().read_byte();
+    fn get_upvalue_long(&mut self) -> Result<(), RuntimeError> {
+        let idx = self.read_u16()?;
+        self.get_upvalue_at(idx as usize)
+    }
+
+    fn get_upvalue_at(&mut self, idx: usize) -> Result<(), RuntimeError> {
         let value = self.current_closure()
-            .get(idx as usize)
+            .get(idx)
             .get()
             .unwrap_or_else(|i| self.stack[i]);
         
-        self.push(value)
+        self.push(value);
+
+        Ok(())
     }
 
     #[flame]
-    fn close_upvalue(&mut self) {
+    fn close_upvalue(&mut self) -> Result<(), RuntimeError> {
         let end = self.stack.len() - 1;
 
         self.close_upvalues(end);
         self.pop();
+
+        Ok(())
     }
 
     #[flame]
@@ -340,95 +1221,231 @@ impl VM {
     }
 
     #[flame]
-    fn allocate(&mut self, object: Object) -> Handle<Object> {
+    pub(crate) fn allocate(&mut self, object: Object) -> Handle<Object> {
         let handle = self.heap.insert(object).into_handle();
 
-        if self.heap.len() * mem::size_of::<Object>() >= self.next_gc {
+        if self.heap.estimated_bytes() >= self.next_gc {
             self.next_gc *= HEAP_GROWTH;
 
-            let upvalue_iter = self.open_upvalues.iter()
-                .flat_map(|u| u.get().ok())
-                .flat_map(|v| v.as_object());
-
-            let globals_iter = self.globals.values().flat_map(Value::as_object);
-            let stack_iter = self.stack.iter().flat_map(Value::as_object);
-
-            let exclude = stack_iter
-                .chain(Some(handle))
-                .chain(globals_iter)
-                .chain(upvalue_iter);
-            
-            self.heap.clean_excluding(exclude);
+            self.collect_excluding(Some(handle))
         }
 
         handle
     }
 
-    fn constant(&mut self, idx: u8) {
-        let val = self.frame_mut().read_constant_at(idx);
-        self.push(val)
+    /// Run a collection right away, freeing every object not reachable from the stack, the
+    /// globals or open upvalues. Collections otherwise happen as the heap grows.
+    pub fn collect(&mut self) {
+        self.collect_excluding(None)
+    }
+
+    fn collect_excluding(&mut self, extra: Option<Handle<Object>>) {
+        let upvalue_iter = self.open_upvalues.iter()
+            .flat_map(|u| u.get().ok())
+            .flat_map(|v| v.as_object());
+
+        let globals_iter = self.globals.values().flat_map(Value::as_object);
+        let stack_iter = self.stack.iter().flat_map(Value::as_object);
+        let temps_iter = self.native_temps.iter().cloned();
+
+        // Running closures are usually on the stack too, but not methods called by `invoke`, whose
+        // slot holds the receiver instead
+        let frames_iter = self.frames.iter().map(|frame| frame.closure);
+
+        let exclude = stack_iter
+            .chain(frames_iter)
+            .chain(extra)
+            .chain(globals_iter)
+            .chain(upvalue_iter)
+            .chain(temps_iter);
+
+        self.heap.clean_excluding(exclude);
+    }
+
+    fn constant(&mut self) -> Result<(), RuntimeError> {
+        let idx = self.read_byte()?;
+        self.push_constant(idx as u16)
+    }
+
+    #[flame]
+    fn constant_long(&mut self) -> Result<(), RuntimeError> {
+        let idx = self.read_u16()?;
+        self.push_constant(idx)
+    }
+
+    fn push_constant(&mut self, idx: u16) -> Result<(), RuntimeError> {
+        let val = self.read_constant_at(idx)?;
+        self.push(val);
+
+        Ok(())
+    }
+
+    #[flame]
+    fn print(&mut self) -> Result<(), RuntimeError> {
+        let value = self.pop();
+        let text = format!("{}\n", self.display(value));
+
+        self.output(&text);
+
+        Ok(())
     }
 
     #[flame]
-    fn print(&mut self) {
+    fn write(&mut self) -> Result<(), RuntimeError> {
         let value = self.pop();
-        println!("{}", value.with_heap(&self.heap))
+        let text = self.display(value);
+
+        self.output(&text);
+
+        Ok(())
+    }
+
+    fn display(&self, value: Value) -> String {
+        match self.print_policy {
+            DisplayPolicy::Summary => format!("{}", value.with_heap(&self.heap)),
+            DisplayPolicy::Full => format!("{:#}", value.with_heap(&self.heap)),
+        }
+    }
+
+    fn output(&mut self, text: &str) {
+        if let Some(ref mut hook) = self.print_hook {
+            hook(text)
+        } else {
+            print!("{}", text)
+        }
     }
 
     #[flame]
-    fn add(&mut self) {
+    fn add(&mut self) -> Result<(), RuntimeError> {
         let b = self.pop();
         let a = self.pop();
 
         use self::Variant::*;
 
         match (a.decode(), b.decode()) {
-            (Float(a), Float(b)) => return self.push((a + b).into()),
+            (Float(a), Float(b)) => self.push((a + b).into()),
             (Obj(a), Obj(b)) => {
                 let a = self.deref(a).as_string().unwrap();
                 let b = self.deref(b).as_string().unwrap();
 
                 let new = self.allocate(Object::String(format!("{}{}", a, b)));
 
-                return self.push(new.into())
+                self.push(new.into())
             },
             (Obj(a), Float(b)) => {
                 let a = self.deref(a).as_string().unwrap();
 
                 let new = self.allocate(Object::String(format!("{}{}", a, b)));
 
-                return self.push(new.into())
+                self.push(new.into())
             },
             (Float(a), Obj(b)) => {
                 let b = self.deref(b).as_string().unwrap();
 
                 let new = self.allocate(Object::String(format!("{}{}", a, b)));
 
-                return self.push(new.into())
+                self.push(new.into())
             },
             _ => {}
         }
+
+        Ok(())
+    }
+
+    #[flame]
+    fn get_global(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant()?;
+        self.push_global(name)
     }
 
     #[flame]
-    fn get_global(&mut self) {
-        let global = self.frame_mut()
-            .read_constant()
+    fn get_global_long(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant_long()?;
+        self.push_global(name)
+    }
+
+    fn push_global(&mut self, name: Value) -> Result<(), RuntimeError> {
+        let value = self.global_value(name)?;
+
+        self.push(value);
+
+        Ok(())
+    }
+
+    fn global_value(&self, name: Value) -> Result<Value, RuntimeError> {
+        let global = name
             .as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_string())
-            .expect("`GetGlobal` requires a string identifier");
-        
-        if let Some(value) = self.globals.get(global).cloned() {
-            self.push(value)
-        } else {
-            self.runtime_error(&format!("undefined global variable: `{}`", global.clone()))
+            .expect("global names are string constants");
+
+        match self.globals.get(global) {
+            Some(&value) => Ok(value),
+            None => Err(self.runtime_error(&format!("undefined global variable: `{}`", global))),
+        }
+    }
+
+    // The callee goes below the arguments pushed before, where `call` expects it
+    #[flame]
+    fn call_global(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant()?;
+        let arity = self.read_byte()?;
+        let callee = self.global_value(name)?;
+
+        let at = self.stack.len() - arity as usize;
+        self.stack.insert(at, callee);
+
+        self.call(arity)
+    }
+
+    // The dict stays below the arguments in place of the callee, where methods find `self`
+    #[flame]
+    fn invoke(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant()?;
+        let arity = self.read_byte()?;
+
+        self.invoke_named(name, arity)
+    }
+
+    #[flame]
+    fn invoke_long(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant_long()?;
+        let arity = self.read_byte()?;
+
+        self.invoke_named(name, arity)
+    }
+
+    fn invoke_named(&mut self, name: Value, arity: u8) -> Result<(), RuntimeError> {
+        let receiver = self.stack[self.frame_start(arity)];
+
+        let method = match receiver.as_object().map(|o| self.deref(o)) {
+            Some(Object::Dict(dict)) => dict.get(&self.hash_key(name)?).cloned(),
+            _ => return Err(self.runtime_error(&format!(
+                "can't call method `{}` on {}, only dicts have methods",
+                name.with_heap(&self.heap), receiver.with_heap(&self.heap)
+            ))),
+        };
+
+        match method {
+            Some(method) => self.call_value(method, arity),
+            None => Err(self.runtime_error(&format!("no method `{}` on dict", name.with_heap(&self.heap)))),
         }
     }
 
     #[flame]
-    fn define_global(&mut self) {
-        let var = self.frame_mut().read_constant()
+    fn define_global(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant()?;
+        self.define_global_named(name)
+    }
+
+    #[flame]
+    fn define_global_long(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant_long()?;
+        self.define_global_named(name)
+    }
+
+    fn define_global_named(&mut self, name: Value) -> Result<(), RuntimeError> {
+        let var = name
             .as_object()
             .map(|o| self.deref(o))
             .and_then(|o| o.as_string())
@@ -438,11 +1455,24 @@ impl VM {
         let lhs = self.stack.pop().unwrap();
 
         self.globals.insert(var, lhs);
+
+        Ok(())
     }
 
     #[flame]
-    fn set_global(&mut self) {
-        let handle = self.frame_mut().read_constant()
+    fn set_global(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant()?;
+        self.set_global_named(name)
+    }
+
+    #[flame]
+    fn set_global_long(&mut self) -> Result<(), RuntimeError> {
+        let name = self.read_constant_long()?;
+        self.set_global_named(name)
+    }
+
+    fn set_global_named(&mut self, name: Value) -> Result<(), RuntimeError> {
+        let handle = name
             .as_object()
             .filter(|&o| self.deref(o).as_string().is_some())
             .expect("expected constant to be a string value");
@@ -457,251 +1487,301 @@ impl VM {
 
         if let Some(slot) = self.globals.get_mut(var) {
             *slot = value
+        } else if self.strict_globals {
+            return Err(self.runtime_error(&format!("assignment to undeclared global `{}`", var)))
         } else {
             self.globals.insert(var.clone(), value);
         }
+
+        Ok(())
     }
 
     #[flame]
-    fn dict(&mut self) {
+    fn dict(&mut self) -> Result<(), RuntimeError> {
         use im_rc::hashmap::HashMap;
 
-        let element_count = self.read_byte();
+        let element_count = self.read_byte()?;
 
-        let mut content = HashMap::new();
+        let mut pairs = Vec::with_capacity(element_count as usize);
 
         for _ in 0 .. element_count {
             let value = self.pop();
-            let key   = HashValue {
-                variant: self.pop().decode().to_hash(&self.heap)
-            };
-
-            content.insert(key, value);
-        }
-
-        let val = self.allocate(Object::Dict(Dict::new(content))).into();
-        self.push(val)
-    }
-
-    #[flame]
-    fn set_dict_element(&mut self) {
-        // corn
-        let dict  = self.pop();
-        let key = HashValue {
-            variant: self.pop().decode().to_hash(&self.heap)
-        };
-
-        let value = self.pop();
-
-        let dict_object = dict
-            .as_object()
-            .map(|o| self.heap.get_mut_unchecked(o));
+            let key   = self.pop();
 
-        if let Some(Object::Dict(ref mut dict)) = dict_object {
-            dict.insert(key, value)
+            pairs.push((self.hash_key(key)?, value));
         }
-    }
-
-    #[flame]
-    fn get_dict_element(&mut self) {
-        // corn
-        let dict  = self.pop();
-        let key = HashValue {
-            variant: self.pop().decode().to_hash(&self.heap)
-        };
 
-        let dict_handle = dict
-            .as_object()
-            .unwrap();
+        // Pairs come off the stack last first, so insert them in reverse for the last occurrence
+        // of a duplicated key in the literal to win
+        let content = pairs.into_iter().rev().collect::<HashMap<_, _>>();
 
-        let dict = self.deref(dict_handle);
+        let val = self.allocate(Object::Dict(Dict::new(content))).into();
+        self.push(val);
 
-        if let Some(value) = dict.as_dict().unwrap().get(&key) {
-            self.push(*value)
-        } else {
-            panic!("no such field `{:?}` on dict", key)
-        }
+        Ok(())
     }
 
     #[flame]
-    fn list(&mut self) {
-        let element_count = self.read_byte();
+    fn list(&mut self) -> Result<(), RuntimeError> {
+        let element_count = self.read_byte()?;
 
-        let mut content = Vec::new();
+        let mut content = Vec::with_capacity(element_count as usize);
 
         for _ in 0 .. element_count {
             content.push(self.pop())
         }
 
         let val = self.allocate(Object::List(List::new(content))).into();
-        self.push(val)
+        self.push(val);
+
+        Ok(())
     }
 
     #[flame]
-    fn set_list_element(&mut self) {
+    fn set_element(&mut self) -> Result<(), RuntimeError> {
         let list = self.pop();
-        let idx  = if let Variant::Float(ref index) = self.pop().decode() {
-            *index as usize
-        } else {
-            panic!("Can't index list with non-number")
+        let index = self.pop();
+        let value = self.pop();
+
+        let handle = match list.as_object() {
+            Some(handle) => handle,
+            None => return Err(self.runtime_error(&format!("can't set an element of {}", self.type_name(list))))
         };
 
-        let value = self.pop();
+        match self.deref(handle) {
+            Object::List(elements) => {
+                let idx = self.element_index(list, index, elements.content.len())?;
 
-        let list_object = list
-            .as_object()
-            .map(|o| self.heap.get_mut_unchecked(o));
+                if let Object::List(elements) = self.heap.get_mut_unchecked(handle) {
+                    elements.set(idx, value)
+                }
+            },
 
-        if let Some(Object::List(ref mut list)) = list_object {
-            list.set(idx as usize, value)
+            Object::Dict(_) => {
+                let key = self.hash_key(index)?;
+
+                if let Object::Dict(dict) = self.heap.get_mut_unchecked(handle) {
+                    dict.insert(key, value)
+                }
+            },
+
+            _ => return Err(self.runtime_error(&format!("can't set an element of {}", self.type_name(list))))
         }
+
+        Ok(())
     }
 
     #[flame]
-    fn set_element(&mut self) {
+    fn index(&mut self) -> Result<(), RuntimeError> {
         let list = self.pop();
         let index = self.pop();
-        let value = self.pop();
 
-        let variant = match index.decode() {
-            Variant::Float(n) => HashVariant::Int(n as i64),
-            c @ Variant::True | c @ Variant::False => HashVariant::Bool(c == Variant::True),
-            Variant::Obj(ref handle) => {
-                HashVariant::Str(self.deref(*handle).as_string().unwrap().to_owned())
-            },
-            Nil => HashVariant::Nil,
-        };
+        let element = self.get_index(list, index)?;
+        self.push(element);
 
-        let list_object = self.heap.get_mut_unchecked(list.as_object().unwrap());
+        Ok(())
+    }
 
-        if let Object::List(list) = list_object {
-            let idx = if let Variant::Float(ref index) = index.decode() {
-                *index as usize
-            } else {
-                panic!("Can't index list with non-number")
-            };
-    
-            list.set(idx as usize, value);
+    // Chained indexing `a[b][c]...`, keys are on the stack below the collection, innermost first
+    #[flame]
+    fn index_path(&mut self) -> Result<(), RuntimeError> {
+        let depth = self.read_byte()?;
 
-            return
-        }
+        let mut value = self.pop();
 
-        if let Object::Dict(dict) = list_object {
-            let key = HashValue {
-                variant
-            };
+        for _ in 0 .. depth {
+            let index = self.pop();
 
-            dict.insert(key, value);
+            value = self.get_index(value, index)?
         }
+
+        self.push(value);
+
+        Ok(())
     }
 
-    #[flame]
-    fn index(&mut self) {
-        let list = self.pop();
-        let index = self.pop();
+    fn get_index(&mut self, collection: Value, index: Value) -> Result<Value, RuntimeError> {
+        let handle = match collection.as_object() {
+            Some(handle) => handle,
+            None => return Err(self.runtime_error(&format!("can't index {}", self.type_name(collection))))
+        };
 
-        let list_handle = list
-            .as_object()
-            .unwrap();
+        match self.deref(handle) {
+            Object::List(list) => {
+                let idx = self.element_index(collection, index, list.content.len())?;
 
-        let list = self.deref(list_handle);
+                Ok(list.get(idx))
+            },
 
-        if let Some(list) = list.as_list() {
-            let idx = if let Variant::Float(ref index) = index.decode() {
-                *index as usize
-            } else {
-                panic!("Can't index list with non-number")
-            };
-    
-            let element = list.get(idx as usize);
-    
-            self.push(element);
+            Object::Dict(dict) => {
+                let key = self.hash_key(index)?;
+
+                match dict.get(&key) {
+                    Some(value) => Ok(*value),
+                    None => Err(self.runtime_error(&format!("no such field `{}` on dict", index.with_heap(&self.heap))))
+                }
+            },
+
+            // Strings are indexed by character, giving a string of just that character
+            Object::String(s) => {
+                let idx = self.element_index(collection, index, s.chars().count())?;
+                let element = s.chars().nth(idx).unwrap().to_string();
+
+                Ok(self.allocate(Object::String(element)).into())
+            },
 
-            return
+            _ => Err(self.runtime_error(&format!("can't index {}", self.type_name(collection))))
         }
+    }
 
-        if let Some(dict) = list.as_dict() {
-            let key = HashValue {
-                variant: index.decode().to_hash(&self.heap)
-            };
+    // Element indices are whole numbers below `len`, fractions are truncated
+    fn element_index(&self, collection: Value, index: Value, len: usize) -> Result<usize, RuntimeError> {
+        match index.decode() {
+            Variant::Float(i) if i >= 0.0 && (i as usize) < len => Ok(i as usize),
 
-            if let Some(value) = dict.get(&key) {
-                self.push(*value)
-            } else {
-                panic!("no such field `{:?}` on dict with {:#?}", key, dict.content)
-            }
+            Variant::Float(i) => Err(self.runtime_error(
+                &format!("index {} out of bounds for {} of length {}", i, self.type_name(collection), len)
+            )),
+
+            _ => Err(self.runtime_error(
+                &format!("can't index {} with {}", self.type_name(collection), self.type_name(index))
+            )),
         }
     }
 
-    fn runtime_error(&self, err: &str) {
-        eprintln!("[error]: {}.", err);
-        for frame in self.frames.iter().rev() {
-            let ip = frame.ip;
-            frame.with_chunk(|chunk| {
-                let name = chunk.name();
-                let line = chunk.line(ip);
-                eprintln!("         at [line {}] in {}", line, name);
-            });
+    // Every path reading or writing dict entries must build its keys here, so that equal values
+    // (e.g. strings in different allocations) always map to the same entry
+    pub(crate) fn hash_key(&self, value: Value) -> Result<HashValue, RuntimeError> {
+        match value.decode().try_to_hash(&self.heap) {
+            Some(variant) => Ok(HashValue { variant }),
+            None => Err(self.runtime_error(&format!("can't use {} as a dict key", self.type_name(value)))),
         }
-        ::std::process::exit(1);
     }
 
-    fn on_loop(&mut self) {
-        self.frame_mut().ip -= self.read_u16() as usize
+    fn malformed_closure(&self, count: usize) -> RuntimeError {
+        self.runtime_error(&format!("malformed closure, expected {} upvalues", count))
+    }
+
+    fn runtime_error(&self, err: &str) -> RuntimeError {
+        self.error(ErrorKind::Message(err.into()))
+    }
+
+    fn error(&self, kind: ErrorKind) -> RuntimeError {
+        let backtrace = self.frames.iter().rev()
+            .map(|frame| {
+                let ip = frame.ip;
+                frame.with_chunk(|chunk| {
+                    TraceFrame {
+                        name: chunk.name().into(),
+                        line: chunk.line(ip),
+                        ip,
+                        // The ip is already past the instruction
+                        span: chunk.span(ip.saturating_sub(1)),
+                    }
+                })
+            })
+            .collect();
+
+        RuntimeError::new(kind, backtrace)
     }
 
-    fn get_local(&mut self) {
+    fn get_local(&mut self) -> Result<(), RuntimeError> {
         let start = self.frame().stack_start;
-        let idx = self.read_byte() as usize;
+        let idx = self.read_byte()? as usize;
         let val = self.stack[start + idx];
 
-        self.push(val)
+        self.push(val);
+
+        Ok(())
     }
 
-    fn set_local(&mut self) {
+    fn set_local(&mut self) -> Result<(), RuntimeError> {
         let val = self.peek();
         let start = self.frame().stack_start;
-        let idx = self.read_byte() as usize;
+        let idx = self.read_byte()? as usize;
+
+        self.stack[start + idx] = val;
 
-        self.stack[start + idx] = val
+        Ok(())
     }
 
-    fn immediate(&mut self) {
-        let raw = self.frame_mut().read_u64();
+    fn immediate(&mut self) -> Result<(), RuntimeError> {
+        let raw = self.read_u64()?;
         let val = unsafe { Value::from_raw(raw) };
 
-        self.push(val)
+        self.push(val);
+
+        Ok(())
     }
 
-    fn imm_nil(&mut self) {
+    fn imm_nil(&mut self) -> Result<(), RuntimeError> {
         self.push(Value::nil());
+
+        Ok(())
     }
 
-    fn imm_true(&mut self) {
+    fn imm_true(&mut self) -> Result<(), RuntimeError> {
         self.push(Value::truelit());
+
+        Ok(())
     }
 
-    fn imm_false(&mut self) {
+    fn imm_false(&mut self) -> Result<(), RuntimeError> {
         self.push(Value::falselit());
+
+        Ok(())
+    }
+
+    fn op_pop(&mut self) -> Result<(), RuntimeError> {
+        self.pop();
+
+        Ok(())
+    }
+
+    fn pop_n(&mut self) -> Result<(), RuntimeError> {
+        let count = self.read_byte()?;
+        let len = self.stack.len() - count as usize;
+        self.stack.truncate(len);
+
+        Ok(())
+    }
+
+    fn nop(&mut self) -> Result<(), RuntimeError> {
+        Ok(())
+    }
+
+    // The yielded value stays on the stack, where `resume` replaces it with nil
+    fn op_yield(&mut self) -> Result<(), RuntimeError> {
+        if !self.resumable {
+            return Err(self.runtime_error("can't yield from a program not run with `exec_resumable`"))
+        }
+
+        if self.native_depth > 0 {
+            return Err(self.runtime_error("can't yield across a native call"))
+        }
+
+        self.yielded = true;
+
+        Ok(())
     }
 
     #[flame]
-    fn sub(&mut self) {
-        binary_op!(self, -);
+    fn sub(&mut self) -> Result<(), RuntimeError> {
+        binary_op!(self, -)
     }
 
     #[flame]
-    fn mul(&mut self) {
-        binary_op!(self, *);
+    fn mul(&mut self) -> Result<(), RuntimeError> {
+        binary_op!(self, *)
     }
 
     #[flame]
-    fn rem(&mut self) {
-        binary_op!(self, %);
+    fn rem(&mut self) -> Result<(), RuntimeError> {
+        binary_op!(self, %)
     }
 
     #[flame]
-    fn pow(&mut self) {
+    fn pow(&mut self) -> Result<(), RuntimeError> {
         let b = self.pop();
         let a = self.pop();
 
@@ -710,64 +1790,224 @@ impl VM {
 
             self.push(c.into());
         }
+
+        Ok(())
     }
 
     #[flame]
-    fn div(&mut self) {
-        binary_op!(self, /);
+    fn div(&mut self) -> Result<(), RuntimeError> {
+        binary_op!(self, /)
     }
 
     #[flame]
-    fn neg(&mut self) {
-        if let Variant::Float(a) = self.pop().decode() {
+    fn neg(&mut self) -> Result<(), RuntimeError> {
+        let a = self.pop();
+
+        if let Variant::Float(a) = a.decode() {
             self.push((-a).into());
+
+            Ok(())
+        } else {
+            Err(self.runtime_error(&format!("can't negate {}", self.type_name(a))))
         }
     }
 
     #[flame]
-    fn not(&mut self) {
+    fn not(&mut self) -> Result<(), RuntimeError> {
         let a = self.pop();
 
         self.push(
-            if a.truthy() {
+            if self.truthy(a) {
                 Value::falselit()
             } else {
                 Value::truelit()
             }
-        )
+        );
+
+        Ok(())
+    }
+
+    #[flame]
+    fn eq(&mut self) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let equal = self.values_equal(a, b);
+        self.push(equal.into());
+
+        Ok(())
+    }
+
+    #[flame]
+    fn gt(&mut self) -> Result<(), RuntimeError> {
+        self.compare(|ordering| ordering == Ordering::Greater)
     }
 
     #[flame]
-    fn eq(&mut self) {
-        binary_op!(self, ==);
+    fn lt(&mut self) -> Result<(), RuntimeError> {
+        self.compare(|ordering| ordering == Ordering::Less)
     }
 
     #[flame]
-    fn gt(&mut self) {
-        binary_op!(self, >);
+    fn ge(&mut self) -> Result<(), RuntimeError> {
+        self.compare(|ordering| ordering != Ordering::Less)
     }
 
     #[flame]
-    fn lt(&mut self) {
-        binary_op!(self, <);
+    fn le(&mut self) -> Result<(), RuntimeError> {
+        self.compare(|ordering| ordering != Ordering::Greater)
+    }
+
+    // Strings are equal by content, other objects by identity
+    fn values_equal(&self, a: Value, b: Value) -> bool {
+        // Identical encodings are the same number, constant or object, with nothing to dereference.
+        // NaN is the exception, being unequal even to itself.
+        if a.is_same(&b) {
+            return match a.decode() {
+                Variant::Float(n) => !n.is_nan(),
+                _ => true,
+            }
+        }
+
+        match (a.decode(), b.decode()) {
+            (Variant::Obj(a), Variant::Obj(b)) => {
+                match (self.deref(a).as_string(), self.deref(b).as_string()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => a == b,
+                }
+            },
+
+            (a, b) => a == b,
+        }
+    }
+
+    // Numbers and strings are ordered within their own type, anything else is an error. NaN is
+    // unordered, so every comparison involving it is false.
+    fn compare(&mut self, test: fn(Ordering) -> bool) -> Result<(), RuntimeError> {
+        let b = self.pop();
+        let a = self.pop();
+
+        let ordering = a.cmp_with(&b, &self.heap);
+
+        // Only two numbers may be unordered, by one of them being NaN
+        if ordering.is_none() {
+            match (a.decode(), b.decode()) {
+                (Variant::Float(_), Variant::Float(_)) => {},
+                _ => return Err(self.incomparable(a, b)),
+            }
+        }
+
+        self.push(ordering.is_some_and(test).into());
+
+        Ok(())
+    }
+
+    pub(crate) fn incomparable(&self, a: Value, b: Value) -> RuntimeError {
+        self.runtime_error(
+            &format!("can't compare {} with {}", self.type_name(a), self.type_name(b))
+        )
+    }
+
+    pub(crate) fn type_name(&self, value: Value) -> &'static str {
+        use self::Object::*;
+
+        match value.decode() {
+            Variant::Float(_) => "number",
+            Variant::True | Variant::False => "boolean",
+            Variant::Nil => "nil",
+            Variant::Obj(o) => match self.deref(o) {
+                String(_) => "string",
+                Function(_) | NativeFunction(_) | Closure(_) => "function",
+                List(_) => "list",
+                Dict(_) => "dict",
+                StringBuilder(_) => "string builder",
+            }
+        }
     }
 
     #[flame]
-    fn jmp(&mut self) {
-        self.frame_mut().ip = self.read_u16() as usize
+    fn jmp(&mut self) -> Result<(), RuntimeError> {
+        self.frame_mut().ip = self.read_u16()? as usize;
+
+        Ok(())
     }
 
+    // Leaves the condition on the stack on both paths, the compiler pops it
     #[flame]
-    fn jze(&mut self) {
-        let ip = self.read_u16();
-        if !self.peek().truthy() {
+    fn jze(&mut self) -> Result<(), RuntimeError> {
+        let ip = self.read_u16()?;
+        let condition = self.peek();
+
+        if !self.truthy(condition) {
             self.frame_mut().ip = ip as usize
         }
+
+        Ok(())
+    }
+
+    // Jumps to the target for an integer within the table's range, or to the default target that
+    // follows the table otherwise
+    #[flame]
+    fn jump_table(&mut self) -> Result<(), RuntimeError> {
+        let base = f64::from_bits(self.read_u64()?);
+        let count = self.read_byte()? as usize;
+        let table = self.frame().ip;
+
+        let slot = match self.pop().decode() {
+            Variant::Float(n) if n.fract() == 0.0 && n >= base && n < base + count as f64 => {
+                (n - base) as usize
+            },
+
+            _ => count,
+        };
+
+        self.frame_mut().ip = table + slot * 2;
+        self.frame_mut().ip = self.read_u16()? as usize;
+
+        Ok(())
     }
 
     #[flame]
-    fn op_loop(&mut self) {
-        self.frame_mut().ip -= self.read_u16() as usize
+    fn op_loop(&mut self) -> Result<(), RuntimeError> {
+        if self.loop_watch.is_some() {
+            self.watch_loop()
+        }
+
+        let offset = self.read_u16()? as usize;
+        let frame = self.frame_mut();
+
+        // A corrupt offset lands past the end of the chunk, which the run loop reports
+        frame.ip = frame.ip.wrapping_sub(offset);
+
+        Ok(())
+    }
+
+    fn watch_loop(&mut self) {
+        let frame = self.frames.len();
+        let ip = self.frame().ip - 1;
+        let stack = self.stack.len();
+        let name = self.frame().with_chunk(|c| c.name().to_string());
+
+        let watch = match self.loop_watch {
+            Some(ref mut watch) => watch,
+            None => return,
+        };
+
+        if (watch.frame, watch.ip, watch.stack) == (frame, ip, stack) {
+            watch.count += 1
+        } else {
+            watch.frame = frame;
+            watch.ip = ip;
+            watch.stack = stack;
+            watch.count = 1;
+        }
+
+        if watch.count == watch.threshold {
+            (watch.hook)(&format!(
+                "loop at {} in {} ran {} times without changing the stack, it may never end",
+                ip, name, watch.threshold
+            ))
+        }
     }
 
     fn frame(&self) -> &CallFrame {
@@ -778,19 +2018,42 @@ impl VM {
         self.frames.last_mut().expect("frames to be nonempty")
     }
 
-    fn read_byte(&mut self) -> u8 {
-        self.frame_mut().read_byte()
+    fn read_byte(&mut self) -> Result<u8, RuntimeError> {
+        self.frame_mut().read_byte().ok_or_else(|| self.truncated())
     }
 
-    fn read_u16(&mut self) -> u16 {
-        self.frame_mut().read_u16()
+    fn read_u16(&mut self) -> Result<u16, RuntimeError> {
+        self.frame_mut().read_u16().ok_or_else(|| self.truncated())
     }
 
-    fn push(&mut self, value: Value) {
-        if self.stack.len() == STACK_SIZE {
-            panic!("STACK OVERFLOW >:( @ {:#?}", &self.stack[STACK_SIZE - 50 ..]);
-        }
+    fn read_u64(&mut self) -> Result<u64, RuntimeError> {
+        self.frame_mut().read_u64().ok_or_else(|| self.truncated())
+    }
+
+    fn read_constant(&mut self) -> Result<Value, RuntimeError> {
+        let idx = self.read_byte()?;
+        self.read_constant_at(idx as u16)
+    }
+
+    fn read_constant_long(&mut self) -> Result<Value, RuntimeError> {
+        let idx = self.read_u16()?;
+        self.read_constant_at(idx)
+    }
+
+    fn read_constant_at(&self, idx: u16) -> Result<Value, RuntimeError> {
+        self.frame().read_constant_at(idx)
+            .ok_or_else(|| self.runtime_error(&format!("invalid constant index {}", idx)))
+    }
+
+    fn truncated(&self) -> RuntimeError {
+        self.runtime_error(&format!("truncated instruction at ip {}", self.frame().op_start))
+    }
+
+    fn unknown_op(&mut self, op: u8) -> Result<(), RuntimeError> {
+        Err(self.runtime_error(&format!("unknown opcode {:#04x} at ip {}", op, self.frame().op_start)))
+    }
 
+    fn push(&mut self, value: Value) {
         self.stack.push(value);
     }
 